@@ -1,6 +1,15 @@
 pub use sea_orm_migration::prelude::*;
 
 mod m20250101_000001_create_user_table;
+mod m20250301_000001_hash_user_password;
+mod m20250401_000001_create_rbac_tables;
+mod m20250501_000001_add_user_lockout;
+mod m20250601_000001_create_api_key_table;
+mod m20250701_000001_add_user_username_index;
+mod m20250801_000001_create_password_reset_table;
+mod m20250901_000001_add_user_oidc_identity;
+mod m20251001_000001_seed_upload_permission;
+mod m20251101_000001_add_user_credentials_changed_at;
 
 pub struct Migrator;
 
@@ -9,6 +18,15 @@ impl MigratorTrait for Migrator {
     fn migrations() -> Vec<Box<dyn MigrationTrait>> {
         vec![
             Box::new(m20250101_000001_create_user_table::Migration),
+            Box::new(m20250301_000001_hash_user_password::Migration),
+            Box::new(m20250401_000001_create_rbac_tables::Migration),
+            Box::new(m20250501_000001_add_user_lockout::Migration),
+            Box::new(m20250601_000001_create_api_key_table::Migration),
+            Box::new(m20250701_000001_add_user_username_index::Migration),
+            Box::new(m20250801_000001_create_password_reset_table::Migration),
+            Box::new(m20250901_000001_add_user_oidc_identity::Migration),
+            Box::new(m20251001_000001_seed_upload_permission::Migration),
+            Box::new(m20251101_000001_add_user_credentials_changed_at::Migration),
         ]
     }
 }
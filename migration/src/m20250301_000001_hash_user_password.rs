@@ -0,0 +1,80 @@
+use argon2::password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, SaltString};
+use argon2::Argon2;
+use sea_orm_migration::prelude::*;
+use sea_orm_migration::sea_orm::ConnectionTrait;
+
+/// Renames `user.password` to `user.password_hash` and replaces every plaintext
+/// value with an Argon2id PHC string (`$argon2id$v=19$...`).
+///
+/// The column stays a `varchar(256)`, which comfortably holds a PHC string.
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(User::Table)
+                    .rename_column(User::Password, User::PasswordHash)
+                    .to_owned(),
+            )
+            .await?;
+
+        let db = manager.get_connection();
+        let backend = db.get_database_backend();
+        let rows = db
+            .query_all(
+                backend.build(
+                    Query::select()
+                        .columns([User::Id, User::PasswordHash])
+                        .from(User::Table),
+                ),
+            )
+            .await?;
+
+        for row in rows {
+            let id: i32 = row.try_get("", "id")?;
+            let password: String = row.try_get("", "password_hash")?;
+            if PasswordHash::new(&password).is_ok() {
+                continue;
+            }
+            let salt = SaltString::generate(&mut OsRng);
+            let hash = Argon2::default()
+                .hash_password(password.as_bytes(), &salt)
+                .map_err(|e| DbErr::Migration(format!("failed to hash password of user {id}: {e}")))?
+                .to_string();
+            db.execute(
+                backend.build(
+                    Query::update()
+                        .table(User::Table)
+                        .value(User::PasswordHash, hash)
+                        .and_where(Expr::col(User::Id).eq(id)),
+                ),
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // hashes cannot be reverted to plaintext, only the column name is restored
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(User::Table)
+                    .rename_column(User::PasswordHash, User::Password)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+#[derive(Iden)]
+enum User {
+    Table,
+    Id,
+    Password,
+    PasswordHash,
+}
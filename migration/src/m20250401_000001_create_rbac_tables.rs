@@ -0,0 +1,160 @@
+use sea_orm_migration::prelude::*;
+use sea_orm_migration::schema::*;
+use sea_orm_migration::sea_orm::ConnectionTrait;
+
+/// Roles, permissions and their assignments. Seeds an `admin` role holding the
+/// wildcard permission `*`.
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(Role::Table)
+                    .col(pk_auto(Role::Id))
+                    .col(string(Role::Name).char_len(64).unique_key())
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_table(
+                Table::create()
+                    .table(Permission::Table)
+                    .col(pk_auto(Permission::Id))
+                    .col(string(Permission::Name).char_len(64).unique_key())
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_table(
+                Table::create()
+                    .table(RolePermission::Table)
+                    .col(integer(RolePermission::RoleId))
+                    .col(integer(RolePermission::PermissionId))
+                    .primary_key(
+                        Index::create()
+                            .col(RolePermission::RoleId)
+                            .col(RolePermission::PermissionId),
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .from(RolePermission::Table, RolePermission::RoleId)
+                            .to(Role::Table, Role::Id)
+                            .on_delete(ForeignKeyAction::Cascade),
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .from(RolePermission::Table, RolePermission::PermissionId)
+                            .to(Permission::Table, Permission::Id)
+                            .on_delete(ForeignKeyAction::Cascade),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_table(
+                Table::create()
+                    .table(UserRole::Table)
+                    .col(integer(UserRole::UserId))
+                    .col(integer(UserRole::RoleId))
+                    .primary_key(Index::create().col(UserRole::UserId).col(UserRole::RoleId))
+                    .foreign_key(
+                        ForeignKey::create()
+                            .from(UserRole::Table, UserRole::UserId)
+                            .to(User::Table, User::Id)
+                            .on_delete(ForeignKeyAction::Cascade),
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .from(UserRole::Table, UserRole::RoleId)
+                            .to(Role::Table, Role::Id)
+                            .on_delete(ForeignKeyAction::Cascade),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        let db = manager.get_connection();
+        let backend = db.get_database_backend();
+        db.execute(
+            backend.build(
+                Query::insert()
+                    .into_table(Role::Table)
+                    .columns([Role::Id, Role::Name])
+                    .values_panic([1.into(), "admin".into()]),
+            ),
+        )
+        .await?;
+        db.execute(
+            backend.build(
+                Query::insert()
+                    .into_table(Permission::Table)
+                    .columns([Permission::Id, Permission::Name])
+                    .values_panic([1.into(), "*".into()]),
+            ),
+        )
+        .await?;
+        db.execute(
+            backend.build(
+                Query::insert()
+                    .into_table(RolePermission::Table)
+                    .columns([RolePermission::RoleId, RolePermission::PermissionId])
+                    .values_panic([1.into(), 1.into()]),
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(UserRole::Table).to_owned())
+            .await?;
+        manager
+            .drop_table(Table::drop().table(RolePermission::Table).to_owned())
+            .await?;
+        manager
+            .drop_table(Table::drop().table(Permission::Table).to_owned())
+            .await?;
+        manager
+            .drop_table(Table::drop().table(Role::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(Iden)]
+enum User {
+    Table,
+    Id,
+}
+
+#[derive(Iden)]
+enum Role {
+    Table,
+    Id,
+    Name,
+}
+
+#[derive(Iden)]
+enum Permission {
+    Table,
+    Id,
+    Name,
+}
+
+#[derive(Iden)]
+enum RolePermission {
+    Table,
+    RoleId,
+    PermissionId,
+}
+
+#[derive(Iden)]
+enum UserRole {
+    Table,
+    UserId,
+    RoleId,
+}
@@ -0,0 +1,63 @@
+use sea_orm_migration::prelude::*;
+use sea_orm_migration::schema::*;
+
+/// API keys for machine clients. Only a SHA-256 hash of the key is stored, the
+/// `prefix` identifies the key for lookup. Timestamps are unix seconds.
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(ApiKey::Table)
+                    .col(pk_auto(ApiKey::Id))
+                    .col(string(ApiKey::Prefix).char_len(16).unique_key())
+                    .col(string(ApiKey::KeyHash).char_len(64))
+                    .col(integer(ApiKey::OwnerId))
+                    .col(string(ApiKey::Name).char_len(256))
+                    .col(text(ApiKey::Scopes))
+                    .col(big_integer(ApiKey::CreatedAt))
+                    .col(big_integer_null(ApiKey::LastUsedAt))
+                    .col(big_integer_null(ApiKey::ExpiresAt))
+                    .col(big_integer_null(ApiKey::RevokedAt))
+                    .foreign_key(
+                        ForeignKey::create()
+                            .from(ApiKey::Table, ApiKey::OwnerId)
+                            .to(User::Table, User::Id)
+                            .on_delete(ForeignKeyAction::Cascade),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(ApiKey::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(Iden)]
+enum User {
+    Table,
+    Id,
+}
+
+#[derive(Iden)]
+enum ApiKey {
+    Table,
+    Id,
+    Prefix,
+    KeyHash,
+    OwnerId,
+    Name,
+    Scopes,
+    CreatedAt,
+    LastUsedAt,
+    ExpiresAt,
+    RevokedAt,
+}
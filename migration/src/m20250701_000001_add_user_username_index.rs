@@ -0,0 +1,38 @@
+use sea_orm_migration::prelude::*;
+
+/// Makes `user.username` unique, the user API relies on it to answer 409 on duplicates.
+///
+/// Fails if the table already holds duplicate usernames, those have to be cleaned up
+/// by hand first.
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+const INDEX_NAME: &str = "idx_user_username";
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_index(
+                Index::create()
+                    .name(INDEX_NAME)
+                    .table(User::Table)
+                    .col(User::Username)
+                    .unique()
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_index(Index::drop().name(INDEX_NAME).table(User::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(Iden)]
+enum User {
+    Table,
+    Username,
+}
@@ -0,0 +1,55 @@
+use sea_orm_migration::prelude::*;
+use sea_orm_migration::schema::*;
+
+/// Single-use password reset tokens. Only a SHA-256 hash of the token is stored,
+/// timestamps are unix seconds.
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(PasswordReset::Table)
+                    .col(pk_auto(PasswordReset::Id))
+                    .col(integer(PasswordReset::UserId))
+                    .col(string(PasswordReset::TokenHash).char_len(64).unique_key())
+                    .col(big_integer(PasswordReset::CreatedAt))
+                    .col(big_integer(PasswordReset::ExpiresAt))
+                    .col(big_integer_null(PasswordReset::UsedAt))
+                    .foreign_key(
+                        ForeignKey::create()
+                            .from(PasswordReset::Table, PasswordReset::UserId)
+                            .to(User::Table, User::Id)
+                            .on_delete(ForeignKeyAction::Cascade),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(PasswordReset::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(Iden)]
+enum User {
+    Table,
+    Id,
+}
+
+#[derive(Iden)]
+enum PasswordReset {
+    Table,
+    Id,
+    UserId,
+    TokenHash,
+    CreatedAt,
+    ExpiresAt,
+    UsedAt,
+}
@@ -0,0 +1,134 @@
+use sea_orm_migration::prelude::*;
+
+/// Seeds the `upload:write` permission required by `POST /api/uploads`, granted to a
+/// new `uploader` role. `admin` already holds it through `*`.
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+const PERMISSION: &str = "upload:write";
+const ROLE: &str = "uploader";
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		let db = manager.get_connection();
+		let backend = db.get_database_backend();
+		db.execute(
+			backend.build(
+				Query::insert()
+					.into_table(Role::Table)
+					.columns([Role::Name])
+					.values_panic([ROLE.into()]),
+			),
+		)
+		.await?;
+		db.execute(
+			backend.build(
+				Query::insert()
+					.into_table(Permission::Table)
+					.columns([Permission::Name])
+					.values_panic([PERMISSION.into()]),
+			),
+		)
+		.await?;
+		db.execute(
+			backend.build(
+				Query::insert()
+					.into_table(RolePermission::Table)
+					.columns([RolePermission::RoleId, RolePermission::PermissionId])
+					.select_from(
+						Query::select()
+							.column((Role::Table, Role::Id))
+							.column((Permission::Table, Permission::Id))
+							.from(Role::Table)
+							.from(Permission::Table)
+							.and_where(Expr::col((Role::Table, Role::Name)).eq(ROLE))
+							.and_where(
+								Expr::col((Permission::Table, Permission::Name)).eq(PERMISSION),
+							)
+							.to_owned(),
+					)
+					.map_err(|err| DbErr::Custom(err.to_string()))?,
+			),
+		)
+		.await?;
+		Ok(())
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		let db = manager.get_connection();
+		let backend = db.get_database_backend();
+		db.execute(
+			backend.build(
+				Query::delete().from_table(RolePermission::Table).and_where(
+					Expr::col(RolePermission::RoleId).in_subquery(
+						Query::select()
+							.column(Role::Id)
+							.from(Role::Table)
+							.and_where(Expr::col(Role::Name).eq(ROLE))
+							.to_owned(),
+					),
+				),
+			),
+		)
+		.await?;
+		db.execute(
+			backend.build(
+				Query::delete().from_table(UserRole::Table).and_where(
+					Expr::col(UserRole::RoleId).in_subquery(
+						Query::select()
+							.column(Role::Id)
+							.from(Role::Table)
+							.and_where(Expr::col(Role::Name).eq(ROLE))
+							.to_owned(),
+					),
+				),
+			),
+		)
+		.await?;
+		db.execute(
+			backend.build(
+				Query::delete()
+					.from_table(Role::Table)
+					.and_where(Expr::col(Role::Name).eq(ROLE)),
+			),
+		)
+		.await?;
+		db.execute(
+			backend.build(
+				Query::delete()
+					.from_table(Permission::Table)
+					.and_where(Expr::col(Permission::Name).eq(PERMISSION)),
+			),
+		)
+		.await?;
+		Ok(())
+	}
+}
+
+#[derive(Iden)]
+enum Role {
+	Table,
+	Id,
+	Name,
+}
+
+#[derive(Iden)]
+enum Permission {
+	Table,
+	Id,
+	Name,
+}
+
+#[derive(Iden)]
+enum RolePermission {
+	Table,
+	RoleId,
+	PermissionId,
+}
+
+#[derive(Iden)]
+enum UserRole {
+	Table,
+	RoleId,
+}
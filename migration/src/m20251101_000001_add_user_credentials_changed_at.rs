@@ -0,0 +1,39 @@
+use sea_orm_migration::prelude::*;
+use sea_orm_migration::schema::*;
+
+/// When the credentials of a user were created or last changed, in unix
+/// milliseconds. Sessions and bearer tokens obtained before that are rejected, on
+/// every instance and across restarts. NULL for users that predate the column.
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(User::Table)
+                    .add_column(big_integer_null(User::CredentialsChangedAt))
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(User::Table)
+                    .drop_column(User::CredentialsChangedAt)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+#[derive(Iden)]
+enum User {
+    Table,
+    CredentialsChangedAt,
+}
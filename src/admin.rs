@@ -0,0 +1,180 @@
+use anyhow_ext::{Context, anyhow};
+use serde_json::json;
+use tide::{Middleware, Next, Request};
+use tracing::{info, warn};
+
+use crate::config::AdminConfig;
+use crate::error::AppError;
+use crate::route_pattern::{self, RoutePattern};
+use crate::server::{AccessLogMiddleware, ErrorHandleMiddleware, make_resp};
+use crate::{api_key, auth, config, health, listener, logger, oidc, shutdown, telemetry, utils};
+
+/// The admin app serving the operational endpoints, bound to `[admin]` listeners only.
+///
+/// It shares no routes, sessions or users with the public app, requests are
+/// authenticated with the bearer tokens of [`AdminConfig::token_sha256`].
+pub fn admin_app(cfg: &AdminConfig) -> tide::Server<()> {
+	let mut app = tide::new();
+	app.with(ErrorHandleMiddleware);
+	app.with(AccessLogMiddleware);
+	app.with(shutdown::InFlightMiddleware);
+	app.with(listener::ListenerRoutesMiddleware);
+	app.with(AdminAuthMiddleware::new(cfg));
+
+	// Probes for the load balancer and Kubernetes
+	app.at("/healthz").get(health::healthz);
+	app.at("/readyz").get(health::readyz);
+	app.at("/api/status").get(health::status);
+	app.at("/metrics").get(telemetry::render);
+	app.at("/api/config").get(dump_config);
+	app.at("/api/cache/flush").post(flush_caches);
+	app.at("/api/log").get(get_log_level);
+	app.at("/api/log/:directive").post(set_log_level);
+	app
+}
+
+/// Bearer token auth of the admin app, independent of the public app's users so a
+/// leaked user password or session never opens the admin endpoints
+pub struct AdminAuthMiddleware {
+	token_sha256: Vec<String>,
+	public_routes: Vec<RoutePattern>,
+}
+
+impl AdminAuthMiddleware {
+	pub fn new(cfg: &AdminConfig) -> Self {
+		Self {
+			token_sha256: cfg
+				.token_sha256
+				.iter()
+				.map(|digest| digest.trim().to_ascii_lowercase())
+				.collect(),
+			public_routes: cfg.public_routes.clone(),
+		}
+	}
+
+	fn token_valid(&self, token: &str) -> bool {
+		let digest = utils::sha256_hex(token);
+		// no short-circuit, every configured token is compared
+		self.token_sha256.iter().fold(false, |valid, t| {
+			api_key::constant_time_eq(t, &digest) | valid
+		})
+	}
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for AdminAuthMiddleware {
+	async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+		if route_pattern::matches_any(&self.public_routes, req.method(), req.url().path()) {
+			return Ok(next.run(req).await);
+		}
+		let Some(token) = auth::read_bearer_token(&req) else {
+			return Ok(
+				AppError::unauthorized("unauthenticated", "admin token is required")
+					.with_header("WWW-Authenticate", r#"Bearer realm="admin""#)
+					.into(),
+			);
+		};
+		if !self.token_valid(&token) {
+			warn!(peer = ?req.peer_addr(), "invalid admin token");
+			return Ok(
+				AppError::unauthorized("invalid_token", "invalid admin token")
+					.with_header(
+						"WWW-Authenticate",
+						r#"Bearer realm="admin", error="invalid_token""#,
+					)
+					.into(),
+			);
+		}
+		Ok(next.run(req).await)
+	}
+}
+
+/// `GET /api/config`, the effective config with secrets redacted
+async fn dump_config(_req: Request<()>) -> tide::Result {
+	let body = tide::Body::from_json(&*config::cfg().await)?;
+	Ok(make_resp(200, body))
+}
+
+/// `POST /api/cache/flush`, drops the credential cache and the discovered OIDC provider
+async fn flush_caches(_req: Request<()>) -> tide::Result {
+	let credentials = auth::flush_cred_cache();
+	let oidc_provider = oidc::forget_provider().await;
+	info!(credentials, oidc_provider, "caches flushed");
+	let body = tide::Body::from_json(&json!({
+		"credentials": credentials,
+		"oidc_provider": oidc_provider,
+	}))?;
+	Ok(make_resp(200, body))
+}
+
+/// `GET /api/log`, the current log directive
+async fn get_log_level(_req: Request<()>) -> tide::Result {
+	Ok(make_resp(200, logger::get_global_log_level().dot()?))
+}
+
+/// `POST /api/log/:directive`, replaces the log directive until the next restart
+async fn set_log_level(req: Request<()>) -> tide::Result {
+	let directive = req
+		.param("directive")
+		.map_err(|_e| anyhow!("directive is required"))
+		.dot()?;
+	logger::update_global_log_level(directive).dot()?;
+	Ok(make_resp(200, ""))
+}
+
+#[cfg(test)]
+mod tests {
+	use tide::StatusCode;
+	use tide::http::{Method, Request as HttpRequest, Response as HttpResponse, Url};
+
+	use super::*;
+
+	const TOKEN: &str = "s3cret-admin-token";
+
+	fn app() -> tide::Server<()> {
+		admin_app(&AdminConfig {
+			token_sha256: vec![utils::sha256_hex(TOKEN).to_uppercase()],
+			..Default::default()
+		})
+	}
+
+	async fn send(method: Method, path: &str, token: Option<&str>) -> HttpResponse {
+		let url = Url::parse(&format!("http://localhost{path}")).unwrap();
+		let mut req = HttpRequest::new(method, url);
+		if let Some(token) = token {
+			req.insert_header("Authorization", format!("Bearer {token}"));
+		}
+		app().respond(req).await.unwrap()
+	}
+
+	#[async_std::test]
+	async fn test_public_routes_need_no_token() {
+		let resp = send(Method::Get, "/healthz", None).await;
+		assert_eq!(resp.status(), StatusCode::Ok);
+	}
+
+	#[async_std::test]
+	async fn test_admin_routes_need_a_valid_token() {
+		let resp = send(Method::Get, "/api/config", None).await;
+		assert_eq!(resp.status(), StatusCode::Unauthorized);
+		assert!(resp["WWW-Authenticate"].as_str().starts_with("Bearer"));
+
+		let resp = send(Method::Post, "/api/cache/flush", Some("guess")).await;
+		assert_eq!(resp.status(), StatusCode::Unauthorized);
+
+		let mut resp = send(Method::Post, "/api/cache/flush", Some(TOKEN)).await;
+		assert_eq!(resp.status(), StatusCode::Ok);
+		let body: serde_json::Value = resp.body_json().await.unwrap();
+		assert_eq!(body["oidc_provider"], false);
+	}
+
+	#[async_std::test]
+	async fn test_no_tokens_configured_refuses_everything_else() {
+		let app = admin_app(&AdminConfig::default());
+		let url = Url::parse("http://localhost/api/config").unwrap();
+		let mut req = HttpRequest::new(Method::Get, url);
+		req.insert_header("Authorization", "Bearer anything");
+		let resp: HttpResponse = app.respond(req).await.unwrap();
+		assert_eq!(resp.status(), StatusCode::Unauthorized);
+	}
+}
@@ -0,0 +1,228 @@
+use anyhow_ext::{Context, Result, bail};
+use sea_orm::{ActiveModelTrait, ColumnTrait, EntityTrait, QueryFilter, QueryOrder, Set};
+
+use crate::{
+	auth,
+	cli::ApiKeyCommands,
+	database,
+	entity::{api_key, user},
+	utils,
+};
+
+/// Keys look like `rtt_<prefix>_<secret>`, the prefix is stored in plaintext for lookup
+pub(crate) const KEY_TAG: &str = "rtt";
+const PREFIX_LEN: u8 = 8;
+const SECRET_LEN: u8 = 32;
+/// `last_used_at` is written at most this often per key, in seconds
+const LAST_USED_RESOLUTION: i64 = 60;
+
+/// The key a request authenticated with, set into the request extensions by
+/// [`crate::auth::AuthMiddleware`].
+#[derive(Debug, Clone)]
+pub struct ApiKeyInfo {
+	pub id: i32,
+	/// Identifies the key in logs without revealing it
+	pub prefix: String,
+	pub scopes: Vec<String>,
+}
+
+impl From<&api_key::Model> for ApiKeyInfo {
+	fn from(key: &api_key::Model) -> Self {
+		Self {
+			id: key.id,
+			prefix: key.prefix.clone(),
+			scopes: split_scopes(&key.scopes),
+		}
+	}
+}
+
+fn split_scopes(scopes: &str) -> Vec<String> {
+	scopes.split_whitespace().map(str::to_owned).collect()
+}
+
+fn parse_prefix(key: &str) -> Option<&str> {
+	let rest = key.strip_prefix(KEY_TAG)?.strip_prefix('_')?;
+	let (prefix, secret) = rest.split_once('_')?;
+	(!prefix.is_empty() && !secret.is_empty()).then_some(prefix)
+}
+
+pub(crate) fn constant_time_eq(a: &str, b: &str) -> bool {
+	a.len() == b.len()
+		&& a.bytes()
+			.zip(b.bytes())
+			.fold(0, |acc, (x, y)| acc | (x ^ y))
+			== 0
+}
+
+/// Create a key for `owner`. The plaintext key is only returned here, the database
+/// keeps its hash.
+pub async fn mint(
+	owner: &str,
+	name: &str,
+	scopes: &[String],
+	expires_at: Option<i64>,
+) -> Result<(String, api_key::Model)> {
+	let db = database::get_db_conn().dot()?;
+	let Some(owner) = auth::find_user(owner).await.dot()? else {
+		bail!("user {owner} does not exist");
+	};
+	let prefix = utils::gen_n_random_str(PREFIX_LEN);
+	let key = format!("{KEY_TAG}_{prefix}_{}", utils::gen_n_random_str(SECRET_LEN));
+	let model = api_key::ActiveModel {
+		prefix: Set(prefix),
+		key_hash: Set(utils::sha256_hex(&key)),
+		owner_id: Set(owner.id),
+		name: Set(name.to_owned()),
+		scopes: Set(scopes.join(" ")),
+		created_at: Set(utils::unix_now()),
+		expires_at: Set(expires_at),
+		..Default::default()
+	}
+	.insert(db)
+	.await
+	.dot()?;
+	Ok((key, model))
+}
+
+/// Returns false if no active key has this prefix
+pub async fn revoke(prefix: &str) -> Result<bool> {
+	let db = database::get_db_conn().dot()?;
+	let key = api_key::Entity::find()
+		.filter(api_key::Column::Prefix.eq(prefix))
+		.filter(api_key::Column::RevokedAt.is_null())
+		.one(db)
+		.await
+		.dot()?;
+	let Some(key) = key else {
+		return Ok(false);
+	};
+	let mut active: api_key::ActiveModel = key.into();
+	active.revoked_at = Set(Some(utils::unix_now()));
+	active.update(db).await.dot()?;
+	Ok(true)
+}
+
+/// Look up a presented key and its owner, None if it's unknown, revoked or expired
+pub async fn verify(key: &str) -> Result<Option<(api_key::Model, user::Model)>> {
+	let Some(prefix) = parse_prefix(key) else {
+		return Ok(None);
+	};
+	let db = database::get_db_conn().dot()?;
+	let found = api_key::Entity::find()
+		.filter(api_key::Column::Prefix.eq(prefix))
+		.find_also_related(user::Entity)
+		.one(db)
+		.await
+		.dot()?;
+	let Some((model, Some(owner))) = found else {
+		return Ok(None);
+	};
+	let now = utils::unix_now();
+	if !constant_time_eq(&model.key_hash, &utils::sha256_hex(key))
+		|| model.revoked_at.is_some()
+		|| model.expires_at.is_some_and(|at| at <= now)
+	{
+		return Ok(None);
+	}
+
+	if model
+		.last_used_at
+		.is_none_or(|at| now - at >= LAST_USED_RESOLUTION)
+	{
+		let mut active: api_key::ActiveModel = model.clone().into();
+		active.last_used_at = Set(Some(now));
+		active.update(db).await.dot()?;
+	}
+	Ok(Some((model, owner)))
+}
+
+pub async fn run_command(command: ApiKeyCommands) -> Result<()> {
+	match command {
+		ApiKeyCommands::Mint {
+			owner,
+			name,
+			scopes,
+			expires_in_days,
+		} => {
+			let expires_at =
+				expires_in_days.map(|days| utils::unix_now() + i64::from(days) * 24 * 60 * 60);
+			let (key, model) = mint(&owner, &name, &scopes, expires_at).await.dot()?;
+			println!("Minted API key {} for {owner}", model.prefix);
+			println!("{key}");
+			println!("Store it now, it can't be shown again.");
+		}
+		ApiKeyCommands::Revoke { prefix } => {
+			if revoke(&prefix).await.dot()? {
+				println!("Revoked API key {prefix}");
+			} else {
+				bail!("no active API key with prefix {prefix}");
+			}
+		}
+		ApiKeyCommands::List { owner } => {
+			let db = database::get_db_conn().dot()?;
+			let mut query = api_key::Entity::find()
+				.find_also_related(user::Entity)
+				.order_by_asc(api_key::Column::Id);
+			if let Some(owner) = &owner {
+				query = query.filter(user::Column::Username.eq(owner));
+			}
+			println!("PREFIX\tOWNER\tNAME\tSCOPES\tCREATED\tLAST_USED\tEXPIRES\tREVOKED");
+			for (key, owner) in query.all(db).await.dot()? {
+				println!(
+					"{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+					key.prefix,
+					owner.map(|u| u.username).unwrap_or("-".to_owned()),
+					key.name,
+					key.scopes,
+					key.created_at,
+					fmt_opt(key.last_used_at),
+					fmt_opt(key.expires_at),
+					fmt_opt(key.revoked_at),
+				);
+			}
+		}
+	}
+	Ok(())
+}
+
+fn fmt_opt(v: Option<i64>) -> String {
+	v.map(|v| v.to_string()).unwrap_or("-".to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parse_prefix() {
+		assert_eq!(parse_prefix("rtt_abcd1234_secret"), Some("abcd1234"));
+		assert_eq!(parse_prefix("rtt_abcd1234_"), None);
+		assert_eq!(parse_prefix("rtt__secret"), None);
+		assert_eq!(parse_prefix("xyz_abcd1234_secret"), None);
+		assert_eq!(parse_prefix("rtt_abcd1234"), None);
+	}
+
+	#[test]
+	fn test_hash_key() {
+		let hash = utils::sha256_hex("rtt_abcd1234_secret");
+		assert_eq!(hash.len(), 64);
+		assert_eq!(hash, utils::sha256_hex("rtt_abcd1234_secret"));
+		assert_ne!(hash, utils::sha256_hex("rtt_abcd1234_secreT"));
+	}
+
+	#[test]
+	fn test_constant_time_eq() {
+		assert!(constant_time_eq("abc", "abc"));
+		assert!(!constant_time_eq("abc", "abd"));
+		assert!(!constant_time_eq("abc", "abcd"));
+	}
+
+	#[test]
+	fn test_split_scopes() {
+		assert_eq!(
+			split_scopes("log:read  log:write"),
+			vec!["log:read", "log:write"]
+		);
+		assert!(split_scopes("").is_empty());
+	}
+}
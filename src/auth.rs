@@ -1,105 +1,740 @@
 use std::{
+	fmt,
 	sync::LazyLock,
 	time::{Duration, Instant},
 };
 
+use anyhow_ext::{Context, Result, bail};
+use argon2::{
+	Argon2,
+	password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString, rand_core::OsRng},
+};
 use dashmap::DashMap;
+use hmac::{Hmac, Mac};
+use sea_orm::{ColumnTrait, EntityTrait, QueryFilter};
 use serde::Deserialize;
-use tracing::info;
+use sha2::Sha256;
+use tide::{
+	Response,
+	http::cookies::SameSite,
+	sessions::{MemoryStore, Session, SessionMiddleware},
+};
+use tracing::{info, warn};
 
-use crate::server::make_resp;
+use crate::{
+	api_key::{self, ApiKeyInfo},
+	config::{self, SameSitePolicy, SessionConfig},
+	database,
+	entity::user,
+	error::AppError,
+	jwt, lockout, proxy, rbac,
+	route_pattern::{self, RoutePattern},
+	server::{LoggedUser, make_resp},
+	utils,
+};
 
-static CRED_CACHE: LazyLock<DashMap<String, (String, Instant)>> = LazyLock::new(|| DashMap::new());
+type HmacSha256 = Hmac<Sha256>;
 
-#[derive(Deserialize, Default, Debug, Clone)]
+static CRED_CACHE: LazyLock<CredCache> = LazyLock::new(CredCache::new);
+
+#[derive(Deserialize, Default, Debug, Clone, PartialEq, Eq)]
 pub struct Cred {
 	pub username: String,
 	pub password: String,
 }
 
-pub struct AuthMiddleware;
+/// Who the current request is authenticated as, set into the request extensions by
+/// [`AuthMiddleware`].
+#[derive(Debug, Clone)]
+pub struct Identity {
+	pub username: String,
+	pub method: AuthMethod,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthMethod {
+	Session,
+	Bearer,
+	ApiKey,
+	Basic,
+}
+
+/// Session key holding the username of a logged in user
+const SESSION_USER_KEY: &str = "username";
+/// Session key holding when the user logged in, in unix milliseconds
+const SESSION_AUTH_AT_KEY: &str = "auth_at";
+
+pub struct AuthMiddleware {
+	public_routes: Vec<RoutePattern>,
+}
+
+impl AuthMiddleware {
+	/// Requests matching `public_routes` pass through without authentication
+	pub fn new(public_routes: Vec<RoutePattern>) -> Self {
+		Self { public_routes }
+	}
+}
+
 #[tide::utils::async_trait]
 impl<State: Clone + Send + Sync + 'static> tide::Middleware<State> for AuthMiddleware {
-	async fn handle(&self, req: tide::Request<State>, next: tide::Next<'_, State>) -> tide::Result {
-		info!("enter auth");
-		let cred = read_cred_from_basic_auth(&req);
-		match cred {
-			None => return Ok(make_resp(401, "basic auth is required")),
-			Some(cred) => {
-				let mut authn_passed = false;
-				if let Some(cached_pswd) = get_cached_cred(&cred.username).await {
-					if cached_pswd == cred.password {
-						authn_passed = true
-					}
-				} else if authn(&cred.username, &cred.password).await? {
-					cache_cred(&cred.username, cred.password).await;
-					authn_passed = true
+	async fn handle(
+		&self,
+		mut req: tide::Request<State>,
+		next: tide::Next<'_, State>,
+	) -> tide::Result {
+		if route_pattern::matches_any(&self.public_routes, req.method(), req.url().path()) {
+			return Ok(next.run(req).await);
+		}
+
+		// a session only exists when the session middleware is mounted in front of us
+		let session_user = req.ext::<Session>().and_then(|session| {
+			let username = session.get::<String>(SESSION_USER_KEY)?;
+			let auth_at = session.get::<i64>(SESSION_AUTH_AT_KEY).unwrap_or_default();
+			Some((username, auth_at))
+		});
+		let session_user = match session_user {
+			Some((username, auth_at)) if is_revoked(&username, auth_at).await? => {
+				req.session_mut().destroy();
+				None
+			}
+			session_user => session_user.map(|(username, _)| username),
+		};
+		let identity = if let Some(username) = session_user {
+			Identity {
+				username,
+				method: AuthMethod::Session,
+			}
+		} else if let Some(token) = read_bearer_token(&req) {
+			let claims = match jwt::jwt_keys().and_then(|keys| keys.verify(&token)) {
+				Ok(claims) => claims,
+				Err(err) => {
+					info!("invalid bearer token: {err}");
+					return Ok(
+						AppError::unauthorized("invalid_token", "invalid bearer token").into(),
+					);
+				}
+			};
+			// `iat` only has second precision, so a token issued within the same second
+			// as the revocation is still accepted rather than rejecting fresh ones
+			if is_revoked(&claims.sub, claims.iat as i64 * 1000 + 999).await? {
+				return Ok(AppError::unauthorized(
+					"token_revoked",
+					"bearer token has been revoked",
+				)
+				.into());
+			}
+			let identity = Identity {
+				username: claims.sub.clone(),
+				method: AuthMethod::Bearer,
+			};
+			req.set_ext(claims);
+			identity
+		} else if let Some(key) = read_api_key(&req) {
+			let Some((key, owner)) = api_key::verify(&key).await? else {
+				return Ok(AppError::unauthorized("invalid_api_key", "invalid API key").into());
+			};
+			req.set_ext(ApiKeyInfo::from(&key));
+			Identity {
+				username: owner.username,
+				method: AuthMethod::ApiKey,
+			}
+		} else {
+			match read_cred_from_basic_auth(&req) {
+				Err(err) => return Ok(invalid_basic_auth(err).into()),
+				Ok(None) => {
+					return Ok(AppError::unauthorized(
+						"unauthenticated",
+						"login or basic auth is required",
+					)
+					.into());
 				}
-				if !authn_passed {
-					return Ok(make_resp(401, "incorrect username or password"));
+				Ok(Some(cred)) => {
+					let ip = proxy::client_ip(&req);
+					let check = verify_cred(&cred, ip.as_deref()).await?;
+					if let Some(resp) = cred_check_failure_resp(check) {
+						return Ok(resp);
+					}
+					Identity {
+						username: cred.username,
+						method: AuthMethod::Basic,
+					}
 				}
+			}
+		};
 
-				let resp = next.run(req).await;
-				return Ok(resp);
+		let mut grants = rbac::load_grants(&identity.username).await?;
+		if let Some(key) = req.ext::<ApiKeyInfo>() {
+			grants = grants.restrict_to(&key.scopes);
+		}
+		if let Some(user) = req.ext::<LoggedUser>() {
+			user.set(match req.ext::<ApiKeyInfo>() {
+				Some(key) => format!("{}/{}_{}", identity.username, api_key::KEY_TAG, key.prefix),
+				None => identity.username.clone(),
+			});
+		}
+		req.set_ext(identity);
+		req.set_ext(grants);
+		Ok(next.run(req).await)
+	}
+}
+
+/// Build the cookie session middleware, it must be mounted before [`AuthMiddleware`].
+pub fn session_middleware(cfg: &SessionConfig) -> Result<SessionMiddleware<MemoryStore>> {
+	let secret = match &cfg.secret {
+		Some(secret) => {
+			if secret.len() < 32 {
+				bail!("session secret must be at least 32 bytes");
 			}
+			secret.as_bytes().to_vec()
 		}
+		None => {
+			warn!("No session secret configured, sessions will not survive a restart");
+			rand::random::<[u8; 32]>().to_vec()
+		}
+	};
+	let same_site = match cfg.same_site {
+		SameSitePolicy::Strict => SameSite::Strict,
+		SameSitePolicy::Lax => SameSite::Lax,
+		SameSitePolicy::None => SameSite::None,
+	};
+	let middleware = SessionMiddleware::new(MemoryStore::new(), &secret)
+		.with_cookie_name(&cfg.cookie_name)
+		.with_session_ttl(Some(Duration::from_secs(cfg.ttl_secs)))
+		.with_same_site_policy(same_site)
+		// anonymous requests shouldn't get a session cookie
+		.without_save_unchanged();
+	Ok(middleware)
+}
+
+/// `POST /api/login` with a JSON `{"username", "password"}` body, starts a cookie session.
+pub async fn login<State: Clone + Send + Sync + 'static>(
+	mut req: tide::Request<State>,
+) -> tide::Result {
+	let cred: Cred = req.body_json().await?;
+	let ip = proxy::client_ip(&req);
+	let check = verify_cred(&cred, ip.as_deref()).await?;
+	if let Some(resp) = cred_check_failure_resp(check) {
+		return Ok(resp);
 	}
+	start_session(req.session_mut(), &cred.username)?;
+	Ok(make_resp(204, ""))
+}
+
+/// Log `username` into `session` under a fresh session id, which prevents session fixation
+pub fn start_session(session: &mut Session, username: &str) -> tide::Result<()> {
+	session.regenerate();
+	session.insert(SESSION_USER_KEY, username)?;
+	session.insert(SESSION_AUTH_AT_KEY, utils::unix_now_millis())?;
+	Ok(())
+}
+
+/// Whether credentials of `username` obtained at `authenticated_at` (unix
+/// milliseconds) have been revoked since, see [`credentials_changed_since`]
+async fn is_revoked(username: &str, authenticated_at: i64) -> Result<bool> {
+	let user = find_user(username).await.dot()?;
+	Ok(credentials_changed_since(user.as_ref(), authenticated_at))
+}
+
+/// Whether `user` changed its credentials after `authenticated_at`. A user that went
+/// away or was renamed revokes everything; a user created or renamed into the name later
+/// has a newer `credentials_changed_at`.
+fn credentials_changed_since(user: Option<&user::Model>, authenticated_at: i64) -> bool {
+	match user {
+		Some(user) => user
+			.credentials_changed_at
+			.is_some_and(|changed_at| authenticated_at < changed_at),
+		None => true,
+	}
+}
+
+/// `POST /api/logout`, destroys the current session and clears its cookie.
+pub async fn logout<State: Clone + Send + Sync + 'static>(
+	mut req: tide::Request<State>,
+) -> tide::Result {
+	req.session_mut().destroy();
+	Ok(make_resp(204, ""))
+}
+
+pub fn read_bearer_token<State: Clone + Send + Sync + 'static>(
+	req: &tide::Request<State>,
+) -> Option<String> {
+	let value = req.header("Authorization")?;
+	let token = strip_auth_scheme(value.as_str(), "Bearer")?;
+	Some(token.to_owned())
+}
+
+/// API key from the `X-API-Key` header or `Authorization: ApiKey <key>`
+pub fn read_api_key<State: Clone + Send + Sync + 'static>(
+	req: &tide::Request<State>,
+) -> Option<String> {
+	if let Some(value) = req.header("X-API-Key") {
+		return Some(value.as_str().trim().to_owned());
+	}
+	let value = req.header("Authorization")?;
+	let key = strip_auth_scheme(value.as_str(), "ApiKey")?;
+	Some(key.to_owned())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CredCheck {
+	Valid,
+	Invalid,
+	/// Too many failed logins, retry after this many seconds
+	Locked(u64),
+}
+
+/// Check a credential against the credential cache first and the database on a miss.
+///
+/// Failures count towards the lockout of both the username and the client `ip`.
+/// The lockout is checked before the cache, a locked out account stays locked for
+/// clients already holding its password.
+pub async fn verify_cred(cred: &Cred, ip: Option<&str>) -> Result<CredCheck> {
+	let user = find_user(&cred.username).await.dot()?;
+	if let Some(retry_after) = lockout::check(user.as_ref(), &cred.username, ip).await {
+		return Ok(CredCheck::Locked(retry_after));
+	}
+	if is_cred_cached(cred).await {
+		return Ok(CredCheck::Valid);
+	}
+	if authn(user.as_ref(), &cred.password).await {
+		if let Some(user) = &user {
+			lockout::record_success(user).await.dot()?;
+		}
+		cache_cred(cred).await;
+		Ok(CredCheck::Valid)
+	} else {
+		lockout::record_failure(user.as_ref(), &cred.username, ip)
+			.await
+			.dot()?;
+		Ok(CredCheck::Invalid)
+	}
+}
+
+/// 401 for a malformed `Authorization: Basic` header
+pub fn invalid_basic_auth(err: BasicAuthError) -> AppError {
+	AppError::unauthorized("invalid_basic_auth", format!("invalid basic auth: {err}"))
+}
+
+/// Response for a failed [`verify_cred`], None if the credential is valid
+pub fn cred_check_failure_resp(check: CredCheck) -> Option<Response> {
+	match check {
+		CredCheck::Valid => None,
+		CredCheck::Invalid => Some(
+			AppError::unauthorized("invalid_credentials", "incorrect username or password").into(),
+		),
+		CredCheck::Locked(retry_after) => Some(
+			AppError::too_many_requests(
+				"login_locked",
+				"too many failed logins, retry later",
+				retry_after,
+			)
+			.into(),
+		),
+	}
+}
+
+pub async fn find_user(username: &str) -> Result<Option<user::Model>> {
+	let db = database::get_db_conn().dot()?;
+	let user = user::Entity::find()
+		.filter(user::Column::Username.eq(username))
+		.one(db)
+		.await
+		.dot()?;
+	Ok(user)
+}
+
+/// Verify `password` against the Argon2 hash of `user`.
+///
+/// Unknown users are still checked against a dummy hash, so response times don't
+/// reveal which accounts exist.
+pub async fn authn(user: Option<&user::Model>, password: &str) -> bool {
+	let phc = match user {
+		Some(user) => user.password_hash.clone(),
+		None => DUMMY_HASH.clone(),
+	};
+	// argon2 is deliberately slow, keep it off the async executor
+	let password = password.to_owned();
+	let matched = async_std::task::spawn_blocking(move || verify_password(&password, &phc)).await;
+	user.is_some() && matched
+}
+
+static DUMMY_HASH: LazyLock<String> =
+	LazyLock::new(|| hash_password("dummy password").expect("failed to hash dummy password"));
+
+/// Hash a password with Argon2id into a PHC string, ready to store in `user.password_hash`.
+pub fn hash_password(password: &str) -> Result<String> {
+	let salt = SaltString::generate(&mut OsRng);
+	let hash = Argon2::default()
+		.hash_password(password.as_bytes(), &salt)
+		.dot()?;
+	Ok(hash.to_string())
+}
+
+/// Returns false for anything that is not a valid PHC string, so a plaintext value
+/// left in the column can never match.
+pub fn verify_password(password: &str, phc: &str) -> bool {
+	match PasswordHash::new(phc) {
+		Ok(hash) => Argon2::default()
+			.verify_password(password.as_bytes(), &hash)
+			.is_ok(),
+		Err(_) => false,
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BasicAuthError {
+	InvalidBase64,
+	NotUtf8,
+	MissingColon,
+	EmptyUsername,
 }
 
-async fn authn(username: &str, password: &str) -> tide::Result<bool> {
-	// let db = DB_POOL.get().unwrap();
-	// let user = db.get_user_by_username(username).await?;
-	// if let Some(user) = user {
-	// 	if user.password == password {
-	// 		return Ok(true);
-	// 	}
-	// }
-	// Ok(false)
-	Ok(true)
+impl fmt::Display for BasicAuthError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let msg = match self {
+			BasicAuthError::InvalidBase64 => "credentials are not valid base64",
+			BasicAuthError::NotUtf8 => "credentials are not valid UTF-8",
+			BasicAuthError::MissingColon => "credentials must be in the form username:password",
+			BasicAuthError::EmptyUsername => "username is empty",
+		};
+		f.write_str(msg)
+	}
 }
 
+impl std::error::Error for BasicAuthError {}
+
+/// Read Basic auth credentials from the `Authorization` header.
+///
+/// Returns `Ok(None)` when there is no header or it uses another scheme.
 pub fn read_cred_from_basic_auth<State: Clone + Send + Sync + 'static>(
 	req: &tide::Request<State>,
-) -> Option<Cred> {
-	let value = req.header("Authorization").or(req.header("authorization"));
-	match value {
-		Some(v) => {
-			let s = v.as_str();
-			let base64 = s.strip_prefix("Basic ");
-			match base64 {
-				None => return None,
-				Some(s) => {
-					let v = base64_simd::STANDARD.decode_to_vec(s).unwrap();
-					let s = String::from_utf8_lossy(&v);
-					let s: Vec<&str> = s.split(":").collect();
-					let username = s.first().unwrap();
-					let password = s.get(1).unwrap();
-					return Some(Cred {
-						username: (*username).to_owned(),
-						password: (*password).to_owned(),
-					});
+) -> Result<Option<Cred>, BasicAuthError> {
+	match req.header("Authorization") {
+		Some(value) => parse_basic_auth(value.as_str()),
+		None => Ok(None),
+	}
+}
+
+/// Parse an `Authorization` header value of the Basic scheme (RFC 7617).
+///
+/// The scheme is matched case-insensitively and the credentials are split at the first
+/// colon only, so passwords may contain `:`.
+pub fn parse_basic_auth(value: &str) -> Result<Option<Cred>, BasicAuthError> {
+	let Some(encoded) = strip_auth_scheme(value, "Basic") else {
+		return Ok(None);
+	};
+	let decoded = base64_simd::STANDARD
+		.decode_to_vec(encoded)
+		.map_err(|_| BasicAuthError::InvalidBase64)?;
+	let decoded = String::from_utf8(decoded).map_err(|_| BasicAuthError::NotUtf8)?;
+	let (username, password) = decoded
+		.split_once(':')
+		.ok_or(BasicAuthError::MissingColon)?;
+	if username.is_empty() {
+		return Err(BasicAuthError::EmptyUsername);
+	}
+	Ok(Some(Cred {
+		username: username.to_owned(),
+		password: password.to_owned(),
+	}))
+}
+
+/// The part of an `Authorization` header value after `scheme`, which is matched
+/// case-insensitively as the spec requires.
+fn strip_auth_scheme<'a>(value: &'a str, scheme: &str) -> Option<&'a str> {
+	let value = value.trim();
+	let (name, rest) = value.split_once(' ').unwrap_or((value, ""));
+	if name.eq_ignore_ascii_case(scheme) {
+		Some(rest.trim())
+	} else {
+		None
+	}
+}
+
+/// Cache of recently verified credentials, so argon2 only runs on a cache miss.
+///
+/// Only an HMAC-SHA256 digest of `username:password` is kept, keyed with a random
+/// per-process key, so a memory dump doesn't reveal passwords.
+struct CredCache {
+	key: [u8; 32],
+	entries: DashMap<String, ([u8; 32], Instant)>,
+}
+
+impl CredCache {
+	fn new() -> Self {
+		Self {
+			key: rand::random(),
+			entries: DashMap::new(),
+		}
+	}
+
+	fn mac(&self, cred: &Cred) -> HmacSha256 {
+		let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC accepts keys of any size");
+		mac.update(cred.username.as_bytes());
+		mac.update(b":");
+		mac.update(cred.password.as_bytes());
+		mac
+	}
+
+	fn insert(&self, cred: &Cred, ttl: Duration, max_entries: usize) {
+		if max_entries == 0 {
+			return;
+		}
+		if self.entries.len() >= max_entries && !self.entries.contains_key(&cred.username) {
+			self.entries
+				.retain(|_, (_, cached_at)| cached_at.elapsed() < ttl);
+			while self.entries.len() >= max_entries {
+				let oldest = self
+					.entries
+					.iter()
+					.min_by_key(|entry| entry.value().1)
+					.map(|entry| entry.key().clone());
+				match oldest {
+					Some(username) => self.entries.remove(&username),
+					None => break,
+				};
+			}
+		}
+		let digest = self.mac(cred).finalize().into_bytes().into();
+		self.entries
+			.insert(cred.username.clone(), (digest, Instant::now()));
+	}
+
+	fn contains(&self, cred: &Cred, ttl: Duration) -> bool {
+		let expired = match self.entries.get(&cred.username) {
+			None => return false,
+			Some(entry) => {
+				let (digest, cached_at) = entry.value();
+				if cached_at.elapsed() < ttl {
+					return self.mac(cred).verify_slice(digest).is_ok();
 				}
+				true
 			}
+		};
+		// the read guard must be dropped before removing, or DashMap deadlocks
+		if expired {
+			self.entries.remove(&cred.username);
 		}
-		None => return None,
+		false
+	}
+
+	fn invalidate(&self, username: &str) {
+		self.entries.remove(username);
+	}
+
+	fn clear(&self) -> usize {
+		let len = self.entries.len();
+		self.entries.clear();
+		len
 	}
 }
 
-pub async fn cache_cred(username: &str, cred: String) {
-	CRED_CACHE.insert(username.to_string(), (cred, Instant::now()));
+pub async fn cache_cred(cred: &Cred) {
+	let (ttl, max_entries) = {
+		let cfg = config::cfg().await;
+		(
+			Duration::from_secs(cfg.auth.cred_cache_ttl_secs),
+			cfg.auth.cred_cache_max_entries,
+		)
+	};
+	CRED_CACHE.insert(cred, ttl, max_entries);
 }
 
-const ONE_DAY: u64 = 60 * 60 * 24;
+/// Whether `cred` matches an unexpired cache entry, compared in constant time.
+pub async fn is_cred_cached(cred: &Cred) -> bool {
+	let ttl = Duration::from_secs(config::cfg().await.auth.cred_cache_ttl_secs);
+	CRED_CACHE.contains(cred, ttl)
+}
 
-pub async fn get_cached_cred(username: &str) -> Option<String> {
-	if let Some(x) = CRED_CACHE.get(username) {
-		let timestamp = x.1;
-		if timestamp.elapsed() < Duration::from_secs(ONE_DAY) {
-			let pswd = &x.0;
-			return Some(pswd.clone());
-		} else {
-			CRED_CACHE.remove(username);
+/// Drop the cached credential of `username`, e.g. after a password change or when the
+/// account gets disabled, so the next request is verified against the database again.
+pub fn invalidate_cached_cred(username: &str) {
+	CRED_CACHE.invalidate(username);
+}
+
+/// Drop every cached credential, returns how many there were
+pub fn flush_cred_cache() -> usize {
+	CRED_CACHE.clear()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_hash_password_is_phc_argon2id() {
+		let hash = hash_password("s3cret").unwrap();
+		assert!(hash.starts_with("$argon2id$"));
+		assert!(verify_password("s3cret", &hash));
+		assert!(!verify_password("wrong", &hash));
+	}
+
+	#[test]
+	fn test_hash_password_is_salted() {
+		assert_ne!(
+			hash_password("s3cret").unwrap(),
+			hash_password("s3cret").unwrap()
+		);
+	}
+
+	#[test]
+	fn test_verify_password_rejects_plaintext() {
+		assert!(!verify_password("s3cret", "s3cret"));
+		assert!(!verify_password("", ""));
+	}
+
+	#[test]
+	fn test_credentials_changed_since_ends_earlier_logins_only() {
+		let now = utils::unix_now_millis();
+		let mut user = user::Model {
+			id: 1,
+			username: "test_revoke_credentials_user".to_owned(),
+			password_hash: String::new(),
+			age: 0,
+			failed_login_count: 0,
+			last_failed_login_at: None,
+			locked_until: None,
+			oidc_issuer: None,
+			oidc_subject: None,
+			credentials_changed_at: None,
+		};
+		assert!(!credentials_changed_since(Some(&user), now - 1));
+		user.credentials_changed_at = Some(now);
+		assert!(credentials_changed_since(Some(&user), now - 1));
+		assert!(!credentials_changed_since(Some(&user), now));
+		assert!(credentials_changed_since(None, now));
+	}
+
+	fn cred(username: &str, password: &str) -> Cred {
+		Cred {
+			username: username.to_owned(),
+			password: password.to_owned(),
 		}
 	}
-	None
+
+	const TTL: Duration = Duration::from_secs(60);
+
+	#[test]
+	fn test_cred_cache_matches_only_same_password() {
+		let cache = CredCache::new();
+		cache.insert(&cred("alice", "s3cret"), TTL, 10);
+		assert!(cache.contains(&cred("alice", "s3cret"), TTL));
+		assert!(!cache.contains(&cred("alice", "wrong"), TTL));
+		assert!(!cache.contains(&cred("bob", "s3cret"), TTL));
+	}
+
+	#[test]
+	fn test_cred_cache_does_not_store_plaintext() {
+		let cache = CredCache::new();
+		cache.insert(&cred("alice", "s3cret"), TTL, 10);
+		let entry = cache.entries.get("alice").unwrap();
+		assert_ne!(&entry.value().0[..6], b"s3cret");
+	}
+
+	#[test]
+	fn test_cred_cache_expires_entries() {
+		let cache = CredCache::new();
+		cache.insert(&cred("alice", "s3cret"), TTL, 10);
+		assert!(!cache.contains(&cred("alice", "s3cret"), Duration::ZERO));
+		assert!(cache.entries.is_empty());
+	}
+
+	#[test]
+	fn test_cred_cache_evicts_oldest_beyond_max_entries() {
+		let cache = CredCache::new();
+		cache.insert(&cred("alice", "a"), TTL, 2);
+		cache.insert(&cred("bob", "b"), TTL, 2);
+		cache.insert(&cred("carol", "c"), TTL, 2);
+		assert_eq!(cache.entries.len(), 2);
+		assert!(!cache.contains(&cred("alice", "a"), TTL));
+		assert!(cache.contains(&cred("bob", "b"), TTL));
+		assert!(cache.contains(&cred("carol", "c"), TTL));
+	}
+
+	#[test]
+	fn test_cred_cache_invalidate() {
+		let cache = CredCache::new();
+		cache.insert(&cred("alice", "s3cret"), TTL, 10);
+		cache.invalidate("alice");
+		assert!(!cache.contains(&cred("alice", "s3cret"), TTL));
+	}
+
+	fn basic(userpass: &str) -> String {
+		format!("Basic {}", base64_simd::STANDARD.encode_to_string(userpass))
+	}
+
+	#[test]
+	fn test_parse_basic_auth() {
+		let cred = parse_basic_auth(&basic("alice:s3cret")).unwrap().unwrap();
+		assert_eq!(cred.username, "alice");
+		assert_eq!(cred.password, "s3cret");
+	}
+
+	#[test]
+	fn test_parse_basic_auth_password_with_colon() {
+		let cred = parse_basic_auth(&basic("alice:a:b:c")).unwrap().unwrap();
+		assert_eq!(cred.username, "alice");
+		assert_eq!(cred.password, "a:b:c");
+	}
+
+	#[test]
+	fn test_parse_basic_auth_empty_password() {
+		let cred = parse_basic_auth(&basic("alice:")).unwrap().unwrap();
+		assert_eq!(cred.password, "");
+	}
+
+	#[test]
+	fn test_parse_basic_auth_scheme_case_insensitive() {
+		let encoded = base64_simd::STANDARD.encode_to_string("alice:s3cret");
+		for scheme in ["basic", "BASIC", "bAsIc"] {
+			let cred = parse_basic_auth(&format!("{scheme} {encoded}"))
+				.unwrap()
+				.unwrap();
+			assert_eq!(cred.username, "alice");
+		}
+	}
+
+	#[test]
+	fn test_parse_basic_auth_other_scheme() {
+		assert_eq!(parse_basic_auth("Bearer abc.def.ghi"), Ok(None));
+		assert_eq!(parse_basic_auth("Basicabc"), Ok(None));
+		assert_eq!(parse_basic_auth(""), Ok(None));
+	}
+
+	#[test]
+	fn test_parse_basic_auth_invalid_base64() {
+		assert_eq!(
+			parse_basic_auth("Basic !!!").unwrap_err(),
+			BasicAuthError::InvalidBase64
+		);
+	}
+
+	#[test]
+	fn test_parse_basic_auth_missing_colon() {
+		assert_eq!(
+			parse_basic_auth(&basic("alice")).unwrap_err(),
+			BasicAuthError::MissingColon
+		);
+		assert_eq!(
+			parse_basic_auth("Basic").unwrap_err(),
+			BasicAuthError::MissingColon
+		);
+	}
+
+	#[test]
+	fn test_parse_basic_auth_not_utf8() {
+		let encoded = base64_simd::STANDARD.encode_to_string([0xff, 0xfe, b':', b'x']);
+		assert_eq!(
+			parse_basic_auth(&format!("Basic {encoded}")).unwrap_err(),
+			BasicAuthError::NotUtf8
+		);
+	}
+
+	#[test]
+	fn test_parse_basic_auth_empty_username() {
+		assert_eq!(
+			parse_basic_auth(&basic(":s3cret")).unwrap_err(),
+			BasicAuthError::EmptyUsername
+		);
+	}
 }
@@ -0,0 +1,178 @@
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll};
+
+use futures_lite::io::BufReader;
+use futures_lite::{AsyncRead, ready};
+use tide::{Body, Middleware, Next, Request, StatusCode};
+
+use crate::config::BodyLimitConfig;
+use crate::error::AppError;
+use crate::route_pattern::RoutePattern;
+
+/// Answers 413 for request bodies above the limit of their route, see
+/// [`BodyLimitConfig`].
+///
+/// A too large `Content-Length` is refused before the handler runs. Other bodies are
+/// counted while the handler reads them, reading fails once the limit is passed and
+/// the handler's response is replaced with the 413.
+pub struct BodyLimitMiddleware {
+	max_bytes: u64,
+	routes: Vec<(RoutePattern, u64)>,
+}
+
+impl BodyLimitMiddleware {
+	pub fn new(cfg: &BodyLimitConfig) -> Self {
+		Self {
+			max_bytes: cfg.max_bytes,
+			routes: cfg
+				.routes
+				.iter()
+				.map(|route| (route.route.clone(), route.max_bytes))
+				.collect(),
+		}
+	}
+
+	fn limit<State>(&self, req: &Request<State>) -> u64 {
+		self.routes
+			.iter()
+			.find(|(route, _)| route.matches(req.method(), req.url().path()))
+			.map_or(self.max_bytes, |(_, max_bytes)| *max_bytes)
+	}
+}
+
+fn too_large(limit: u64) -> tide::Result {
+	Ok(AppError::new(
+		StatusCode::PayloadTooLarge,
+		"payload_too_large",
+		format!("request body exceeds {limit} bytes"),
+	)
+	.with_detail("max_bytes", limit)
+	// the rest of the body is never read, the connection can't be reused
+	.with_header("Connection", "close")
+	.into())
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for BodyLimitMiddleware {
+	async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+		let limit = self.limit(&req);
+		let len = req.len();
+		match len {
+			Some(len) if len as u64 > limit => return too_large(limit),
+			Some(0) => return Ok(next.run(req).await),
+			_ => {}
+		}
+
+		let exceeded = Arc::new(AtomicBool::new(false));
+		let body = req.take_body();
+		let mime = body.mime().clone();
+		let mut limited = Body::from_reader(
+			BufReader::new(LimitedReader {
+				inner: body,
+				remaining: limit,
+				exceeded: exceeded.clone(),
+			}),
+			len,
+		);
+		limited.set_mime(mime);
+		req.set_body(limited);
+
+		let resp = next.run(req).await;
+		if exceeded.load(Ordering::Acquire) {
+			return too_large(limit);
+		}
+		Ok(resp)
+	}
+}
+
+/// Fails reads once more than `remaining` bytes came through
+struct LimitedReader<R> {
+	inner: R,
+	remaining: u64,
+	exceeded: Arc<AtomicBool>,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for LimitedReader<R> {
+	fn poll_read(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut [u8],
+	) -> Poll<io::Result<usize>> {
+		// one byte past the limit tells a too large body from one of exactly the limit
+		let max = usize::try_from(self.remaining.saturating_add(1)).unwrap_or(usize::MAX);
+		let max = buf.len().min(max);
+		let n = ready!(Pin::new(&mut self.inner).poll_read(cx, &mut buf[..max]))?;
+		if n as u64 > self.remaining {
+			self.exceeded.store(true, Ordering::Release);
+			return Poll::Ready(Err(io::Error::new(
+				io::ErrorKind::InvalidData,
+				"request body too large",
+			)));
+		}
+		self.remaining -= n as u64;
+		Poll::Ready(Ok(n))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use futures_lite::io::Cursor;
+	use tide::http::{Method, Request as HttpRequest, Response as HttpResponse, Url};
+
+	use super::*;
+	use crate::config::RouteBodyLimit;
+
+	fn app() -> tide::Server<()> {
+		let mut app = tide::new();
+		app.with(BodyLimitMiddleware::new(&BodyLimitConfig {
+			max_bytes: 10,
+			routes: vec![RouteBodyLimit {
+				route: RoutePattern::new("/big", &[]),
+				max_bytes: 100,
+			}],
+		}));
+		for path in ["/echo", "/big"] {
+			app.at(path)
+				.post(|mut req: Request<()>| async move { req.body_string().await });
+		}
+		app
+	}
+
+	async fn post(path: &str, body: &str, chunked: bool) -> HttpResponse {
+		let url = Url::parse(&format!("http://localhost{path}")).unwrap();
+		let mut req = HttpRequest::new(Method::Post, url);
+		let len = (!chunked).then_some(body.len());
+		req.set_body(Body::from_reader(
+			Cursor::new(body.as_bytes().to_vec()),
+			len,
+		));
+		app().respond(req).await.unwrap()
+	}
+
+	#[async_std::test]
+	async fn test_content_length_limit() {
+		let mut resp = post("/echo", "0123456789", false).await;
+		assert_eq!(resp.status(), StatusCode::Ok);
+		assert_eq!(resp.body_string().await.unwrap(), "0123456789");
+
+		let resp = post("/echo", "0123456789a", false).await;
+		assert_eq!(resp.status(), StatusCode::PayloadTooLarge);
+		assert_eq!(resp["Connection"].as_str(), "close");
+
+		let resp = post("/big", "0123456789a", false).await;
+		assert_eq!(resp.status(), StatusCode::Ok);
+	}
+
+	#[async_std::test]
+	async fn test_chunked_body_is_cut_off() {
+		let mut resp = post("/echo", "0123456789", true).await;
+		assert_eq!(resp.status(), StatusCode::Ok);
+		assert_eq!(resp.body_string().await.unwrap(), "0123456789");
+
+		let resp = post("/echo", &"x".repeat(1000), true).await;
+		assert_eq!(resp.status(), StatusCode::PayloadTooLarge);
+	}
+}
@@ -1,7 +1,7 @@
 use core::fmt;
 
 use clap::{Parser, Subcommand, ValueEnum};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 use crate::config::RawConfig;
 
@@ -30,9 +30,40 @@ pub enum Commands {
 		#[arg(short, long)]
 		list: bool,
 	},
+	/// Manage API keys for machine clients
+	ApiKey {
+		#[command(subcommand)]
+		command: ApiKeyCommands,
+	},
+}
+
+#[derive(Subcommand)]
+pub enum ApiKeyCommands {
+	/// Mint a new key, its plaintext is printed only once
+	Mint {
+		/// Username the key acts as
+		#[arg(long)]
+		owner: String,
+		/// What the key is for, e.g. "nightly-backup"
+		#[arg(long)]
+		name: String,
+		/// Comma separated permissions, "*" grants everything the owner has
+		#[arg(long, value_delimiter = ',', default_value = "*")]
+		scopes: Vec<String>,
+		/// The key never expires if unset
+		#[arg(long)]
+		expires_in_days: Option<u32>,
+	},
+	/// Revoke a key by its prefix
+	Revoke { prefix: String },
+	/// List keys, without their secrets
+	List {
+		#[arg(long)]
+		owner: Option<String>,
+	},
 }
 
-#[derive(Deserialize, ValueEnum, Clone, Debug, Default)]
+#[derive(Deserialize, Serialize, ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
 pub enum Env {
 	#[default]
@@ -1,16 +1,24 @@
 use std::path::Path;
 use std::sync::LazyLock;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use async_std::sync::RwLock;
 use clap::Parser;
 
 use anyhow_ext::Context;
 use anyhow_ext::Result;
-use serde::Deserialize;
+use anyhow_ext::bail;
+use serde::{Deserialize, Serialize, Serializer};
 use std::fmt::Debug;
 
+use crate::cli::Env;
+use crate::route_pattern::RoutePattern;
+
 pub static CONFIG: LazyLock<RwLock<Config>> = LazyLock::new(|| RwLock::new(Config::default()));
 
+/// Set once [`load_config`] has put the merged config into [`CONFIG`]
+static CONFIG_LOADED: AtomicBool = AtomicBool::new(false);
+
 #[derive(Deserialize, Default, Debug, Clone, Parser)]
 #[serde(default)]
 pub struct RawConfig {
@@ -35,16 +43,918 @@ pub struct RawConfig {
 	/// Database URL (optional)
 	#[arg(short, long, env = "APP_DB_URL", help = "Database URL (optional)")]
 	pub db_url: Option<String>,
+
+	/// How long to wait for in-flight requests on shutdown, in seconds
+	#[arg(
+		long,
+		env = "APP_DRAIN_TIMEOUT_SECS",
+		help = "Seconds to wait for in-flight requests on shutdown [default: 30]"
+	)]
+	pub drain_timeout_secs: Option<u64>,
+
+	/// How long to keep accepting after a shutdown signal, in seconds
+	#[arg(
+		long,
+		env = "APP_PRE_DRAIN_DELAY_SECS",
+		help = "Seconds to keep accepting connections after a shutdown signal [default: 0 in local, 5 otherwise]"
+	)]
+	pub pre_drain_delay_secs: Option<u64>,
+
+	/// Authentication settings, only configurable in the config file
+	#[arg(skip)]
+	pub auth: AuthConfig,
+
+	/// Cookie session settings, only configurable in the config file
+	#[arg(skip)]
+	pub session: SessionConfig,
+
+	/// JWT bearer token settings, only configurable in the config file
+	#[arg(skip)]
+	pub jwt: JwtConfig,
+
+	/// OpenID Connect login settings, only configurable in the config file
+	#[arg(skip)]
+	pub oidc: OidcConfig,
+
+	/// CORS policy, only configurable in the config file. Defaults depend on `--env`.
+	#[arg(skip)]
+	pub cors: Option<CorsConfig>,
+
+	/// HTTPS listener settings, only configurable in the config file
+	#[arg(skip)]
+	pub tls: TlsConfig,
+
+	/// Listeners used instead of `bind`, only configurable in the config file
+	#[arg(skip)]
+	pub listeners: Vec<ListenerConfig>,
+
+	/// Admin app settings, only configurable in the config file
+	#[arg(skip)]
+	pub admin: AdminConfig,
+
+	/// Request rate limits, only configurable in the config file
+	#[arg(skip)]
+	pub rate_limit: RateLimitConfig,
+
+	/// Request body size limits, only configurable in the config file
+	#[arg(skip)]
+	pub body_limit: BodyLimitConfig,
+
+	/// File upload settings, only configurable in the config file
+	#[arg(skip)]
+	pub upload: UploadConfig,
+
+	/// Request timeouts, only configurable in the config file
+	#[arg(skip)]
+	pub timeout: TimeoutConfig,
+
+	/// Trusted reverse proxies, only configurable in the config file
+	#[arg(skip)]
+	pub proxy: ProxyConfig,
+
+	/// Security response headers, only configurable in the config file. Defaults
+	/// depend on `--env`.
+	#[arg(skip)]
+	pub security_headers: Option<SecurityHeadersConfig>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct AuthConfig {
+	/// How long a verified credential stays in the credential cache, in seconds
+	pub cred_cache_ttl_secs: u64,
+	/// Maximum number of cached credentials, the oldest entry is evicted beyond it.
+	/// 0 disables the cache.
+	pub cred_cache_max_entries: usize,
+	/// Routes reachable without authentication
+	pub public_routes: Vec<RoutePattern>,
+	pub lockout: LockoutConfig,
+	pub password_reset: PasswordResetConfig,
+}
+
+/// Brute-force protection, failures are counted per username and per client IP
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct LockoutConfig {
+	pub enabled: bool,
+	/// Failures allowed before the first lockout
+	pub max_failures: u32,
+	/// Length of the first lockout in seconds, doubled on every further failure
+	pub base_lockout_secs: u64,
+	pub max_lockout_secs: u64,
+	/// Failures older than this are forgotten, in seconds
+	pub failure_window_secs: u64,
+}
+
+impl Default for LockoutConfig {
+	fn default() -> Self {
+		Self {
+			enabled: true,
+			max_failures: 5,
+			base_lockout_secs: 60,
+			max_lockout_secs: 60 * 60,
+			failure_window_secs: 15 * 60,
+		}
+	}
+}
+
+/// Self-service password reset through single-use tokens
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct PasswordResetConfig {
+	/// How long a reset token stays valid, in seconds
+	pub token_ttl_secs: u64,
+	/// How reset tokens reach the user
+	pub notifier: NotifierKind,
+	/// File the `file` notifier appends to, one JSON object per line
+	pub outbox_file: String,
+	/// Reset requests accepted per username and hour, further ones are dropped
+	/// silently. 0 disables the limit.
+	pub requests_per_user_per_hour: u32,
+}
+
+impl Default for PasswordResetConfig {
+	fn default() -> Self {
+		Self {
+			token_ttl_secs: 30 * 60,
+			notifier: NotifierKind::Log,
+			outbox_file: "notifications.jsonl".to_string(),
+			requests_per_user_per_hour: 3,
+		}
+	}
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum NotifierKind {
+	Log,
+	File,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct SessionConfig {
+	/// Secret used to sign session cookies, at least 32 bytes.
+	/// A random one is generated on startup if unset, which logs everyone out on restart.
+	#[serde(serialize_with = "redact")]
+	pub secret: Option<String>,
+	pub cookie_name: String,
+	/// Session lifetime in seconds
+	pub ttl_secs: u64,
+	pub same_site: SameSitePolicy,
+}
+
+impl Default for SessionConfig {
+	fn default() -> Self {
+		Self {
+			secret: None,
+			cookie_name: "sid".to_string(),
+			ttl_secs: 60 * 60 * 24,
+			same_site: SameSitePolicy::Lax,
+		}
+	}
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SameSitePolicy {
+	Strict,
+	Lax,
+	None,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct JwtConfig {
+	pub algorithm: JwtAlgorithm,
+	/// HS256 signing secret, at least 32 bytes. A random one is generated on startup
+	/// if unset, which invalidates all issued tokens on restart.
+	#[serde(serialize_with = "redact")]
+	pub secret: Option<String>,
+	/// PEM private key file for RS256/EdDSA, only needed to issue tokens
+	pub private_key_file: Option<String>,
+	/// PEM public key file for RS256/EdDSA
+	pub public_key_file: Option<String>,
+	pub issuer: String,
+	pub audience: String,
+	/// Token lifetime in seconds
+	pub ttl_secs: u64,
+	/// Allowed clock skew in seconds when checking `exp` and `nbf`
+	pub leeway_secs: u64,
+}
+
+impl Default for JwtConfig {
+	fn default() -> Self {
+		Self {
+			algorithm: JwtAlgorithm::Hs256,
+			secret: None,
+			private_key_file: None,
+			public_key_file: None,
+			issuer: "rust-tide-template".to_string(),
+			audience: "rust-tide-template".to_string(),
+			ttl_secs: 60 * 60,
+			leeway_secs: 30,
+		}
+	}
+}
+
+impl JwtConfig {
+	pub fn validate(&self) -> Result<()> {
+		match &self.secret {
+			Some(secret) if self.algorithm == JwtAlgorithm::Hs256 && secret.len() < 32 => {
+				bail!("secret must be at least 32 bytes for HS256")
+			}
+			_ => Ok(()),
+		}
+	}
+}
+
+/// Login through an external OpenID Connect provider with the authorization code flow.
+/// The callback relies on the session cookie, so `session.same_site` can't be `strict`.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct OidcConfig {
+	pub enabled: bool,
+	/// Issuer URL, the discovery document is loaded from
+	/// `<issuer_url>/.well-known/openid-configuration`
+	pub issuer_url: String,
+	pub client_id: String,
+	/// Sent with HTTP Basic auth to the token endpoint, public clients leave it unset
+	#[serde(serialize_with = "redact")]
+	pub client_secret: Option<String>,
+	pub scopes: Vec<String>,
+	/// Our callback URL as registered at the provider, ends with `/api/oidc/callback`
+	pub redirect_url: String,
+	/// ID token claim used as the username of provisioned users, `sub` when missing
+	pub username_claim: String,
+	/// Whether a first OIDC login may take over an existing local user of the same
+	/// name. Otherwise such logins are refused.
+	pub link_existing_users: bool,
+	/// Whether the first login of an unknown identity creates a user. Otherwise only
+	/// identities already linked, or linked through `link_existing_users`, get in.
+	pub auto_provision: bool,
+	/// Where the browser goes after a successful login
+	pub post_login_redirect: String,
+}
+
+impl Default for OidcConfig {
+	fn default() -> Self {
+		Self {
+			enabled: false,
+			issuer_url: String::new(),
+			client_id: String::new(),
+			client_secret: None,
+			scopes: vec![
+				"openid".to_string(),
+				"profile".to_string(),
+				"email".to_string(),
+			],
+			redirect_url: "http://localhost:8888/api/oidc/callback".to_string(),
+			username_claim: "preferred_username".to_string(),
+			link_existing_users: false,
+			auto_provision: true,
+			post_login_redirect: "/".to_string(),
+		}
+	}
+}
+
+impl OidcConfig {
+	/// A strict session cookie isn't sent along with the provider's redirect back
+	pub fn validate(&self, session: &SessionConfig) -> Result<()> {
+		if self.enabled && session.same_site == SameSitePolicy::Strict {
+			bail!("the callback needs the session cookie, session.same_site can't be \"strict\"");
+		}
+		Ok(())
+	}
+}
+
+/// Which cross-origin requests browsers may make.
+///
+/// A `[cors]` section in the config file replaces the per-environment defaults as a
+/// whole, fields it leaves out take the values of [`CorsConfig::default`].
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct CorsConfig {
+	/// Allowed `Origin`s. `*` allows any origin, other entries containing `*` are
+	/// globs like `https://*.example.com`, entries written as `/.../` are regexes
+	/// matched against the whole origin.
+	pub allowed_origins: Vec<String>,
+	pub allowed_methods: Vec<String>,
+	/// Request headers a preflight may ask for, `*` allows any
+	pub allowed_headers: Vec<String>,
+	/// Response headers readable by scripts
+	pub exposed_headers: Vec<String>,
+	pub allow_credentials: bool,
+	/// How long browsers may cache a preflight, in seconds
+	pub max_age_secs: u64,
+}
+
+impl Default for CorsConfig {
+	fn default() -> Self {
+		Self {
+			allowed_origins: vec![],
+			allowed_methods: ["GET", "POST", "PUT", "PATCH", "DELETE", "OPTIONS"]
+				.map(String::from)
+				.to_vec(),
+			allowed_headers: [
+				"Origin",
+				"X-Requested-With",
+				"Content-Type",
+				"Accept",
+				"Authorization",
+				"X-API-Key",
+			]
+			.map(String::from)
+			.to_vec(),
+			exposed_headers: [
+				"X-Request-Id",
+				"Retry-After",
+				"RateLimit-Limit",
+				"RateLimit-Remaining",
+				"RateLimit-Reset",
+				"RateLimit-Policy",
+			]
+			.map(String::from)
+			.to_vec(),
+			allow_credentials: true,
+			// Chrome caps it at 7200
+			max_age_secs: 7200,
+		}
+	}
+}
+
+impl CorsConfig {
+	/// Defaults without a `[cors]` section: the local frontend dev server is allowed
+	/// on `local`, other environments allow no cross-origin requests.
+	pub fn for_env(env: Env) -> Self {
+		match env {
+			Env::Local => Self {
+				allowed_origins: vec![
+					"http://localhost:5173".to_string(),
+					"http://127.0.0.1:5173".to_string(),
+				],
+				..Default::default()
+			},
+			Env::Uat | Env::Prd => Self::default(),
+		}
+	}
+
+	pub fn validate(&self) -> Result<()> {
+		if self.allow_credentials && self.allowed_origins.iter().any(|o| o == "*") {
+			// every site could make credentialed requests and read the responses
+			bail!("allowed_origins = [\"*\"] can't be combined with allow_credentials");
+		}
+		Ok(())
+	}
+}
+
+/// Security headers added to every response of the public app, headers a handler set
+/// itself are left alone. Empty values leave a header out.
+///
+/// A `[security_headers]` section in the config file replaces the per-environment
+/// defaults as a whole, fields it leaves out take the values of
+/// [`SecurityHeadersConfig::default`].
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct SecurityHeadersConfig {
+	pub enabled: bool,
+	/// `max-age` of `Strict-Transport-Security` in seconds, only sent on HTTPS
+	/// requests. 0 leaves the header out.
+	pub hsts_max_age_secs: u64,
+	pub hsts_include_subdomains: bool,
+	pub hsts_preload: bool,
+	/// `Content-Security-Policy`, every `{nonce}` is replaced by a nonce fresh for each
+	/// request. Handlers find it in the [`crate::security_headers::CspNonce`] extension.
+	pub content_security_policy: String,
+	/// Send `Content-Security-Policy-Report-Only` instead, violations are only reported
+	pub csp_report_only: bool,
+	/// `X-Frame-Options`, also added to the CSP as `frame-ancestors` unless it has one
+	pub frame_options: FrameOptions,
+	/// `X-Content-Type-Options: nosniff`
+	pub content_type_nosniff: bool,
+	pub referrer_policy: String,
+	pub permissions_policy: String,
+	/// Overrides for some routes, the first matching one applies
+	pub routes: Vec<RouteSecurityHeaders>,
+}
+
+impl Default for SecurityHeadersConfig {
+	fn default() -> Self {
+		Self {
+			enabled: true,
+			hsts_max_age_secs: 365 * 24 * 60 * 60,
+			hsts_include_subdomains: true,
+			hsts_preload: false,
+			content_security_policy: "default-src 'self'; script-src 'self' 'nonce-{nonce}'; \
+				object-src 'none'; base-uri 'self'"
+				.to_string(),
+			csp_report_only: false,
+			frame_options: FrameOptions::Deny,
+			content_type_nosniff: true,
+			referrer_policy: "strict-origin-when-cross-origin".to_string(),
+			permissions_policy: "camera=(), microphone=(), geolocation=(), payment=()".to_string(),
+			routes: vec![],
+		}
+	}
+}
+
+impl SecurityHeadersConfig {
+	/// Defaults without a `[security_headers]` section: no HSTS and a report-only CSP on
+	/// `local`, where the app runs on plain HTTP next to the frontend dev server.
+	pub fn for_env(env: Env) -> Self {
+		match env {
+			Env::Local => Self {
+				hsts_max_age_secs: 0,
+				csp_report_only: true,
+				..Default::default()
+			},
+			Env::Uat | Env::Prd => Self::default(),
+		}
+	}
+
+	pub fn validate(&self) -> Result<()> {
+		let values = [
+			&self.content_security_policy,
+			&self.referrer_policy,
+			&self.permissions_policy,
+		];
+		let route_values = self.routes.iter().flat_map(|route| {
+			[
+				&route.content_security_policy,
+				&route.referrer_policy,
+				&route.permissions_policy,
+			]
+			.into_iter()
+			.flatten()
+		});
+		for value in values.into_iter().chain(route_values) {
+			if !value.bytes().all(|b| b == b' ' || b.is_ascii_graphic()) {
+				bail!("header value {value:?} may only contain printable ASCII");
+			}
+		}
+		Ok(())
+	}
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FrameOptions {
+	/// Never shown in a frame
+	Deny,
+	/// Only in frames of our own origin
+	SameOrigin,
+	/// Anyone may frame the page, no header is sent
+	Allow,
+}
+
+/// Security headers of some routes, fields left out take the global values
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct RouteSecurityHeaders {
+	#[serde(flatten)]
+	pub route: RoutePattern,
+	#[serde(default)]
+	pub content_security_policy: Option<String>,
+	#[serde(default)]
+	pub csp_report_only: Option<bool>,
+	#[serde(default)]
+	pub frame_options: Option<FrameOptions>,
+	#[serde(default)]
+	pub referrer_policy: Option<String>,
+	#[serde(default)]
+	pub permissions_policy: Option<String>,
+}
+
+/// One address the server accepts connections on, either `bind` or `unix` is set
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenerConfig {
+	/// TCP address like `0.0.0.0:8888` or `[::1]:8888`
+	pub bind: Option<String>,
+	/// Unix domain socket path, a stale socket file is replaced on startup
+	pub unix: Option<String>,
+	/// Octal permissions of the socket file, e.g. `"660"`
+	pub mode: Option<String>,
+	/// Whether to serve HTTPS with the `[tls]` certificate, defaults to `tls.enabled`
+	pub tls: Option<bool>,
+	/// Only these routes are served on this listener, all when empty
+	pub routes: Vec<RoutePattern>,
+	/// Routes never served on this listener, e.g. the admin endpoints on a public port
+	pub exclude_routes: Vec<RoutePattern>,
+}
+
+impl ListenerConfig {
+	pub fn tcp(bind: &str) -> Self {
+		Self {
+			bind: Some(bind.to_string()),
+			..Default::default()
+		}
+	}
+
+	/// `mode` parsed as octal permission bits
+	pub fn mode_bits(&self) -> Result<Option<u32>> {
+		self.mode
+			.as_deref()
+			.map(|mode| {
+				u32::from_str_radix(mode, 8)
+					.ok()
+					.filter(|bits| *bits <= 0o777)
+					.with_context(|| format!("invalid socket mode {mode:?}, expected e.g. \"660\""))
+			})
+			.transpose()
+	}
+
+	pub fn validate(&self) -> Result<()> {
+		match (&self.bind, &self.unix) {
+			(Some(_), None) if self.mode.is_some() => {
+				bail!("`mode` only applies to `unix` listeners")
+			}
+			(Some(_), None) | (None, Some(_)) => {}
+			_ => bail!("a listener needs exactly one of `bind` and `unix`"),
+		}
+		self.mode_bits()?;
+		Ok(())
+	}
+}
+
+/// Serve HTTPS on `bind` instead of plain HTTP
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct TlsConfig {
+	pub enabled: bool,
+	/// PEM certificate chain, leaf first
+	pub cert_file: String,
+	/// PEM private key, PKCS#8, PKCS#1 or SEC1
+	pub key_file: String,
+	pub min_version: TlsVersion,
+	/// PEM CA bundle client certificates are verified against, enables mTLS when set
+	pub client_ca_file: Option<String>,
+	/// Whether clients without a certificate are refused, only used with `client_ca_file`
+	pub client_cert_required: bool,
+	/// How often the certificate and key files are checked for changes, in seconds.
+	/// 0 disables the reload.
+	pub reload_interval_secs: u64,
+	/// Connections that don't finish the handshake in time are closed, in seconds
+	pub handshake_timeout_secs: u64,
+}
+
+impl Default for TlsConfig {
+	fn default() -> Self {
+		Self {
+			enabled: false,
+			cert_file: String::new(),
+			key_file: String::new(),
+			min_version: TlsVersion::Tls12,
+			client_ca_file: None,
+			client_cert_required: true,
+			reload_interval_secs: 30,
+			handshake_timeout_secs: 10,
+		}
+	}
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlsVersion {
+	#[serde(rename = "1.2")]
+	Tls12,
+	#[serde(rename = "1.3")]
+	Tls13,
+}
+
+/// Operational endpoints (health, metrics, log level, config dump, cache flush) are
+/// served by a separate app on their own listeners, never on the public ones
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct AdminConfig {
+	pub enabled: bool,
+	/// Probes need them too, so in a pod they bind `0.0.0.0` rather than the loopback
+	/// default, which the kubelet can't reach
+	pub listeners: Vec<ListenerConfig>,
+	/// SHA-256 hex digests of the bearer tokens accepted by the admin app, e.g. from
+	/// `printf %s "$TOKEN" | sha256sum`. Only `public_routes` are reachable while empty.
+	#[serde(serialize_with = "redact_all")]
+	pub token_sha256: Vec<String>,
+	/// Admin routes reachable without a token, e.g. for probes and the metrics scraper
+	pub public_routes: Vec<RoutePattern>,
+}
+
+impl Default for AdminConfig {
+	fn default() -> Self {
+		Self {
+			enabled: true,
+			listeners: vec![ListenerConfig::tcp("127.0.0.1:9999")],
+			token_sha256: vec![],
+			public_routes: vec![
+				RoutePattern::new("/healthz", &["GET"]),
+				RoutePattern::new("/readyz", &["GET"]),
+				RoutePattern::new("/metrics", &["GET"]),
+			],
+		}
+	}
+}
+
+/// Token bucket rate limiting per client: a client may send `burst` requests at once,
+/// the bucket refills at `requests_per_sec`. Client IPs are resolved through `[proxy]`.
+///
+/// Limits keyed by IP, `ip_burst` and routes with `key = "ip"`, are checked before
+/// authentication, so failed credential checks count too. Limits keyed by user or API
+/// key are checked after it.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct RateLimitConfig {
+	pub enabled: bool,
+	/// What a bucket belongs to, requests without a username or API key fall back to
+	/// the client IP
+	pub key: RateLimitKey,
+	pub requests_per_sec: f64,
+	pub burst: u32,
+	/// Limit per client IP on every request, checked before authentication. 0 disables.
+	pub ip_requests_per_sec: f64,
+	pub ip_burst: u32,
+	/// Limits for some routes, the first matching one applies instead of the global
+	/// limit. Each has buckets of its own.
+	pub routes: Vec<RouteRateLimit>,
+	/// Buckets kept in memory at most, full ones and then the least recently used are
+	/// dropped beyond it
+	pub max_tracked_keys: usize,
+}
+
+impl Default for RateLimitConfig {
+	fn default() -> Self {
+		Self {
+			enabled: true,
+			key: RateLimitKey::Ip,
+			requests_per_sec: 20.0,
+			burst: 100,
+			ip_requests_per_sec: 50.0,
+			ip_burst: 200,
+			routes: vec![],
+			max_tracked_keys: 100_000,
+		}
+	}
+}
+
+impl RateLimitConfig {
+	pub fn validate(&self) -> Result<()> {
+		let limits = [
+			(self.requests_per_sec, self.burst, "the global limit"),
+			(self.ip_requests_per_sec, self.ip_burst, "the IP limit"),
+		]
+		.into_iter()
+		.chain(
+			self.routes
+				.iter()
+				.map(|r| (r.requests_per_sec, r.burst, r.route.path.as_str())),
+		);
+		for (requests_per_sec, burst, name) in limits {
+			// a bucket that never refills would lock clients out for good
+			if burst > 0 && !(requests_per_sec > 0.0 && requests_per_sec.is_finite()) {
+				bail!("requests_per_sec of {name} must be positive");
+			}
+		}
+		Ok(())
+	}
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitKey {
+	Ip,
+	User,
+	ApiKey,
+}
+
+/// A rate limit for the routes matching `path` and `methods`, `burst = 0` exempts them
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct RouteRateLimit {
+	#[serde(flatten)]
+	pub route: RoutePattern,
+	#[serde(default)]
+	pub requests_per_sec: f64,
+	pub burst: u32,
+	/// Replaces the global `key` for these routes
+	#[serde(default)]
+	pub key: Option<RateLimitKey>,
+}
+
+/// Maximum request body sizes in bytes, answered with 413 when exceeded. Bodies
+/// without a `Content-Length` are cut off as soon as they grow past the limit.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct BodyLimitConfig {
+	pub max_bytes: u64,
+	/// Limits for some routes, the first matching one applies instead of `max_bytes`
+	pub routes: Vec<RouteBodyLimit>,
+}
+
+impl Default for BodyLimitConfig {
+	fn default() -> Self {
+		Self {
+			max_bytes: 1024 * 1024,
+			routes: vec![RouteBodyLimit {
+				route: RoutePattern::new("/api/uploads", &["POST"]),
+				max_bytes: 100 * 1024 * 1024,
+			}],
+		}
+	}
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct RouteBodyLimit {
+	#[serde(flatten)]
+	pub route: RoutePattern,
+	pub max_bytes: u64,
+}
+
+/// `POST /api/uploads`, multipart file uploads streamed to disk
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct UploadConfig {
+	/// Uploaded files are written here under random names, created when missing
+	pub dir: String,
+	/// Maximum size of one file in bytes, the whole request is bounded by `[body_limit]`
+	pub max_file_bytes: u64,
+	/// Maximum number of files in one request
+	pub max_files: usize,
+	/// Accepted `Content-Type`s of the files, `*` matches anything, e.g. `image/*`.
+	/// Files claiming a type with a well known signature, like PNG or PDF, must start
+	/// with it.
+	pub allowed_mime_types: Vec<String>,
+}
+
+impl Default for UploadConfig {
+	fn default() -> Self {
+		Self {
+			dir: "uploads".to_string(),
+			max_file_bytes: 20 * 1024 * 1024,
+			max_files: 10,
+			allowed_mime_types: [
+				"image/png",
+				"image/jpeg",
+				"image/gif",
+				"application/pdf",
+				"text/plain",
+			]
+			.map(String::from)
+			.to_vec(),
+		}
+	}
+}
+
+/// Requests taking longer than their timeout are aborted and answered with `status`,
+/// ones slower than the slow threshold are logged. Both are in milliseconds, 0
+/// disables them.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct TimeoutConfig {
+	pub timeout_ms: u64,
+	pub slow_ms: u64,
+	/// 504 or 503
+	pub status: u16,
+	/// Timeouts for some routes, the first matching one applies
+	pub routes: Vec<RouteTimeout>,
+}
+
+impl Default for TimeoutConfig {
+	fn default() -> Self {
+		Self {
+			timeout_ms: 30_000,
+			slow_ms: 1_000,
+			status: 504,
+			routes: vec![RouteTimeout {
+				route: RoutePattern::new("/api/uploads", &["POST"]),
+				timeout_ms: 10 * 60 * 1000,
+				slow_ms: Some(0),
+			}],
+		}
+	}
+}
+
+impl TimeoutConfig {
+	pub fn validate(&self) -> Result<()> {
+		if !matches!(self.status, 503 | 504) {
+			bail!("status must be 503 or 504, not {}", self.status);
+		}
+		Ok(())
+	}
+}
+
+/// Reverse proxies whose `Forwarded` and `X-Forwarded-*` headers are believed, the
+/// client IP, scheme and host of requests through them are taken from those headers
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct ProxyConfig {
+	/// Addresses or CIDR ranges like `10.0.0.0/8`, `unix` trusts peers on Unix sockets.
+	/// Empty ignores the headers.
+	pub trusted_proxies: Vec<String>,
+}
+
+impl ProxyConfig {
+	pub fn validate(&self) -> Result<()> {
+		crate::proxy::TrustedProxies::parse(&self.trusted_proxies)?;
+		Ok(())
+	}
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct RouteTimeout {
+	#[serde(flatten)]
+	pub route: RoutePattern,
+	pub timeout_ms: u64,
+	/// Falls back to the global `slow_ms`
+	#[serde(default)]
+	pub slow_ms: Option<u64>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JwtAlgorithm {
+	#[serde(rename = "HS256")]
+	Hs256,
+	#[serde(rename = "RS256")]
+	Rs256,
+	#[serde(rename = "EdDSA")]
+	EdDsa,
+}
+
+impl Default for AuthConfig {
+	fn default() -> Self {
+		Self {
+			cred_cache_ttl_secs: 60 * 60 * 24,
+			cred_cache_max_entries: 10_000,
+			public_routes: vec![
+				RoutePattern::new("/", &["GET"]),
+				RoutePattern::new("/api/login", &["POST"]),
+				RoutePattern::new("/api/logout", &["POST"]),
+				RoutePattern::new("/api/token", &["POST"]),
+				RoutePattern::new("/api/password-reset/*", &["POST"]),
+				RoutePattern::new("/api/oidc/login", &["GET"]),
+				RoutePattern::new("/api/oidc/callback", &["GET"]),
+			],
+			lockout: LockoutConfig::default(),
+			password_reset: PasswordResetConfig::default(),
+		}
+	}
 }
 
-#[derive(Debug, Clone, Default)]
+#[derive(Serialize, Debug, Clone, Default)]
 pub struct Config {
-	pub bind: String,
+	pub env: Env,
 	pub log_directive: String,
+	#[serde(serialize_with = "redact_url_password")]
 	pub db_url: Option<String>,
+	pub drain_timeout_secs: u64,
+	/// Connections are still accepted this long after a shutdown signal, while
+	/// `/readyz` already fails, so load balancers stop routing here before the
+	/// listeners close
+	pub pre_drain_delay_secs: u64,
+	pub auth: AuthConfig,
+	pub session: SessionConfig,
+	pub jwt: JwtConfig,
+	pub oidc: OidcConfig,
+	pub cors: CorsConfig,
+	pub tls: TlsConfig,
+	/// Never empty after [`merge`], falls back to a single listener on `bind`
+	pub listeners: Vec<ListenerConfig>,
+	pub admin: AdminConfig,
+	pub rate_limit: RateLimitConfig,
+	pub body_limit: BodyLimitConfig,
+	pub upload: UploadConfig,
+	pub timeout: TimeoutConfig,
+	pub proxy: ProxyConfig,
+	pub security_headers: SecurityHeadersConfig,
 	pub config_file: Option<String>,
 }
 
+const REDACTED: &str = "***";
+
+/// Secrets show up as `***` when the config is dumped, e.g. by `GET /api/config`
+fn redact<S: Serializer>(secret: &Option<String>, serializer: S) -> Result<S::Ok, S::Error> {
+	match secret {
+		Some(_) => serializer.serialize_some(REDACTED),
+		None => serializer.serialize_none(),
+	}
+}
+
+fn redact_all<S: Serializer>(secrets: &[String], serializer: S) -> Result<S::Ok, S::Error> {
+	serializer.collect_seq(secrets.iter().map(|_| REDACTED))
+}
+
+/// Only the password of a database URL is a secret, the rest helps debugging
+fn redact_url_password<S: Serializer>(
+	url: &Option<String>,
+	serializer: S,
+) -> Result<S::Ok, S::Error> {
+	let redacted = url.as_deref().map(|url| match url::Url::parse(url) {
+		Ok(mut parsed) if parsed.password().is_some() => {
+			let _ = parsed.set_password(Some(REDACTED));
+			parsed.to_string()
+		}
+		Ok(_) => url.to_string(),
+		// can't tell which part is the password
+		Err(_) => REDACTED.to_string(),
+	});
+	redacted.serialize(serializer)
+}
+
 fn default_addr() -> String {
 	"0.0.0.0:8888".to_string()
 }
@@ -53,19 +963,58 @@ fn default_log_directive() -> String {
 	"info,tide=warn".to_string()
 }
 
+const DEFAULT_DRAIN_TIMEOUT_SECS: u64 = 30;
+/// Long enough for a few failed readiness probes, local runs stop right away
+const DEFAULT_PRE_DRAIN_DELAY_SECS: u64 = 5;
+
 /// Merge config sources with priority: CLI > config file > env var > default.
 ///
 /// Since clap already handles CLI > env var internally (CLI arg wins over env),
 /// `cli` contains the result of that merge. We then layer the config file
 /// between "explicitly set by CLI/env" and "default".
-pub fn merge(cli: RawConfig, file: RawConfig) -> Config {
+pub fn merge(env: Env, cli: RawConfig, file: RawConfig) -> Config {
+	// `--bind` replaces the listeners of the config file
+	let bind_from_cli = cli.bind.is_some();
+	let bind = cli.bind.or(file.bind).unwrap_or_else(default_addr);
+	let listeners = if bind_from_cli || file.listeners.is_empty() {
+		vec![ListenerConfig::tcp(&bind)]
+	} else {
+		file.listeners
+	};
 	Config {
-		bind: cli.bind.or(file.bind).unwrap_or_else(default_addr),
+		env,
 		log_directive: cli
 			.log_directive
 			.or(file.log_directive)
 			.unwrap_or_else(default_log_directive),
 		db_url: cli.db_url.or(file.db_url),
+		drain_timeout_secs: cli
+			.drain_timeout_secs
+			.or(file.drain_timeout_secs)
+			.unwrap_or(DEFAULT_DRAIN_TIMEOUT_SECS),
+		pre_drain_delay_secs: cli
+			.pre_drain_delay_secs
+			.or(file.pre_drain_delay_secs)
+			.unwrap_or(match env {
+				Env::Local => 0,
+				_ => DEFAULT_PRE_DRAIN_DELAY_SECS,
+			}),
+		auth: file.auth,
+		session: file.session,
+		jwt: file.jwt,
+		oidc: file.oidc,
+		cors: file.cors.unwrap_or_else(|| CorsConfig::for_env(env)),
+		tls: file.tls,
+		listeners,
+		admin: file.admin,
+		rate_limit: file.rate_limit,
+		body_limit: file.body_limit,
+		upload: file.upload,
+		timeout: file.timeout,
+		proxy: file.proxy,
+		security_headers: file
+			.security_headers
+			.unwrap_or_else(|| SecurityHeadersConfig::for_env(env)),
 		config_file: None,
 	}
 }
@@ -88,7 +1037,7 @@ pub fn load_config_file(path: &str) -> Result<RawConfig> {
 	Ok(file_config)
 }
 
-pub async fn load_config(cli: RawConfig, config_file_path: Option<&str>) -> Result<()> {
+pub async fn load_config(env: Env, cli: RawConfig, config_file_path: Option<&str>) -> Result<()> {
 	let file_config = match config_file_path {
 		Some(path) => load_config_file(path)?,
 		None => {
@@ -97,14 +1046,48 @@ pub async fn load_config(cli: RawConfig, config_file_path: Option<&str>) -> Resu
 		}
 	};
 
-	let mut config = merge(cli, file_config);
+	let mut config = merge(env, cli, file_config);
 	config.config_file = config_file_path.map(|s| s.to_string());
+	for listener in &config.listeners {
+		listener
+			.validate()
+			.with_context(|| format!("invalid listener {listener:?}"))?;
+	}
+	config
+		.rate_limit
+		.validate()
+		.context("invalid [rate_limit]")?;
+	config.jwt.validate().context("invalid [jwt]")?;
+	config
+		.oidc
+		.validate(&config.session)
+		.context("invalid [oidc]")?;
+	config.cors.validate().context("invalid [cors]")?;
+	config.timeout.validate().context("invalid [timeout]")?;
+	config.proxy.validate().context("invalid [proxy]")?;
+	config
+		.security_headers
+		.validate()
+		.context("invalid [security_headers]")?;
+	if config.admin.enabled {
+		for listener in &config.admin.listeners {
+			listener
+				.validate()
+				.with_context(|| format!("invalid admin listener {listener:?}"))?;
+		}
+	}
 
 	let mut lock = CONFIG.write().await;
 	*lock = config;
+	CONFIG_LOADED.store(true, Ordering::Release);
 	Ok(())
 }
 
+/// Whether the config has been loaded, the defaults are used until then
+pub fn is_loaded() -> bool {
+	CONFIG_LOADED.load(Ordering::Acquire)
+}
+
 /// Get a read lock guard for zero-copy access to the global config.
 ///
 /// # Returns
@@ -118,7 +1101,7 @@ pub async fn load_config(cli: RawConfig, config_file_path: Option<&str>) -> Resu
 /// ```no_run
 /// # use rust_tide_template::config;
 /// let cfg = config::cfg().await;
-/// println!("Binding to: {}", cfg.bind);
+/// println!("Listeners: {:?}", cfg.listeners);
 /// println!("Log directive: {}", cfg.log_directive);
 /// // Guard is released here when cfg goes out of scope
 /// ```
@@ -127,7 +1110,7 @@ pub async fn load_config(cli: RawConfig, config_file_path: Option<&str>) -> Resu
 /// ```no_run
 /// # use rust_tide_template::config;
 /// let cfg = config::cfg().await;
-/// let bind = cfg.bind.clone();
+/// let listeners = cfg.listeners.clone();
 /// let directive = cfg.log_directive.clone();
 /// // Use cloned values after guard is released
 /// ```
@@ -173,7 +1156,7 @@ pub async fn cfg() -> async_std::sync::RwLockReadGuard<'static, Config> {
 /// # use rust_tide_template::config;
 /// # async fn example() {
 /// let new_config = Config {
-///     bind: "127.0.0.1:8080".to_string(),
+///     listeners: vec![config::ListenerConfig::tcp("127.0.0.1:8080")],
 ///     ..Default::default()
 /// };
 /// config::set_cfg(new_config).await;
@@ -192,10 +1175,12 @@ mod tests {
 	fn test_merge_all_defaults() {
 		let cli = RawConfig::default();
 		let file = RawConfig::default();
-		let config = merge(cli, file);
+		let config = merge(Env::Local, cli, file);
 
-		assert_eq!(config.bind, "0.0.0.0:8888");
+		assert_eq!(config.listeners, vec![ListenerConfig::tcp("0.0.0.0:8888")]);
 		assert_eq!(config.log_directive, "info,tide=warn");
+		assert_eq!(config.drain_timeout_secs, 30);
+		assert_eq!(config.pre_drain_delay_secs, 0);
 		assert_eq!(config.db_url, None);
 	}
 
@@ -205,15 +1190,20 @@ mod tests {
 			bind: Some("127.0.0.1:3000".to_string()),
 			log_directive: Some("debug".to_string()),
 			db_url: Some("sqlite:cli.db".to_string()),
+			..Default::default()
 		};
 		let file = RawConfig {
 			bind: Some("0.0.0.0:9999".to_string()),
 			log_directive: Some("warn".to_string()),
 			db_url: Some("sqlite:file.db".to_string()),
+			..Default::default()
 		};
-		let config = merge(cli, file);
+		let config = merge(Env::Local, cli, file);
 
-		assert_eq!(config.bind, "127.0.0.1:3000");
+		assert_eq!(
+			config.listeners,
+			vec![ListenerConfig::tcp("127.0.0.1:3000")]
+		);
 		assert_eq!(config.log_directive, "debug");
 		assert_eq!(config.db_url, Some("sqlite:cli.db".to_string()));
 	}
@@ -225,10 +1215,11 @@ mod tests {
 			bind: Some("0.0.0.0:9999".to_string()),
 			log_directive: Some("warn".to_string()),
 			db_url: Some("sqlite:file.db".to_string()),
+			..Default::default()
 		};
-		let config = merge(cli, file);
+		let config = merge(Env::Local, cli, file);
 
-		assert_eq!(config.bind, "0.0.0.0:9999");
+		assert_eq!(config.listeners, vec![ListenerConfig::tcp("0.0.0.0:9999")]);
 		assert_eq!(config.log_directive, "warn");
 		assert_eq!(config.db_url, Some("sqlite:file.db".to_string()));
 	}
@@ -243,10 +1234,14 @@ mod tests {
 			bind: Some("0.0.0.0:9999".to_string()),
 			log_directive: Some("warn".to_string()),
 			db_url: Some("sqlite:file.db".to_string()),
+			..Default::default()
 		};
-		let config = merge(cli, file);
+		let config = merge(Env::Local, cli, file);
 
-		assert_eq!(config.bind, "127.0.0.1:3000");
+		assert_eq!(
+			config.listeners,
+			vec![ListenerConfig::tcp("127.0.0.1:3000")]
+		);
 		assert_eq!(config.log_directive, "warn");
 		assert_eq!(config.db_url, Some("sqlite:file.db".to_string()));
 	}
@@ -261,13 +1256,69 @@ mod tests {
 			bind: Some("0.0.0.0:9999".to_string()),
 			..Default::default()
 		};
-		let config = merge(cli, file);
+		let config = merge(Env::Local, cli, file);
 
-		assert_eq!(config.bind, "0.0.0.0:9999");
+		assert_eq!(config.listeners, vec![ListenerConfig::tcp("0.0.0.0:9999")]);
 		assert_eq!(config.log_directive, "debug");
 		assert_eq!(config.db_url, None);
 	}
 
+	#[test]
+	fn test_merge_listeners() {
+		let unix = ListenerConfig {
+			unix: Some("/run/app.sock".to_string()),
+			mode: Some("660".to_string()),
+			..Default::default()
+		};
+		let file = RawConfig {
+			bind: Some("0.0.0.0:9999".to_string()),
+			listeners: vec![ListenerConfig::tcp("[::]:8888"), unix.clone()],
+			..Default::default()
+		};
+		let config = merge(Env::Local, RawConfig::default(), file.clone());
+		assert_eq!(
+			config.listeners,
+			vec![ListenerConfig::tcp("[::]:8888"), unix]
+		);
+
+		// an explicit --bind wins over the file
+		let cli = RawConfig {
+			bind: Some("127.0.0.1:3000".to_string()),
+			..Default::default()
+		};
+		let config = merge(Env::Local, cli, file);
+		assert_eq!(
+			config.listeners,
+			vec![ListenerConfig::tcp("127.0.0.1:3000")]
+		);
+
+		let config = merge(Env::Local, RawConfig::default(), RawConfig::default());
+		assert_eq!(config.listeners, vec![ListenerConfig::tcp("0.0.0.0:8888")]);
+	}
+
+	#[test]
+	fn test_listener_validation() {
+		let unix = ListenerConfig {
+			unix: Some("/run/app.sock".to_string()),
+			mode: Some("660".to_string()),
+			..Default::default()
+		};
+		assert!(unix.validate().is_ok());
+		assert_eq!(unix.mode_bits().unwrap(), Some(0o660));
+
+		let bad_mode = ListenerConfig {
+			mode: Some("rw-rw----".to_string()),
+			..unix.clone()
+		};
+		assert!(bad_mode.validate().is_err());
+		let both = ListenerConfig {
+			bind: Some("0.0.0.0:8888".to_string()),
+			..unix
+		};
+		assert!(both.validate().is_err());
+		assert!(ListenerConfig::default().validate().is_err());
+	}
+
 	#[test]
 	fn test_load_config_file_valid() {
 		let dir = std::env::temp_dir().join("rust_tide_template_test_config");
@@ -310,6 +1361,108 @@ db_url = "sqlite:test.db"
 		std::fs::remove_dir_all(&dir).ok();
 	}
 
+	#[test]
+	fn test_load_config_file_sections() {
+		let dir = std::env::temp_dir().join("rust_tide_template_test_config_sections");
+		std::fs::create_dir_all(&dir).unwrap();
+		let path = dir.join("sections.toml");
+		std::fs::write(
+			&path,
+			r#"[auth]
+cred_cache_ttl_secs = 300
+public_routes = [
+	{ path = "/healthz" },
+	{ path = "/api/login", methods = ["POST"] },
+]
+
+[auth.password_reset]
+notifier = "file"
+
+[session]
+same_site = "strict"
+
+[jwt]
+algorithm = "EdDSA"
+public_key_file = "jwt.pub.pem"
+
+[oidc]
+enabled = true
+issuer_url = "https://idp.example.com"
+client_id = "rust-tide-template"
+
+[tls]
+enabled = true
+cert_file = "server.crt.pem"
+key_file = "server.key.pem"
+min_version = "1.3"
+client_ca_file = "clients.ca.pem"
+
+[admin]
+token_sha256 = ["9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"]
+listeners = [{ unix = "/run/app/admin.sock", mode = "600" }]
+
+[rate_limit]
+key = "user"
+routes = [
+	{ path = "/api/login", methods = ["POST"], requests_per_sec = 0.2, burst = 5, key = "ip" },
+	{ path = "/api/users", burst = 0 },
+]
+"#,
+		)
+		.unwrap();
+
+		let raw = load_config_file(path.to_str().unwrap()).unwrap();
+		assert_eq!(raw.auth.cred_cache_ttl_secs, 300);
+		assert_eq!(raw.auth.cred_cache_max_entries, 10_000);
+		assert_eq!(
+			raw.auth.public_routes,
+			vec![
+				RoutePattern::new("/healthz", &[]),
+				RoutePattern::new("/api/login", &["POST"]),
+			]
+		);
+		assert_eq!(raw.auth.password_reset.notifier, NotifierKind::File);
+		assert_eq!(raw.auth.password_reset.token_ttl_secs, 1800);
+		assert_eq!(raw.session.secret, None);
+		assert_eq!(raw.session.same_site, SameSitePolicy::Strict);
+		assert_eq!(raw.session.cookie_name, "sid");
+		assert_eq!(raw.jwt.algorithm, JwtAlgorithm::EdDsa);
+		assert_eq!(raw.jwt.public_key_file, Some("jwt.pub.pem".to_string()));
+		assert_eq!(raw.jwt.ttl_secs, 3600);
+		assert!(raw.oidc.enabled);
+		assert_eq!(raw.oidc.issuer_url, "https://idp.example.com");
+		assert_eq!(raw.oidc.client_secret, None);
+		assert_eq!(raw.oidc.scopes, vec!["openid", "profile", "email"]);
+		assert!(raw.tls.enabled);
+		assert_eq!(raw.tls.min_version, TlsVersion::Tls13);
+		assert_eq!(raw.tls.client_ca_file, Some("clients.ca.pem".to_string()));
+		assert!(raw.tls.client_cert_required);
+		assert_eq!(raw.tls.reload_interval_secs, 30);
+		assert!(raw.admin.enabled);
+		assert_eq!(raw.admin.token_sha256.len(), 1);
+		assert_eq!(
+			raw.admin.listeners[0].unix,
+			Some("/run/app/admin.sock".to_string())
+		);
+		assert_eq!(raw.admin.public_routes.len(), 3);
+		assert!(raw.rate_limit.enabled);
+		assert_eq!(raw.rate_limit.key, RateLimitKey::User);
+		assert_eq!(raw.rate_limit.burst, 100);
+		assert_eq!(
+			raw.rate_limit.routes[0],
+			RouteRateLimit {
+				route: RoutePattern::new("/api/login", &["POST"]),
+				requests_per_sec: 0.2,
+				burst: 5,
+				key: Some(RateLimitKey::Ip),
+			}
+		);
+		assert_eq!(raw.rate_limit.routes[1].burst, 0);
+		raw.rate_limit.validate().unwrap();
+
+		std::fs::remove_dir_all(&dir).ok();
+	}
+
 	#[test]
 	fn test_load_config_file_not_found() {
 		let raw = load_config_file("/nonexistent/path/config.toml").unwrap();
@@ -329,10 +1482,137 @@ db_url = "sqlite:test.db"
 		std::fs::remove_dir_all(&dir).ok();
 	}
 
+	#[test]
+	fn test_rate_limit_validation() {
+		assert!(RateLimitConfig::default().validate().is_ok());
+		let never_refills = RateLimitConfig {
+			routes: vec![RouteRateLimit {
+				route: RoutePattern::new("/api/token", &[]),
+				requests_per_sec: 0.0,
+				burst: 10,
+				key: None,
+			}],
+			..Default::default()
+		};
+		assert!(never_refills.validate().is_err());
+	}
+
+	#[test]
+	fn test_timeout_validation() {
+		assert!(TimeoutConfig::default().validate().is_ok());
+		let status = TimeoutConfig {
+			status: 500,
+			..Default::default()
+		};
+		assert!(status.validate().is_err());
+	}
+
+	#[test]
+	fn test_jwt_validation() {
+		assert!(JwtConfig::default().validate().is_ok());
+		let short = JwtConfig {
+			secret: Some("change-me".to_string()),
+			..Default::default()
+		};
+		assert!(short.validate().is_err());
+		let long = JwtConfig {
+			secret: Some("x".repeat(32)),
+			..Default::default()
+		};
+		assert!(long.validate().is_ok());
+		// only HS256 signs with the secret
+		let eddsa = JwtConfig {
+			algorithm: JwtAlgorithm::EdDsa,
+			..short
+		};
+		assert!(eddsa.validate().is_ok());
+	}
+
+	#[test]
+	fn test_oidc_validation() {
+		let strict = SessionConfig {
+			same_site: SameSitePolicy::Strict,
+			..Default::default()
+		};
+		let oidc = OidcConfig {
+			enabled: true,
+			..Default::default()
+		};
+		assert!(oidc.validate(&SessionConfig::default()).is_ok());
+		assert!(oidc.validate(&strict).is_err());
+		assert!(OidcConfig::default().validate(&strict).is_ok());
+	}
+
+	#[test]
+	fn test_proxy_validation() {
+		let proxy = ProxyConfig {
+			trusted_proxies: ["10.0.0.0/8", "fd00::/8", "127.0.0.1", "unix"]
+				.map(String::from)
+				.to_vec(),
+		};
+		assert!(proxy.validate().is_ok());
+		let proxy = ProxyConfig {
+			trusted_proxies: vec!["10.0.0.0/40".to_string()],
+		};
+		assert!(proxy.validate().is_err());
+	}
+
+	#[test]
+	fn test_security_headers_defaults_per_env() {
+		let local = merge(Env::Local, RawConfig::default(), RawConfig::default());
+		assert_eq!(local.security_headers.hsts_max_age_secs, 0);
+		assert!(local.security_headers.csp_report_only);
+		let prd = merge(Env::Prd, RawConfig::default(), RawConfig::default());
+		assert_eq!(prd.security_headers, SecurityHeadersConfig::default());
+		prd.security_headers.validate().unwrap();
+
+		let newline = SecurityHeadersConfig {
+			referrer_policy: "no-referrer\r\nSet-Cookie: a=b".to_string(),
+			..Default::default()
+		};
+		assert!(newline.validate().is_err());
+	}
+
+	#[test]
+	fn test_dump_redacts_secrets() {
+		let config = Config {
+			db_url: Some("postgres://app:hunter2@db:5432/app".to_string()),
+			session: SessionConfig {
+				secret: Some("session-secret".to_string()),
+				..Default::default()
+			},
+			jwt: JwtConfig {
+				secret: Some("jwt-secret".to_string()),
+				..Default::default()
+			},
+			admin: AdminConfig {
+				token_sha256: vec!["9f86d081".to_string()],
+				..Default::default()
+			},
+			..Default::default()
+		};
+		let dump = serde_json::to_value(&config).unwrap();
+		let text = dump.to_string();
+		for secret in ["hunter2", "session-secret", "jwt-secret", "9f86d081"] {
+			assert!(!text.contains(secret), "{secret} leaked");
+		}
+		assert_eq!(dump["db_url"], "postgres://app:***@db:5432/app");
+		assert_eq!(dump["session"]["secret"], "***");
+		assert_eq!(dump["oidc"]["client_secret"], serde_json::Value::Null);
+		assert_eq!(dump["env"], "local");
+
+		let sqlite = Config {
+			db_url: Some("sqlite:app.db?mode=rwc".to_string()),
+			..Default::default()
+		};
+		let dump = serde_json::to_value(&sqlite).unwrap();
+		assert_eq!(dump["db_url"], "sqlite:app.db?mode=rwc");
+	}
+
 	#[test]
 	fn test_config_default() {
 		let config = Config::default();
-		assert_eq!(config.bind, "");
+		assert!(config.listeners.is_empty());
 		assert_eq!(config.log_directive, "");
 		assert_eq!(config.db_url, None);
 		assert_eq!(config.config_file, None);
@@ -0,0 +1,329 @@
+use anyhow_ext::{Context, Result};
+use regex::Regex;
+use tide::http::Method;
+use tide::{Middleware, Next, Request, Response, StatusCode};
+
+use crate::{config::CorsConfig, error::AppError, route_pattern};
+
+enum OriginRule {
+	Any,
+	Exact(String),
+	Glob(String),
+	Regex(Regex),
+}
+
+impl OriginRule {
+	fn parse(rule: &str) -> Result<Self> {
+		if rule == "*" {
+			return Ok(Self::Any);
+		}
+		if let Some(re) = rule
+			.strip_prefix('/')
+			.and_then(|r| r.strip_suffix('/'))
+			.filter(|r| !r.is_empty())
+		{
+			let re = Regex::new(&format!("^(?:{re})$"))
+				.with_context(|| format!("invalid CORS origin regex {rule}"))?;
+			return Ok(Self::Regex(re));
+		}
+		if rule.contains('*') {
+			return Ok(Self::Glob(rule.to_string()));
+		}
+		Ok(Self::Exact(rule.to_string()))
+	}
+
+	fn matches(&self, origin: &str) -> bool {
+		match self {
+			Self::Any => true,
+			Self::Exact(exact) => exact.eq_ignore_ascii_case(origin),
+			Self::Glob(glob) => route_pattern::glob_match(glob, origin),
+			Self::Regex(re) => re.is_match(origin),
+		}
+	}
+}
+
+/// Answers preflights and adds the `Access-Control-*` headers to responses for
+/// allowed origins, following [`CorsConfig`].
+///
+/// Preflights from disallowed origins, or asking for a method or header that is not
+/// allowed, are rejected with 403. Must run before [`crate::auth::AuthMiddleware`],
+/// browsers never send credentials with a preflight.
+pub struct CorsMiddleware {
+	origins: Vec<OriginRule>,
+	allowed_methods: Vec<String>,
+	allowed_headers: Vec<String>,
+	allow_methods_header: String,
+	allow_headers_header: String,
+	expose_headers_header: String,
+	allow_credentials: bool,
+	max_age: String,
+}
+
+impl CorsMiddleware {
+	pub fn new(cfg: &CorsConfig) -> Result<Self> {
+		cfg.validate()?;
+		let origins = cfg
+			.allowed_origins
+			.iter()
+			.map(|rule| OriginRule::parse(rule))
+			.collect::<Result<Vec<_>>>()
+			.dot()?;
+		Ok(Self {
+			origins,
+			allowed_methods: cfg.allowed_methods.clone(),
+			allowed_headers: cfg.allowed_headers.clone(),
+			allow_methods_header: cfg.allowed_methods.join(", "),
+			allow_headers_header: cfg.allowed_headers.join(", "),
+			expose_headers_header: cfg.exposed_headers.join(", "),
+			allow_credentials: cfg.allow_credentials,
+			max_age: cfg.max_age_secs.to_string(),
+		})
+	}
+
+	fn origin_allowed(&self, origin: &str) -> bool {
+		self.origins.iter().any(|rule| rule.matches(origin))
+	}
+
+	/// `*` when any origin is allowed, which [`CorsConfig::validate`] only permits
+	/// without credentials
+	fn allow_origin_value<'a>(&self, origin: &'a str) -> &'a str {
+		let any = self
+			.origins
+			.iter()
+			.any(|rule| matches!(rule, OriginRule::Any));
+		if any { "*" } else { origin }
+	}
+
+	fn method_allowed(&self, method: &str) -> bool {
+		self.allowed_methods
+			.iter()
+			.any(|m| m.eq_ignore_ascii_case(method.trim()))
+	}
+
+	fn headers_allowed(&self, requested: &str) -> bool {
+		if self.allowed_headers.iter().any(|h| h == "*") {
+			return true;
+		}
+		requested
+			.split(',')
+			.map(str::trim)
+			.filter(|h| !h.is_empty())
+			.all(|h| {
+				self.allowed_headers
+					.iter()
+					.any(|a| a.eq_ignore_ascii_case(h))
+			})
+	}
+
+	fn insert_common_headers(&self, resp: &mut Response, origin: &str) {
+		resp.insert_header(
+			"Access-Control-Allow-Origin",
+			self.allow_origin_value(origin),
+		);
+		if self.allow_credentials {
+			resp.insert_header("Access-Control-Allow-Credentials", "true");
+		}
+	}
+
+	fn preflight(&self, origin: &str, method: &str, headers: Option<&str>) -> Response {
+		if !self.origin_allowed(origin) {
+			return AppError::forbidden("cors_origin_denied", "origin not allowed").into();
+		}
+		if !self.method_allowed(method) {
+			return AppError::forbidden("cors_method_denied", "method not allowed").into();
+		}
+		if let Some(headers) = headers
+			&& !self.headers_allowed(headers)
+		{
+			return AppError::forbidden("cors_header_denied", "header not allowed").into();
+		}
+
+		let mut resp = Response::new(StatusCode::NoContent);
+		self.insert_common_headers(&mut resp, origin);
+		resp.insert_header(
+			"Access-Control-Allow-Methods",
+			self.allow_methods_header.as_str(),
+		);
+		if self.allowed_headers.iter().any(|h| h == "*") {
+			// `*` is taken literally when credentials are allowed, so echo the request
+			if let Some(headers) = headers {
+				resp.insert_header("Access-Control-Allow-Headers", headers);
+			}
+		} else {
+			resp.insert_header(
+				"Access-Control-Allow-Headers",
+				self.allow_headers_header.as_str(),
+			);
+		}
+		resp.insert_header("Access-Control-Max-Age", self.max_age.as_str());
+		resp
+	}
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for CorsMiddleware {
+	async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+		let origin = req.header("Origin").map(|o| o.as_str().to_string());
+		let requested_method = req
+			.header("Access-Control-Request-Method")
+			.map(|m| m.as_str().to_string());
+
+		let mut resp = match (&origin, requested_method) {
+			// only a preflight is answered here, other OPTIONS requests go to the router
+			(Some(origin), Some(method)) if req.method() == Method::Options => {
+				let headers = req
+					.header("Access-Control-Request-Headers")
+					.map(|h| h.as_str().to_string());
+				self.preflight(origin, &method, headers.as_deref())
+			}
+			_ => {
+				let mut resp = next.run(req).await;
+				if let Some(origin) = &origin
+					&& self.origin_allowed(origin)
+				{
+					self.insert_common_headers(&mut resp, origin);
+					if !self.expose_headers_header.is_empty() {
+						resp.insert_header(
+							"Access-Control-Expose-Headers",
+							self.expose_headers_header.as_str(),
+						);
+					}
+				}
+				resp
+			}
+		};
+		// the response differs per origin, caches must not share it
+		resp.append_header("Vary", "Origin");
+		Ok(resp)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use tide::http::{Request as HttpRequest, Response as HttpResponse, Url};
+
+	use super::*;
+
+	fn app(cfg: CorsConfig) -> tide::Server<()> {
+		let mut app = tide::new();
+		app.with(CorsMiddleware::new(&cfg).unwrap());
+		app.at("/api/users").get(|_| async move { Ok("users") });
+		app
+	}
+
+	fn cfg(origins: &[&str]) -> CorsConfig {
+		CorsConfig {
+			allowed_origins: origins.iter().map(|o| o.to_string()).collect(),
+			..Default::default()
+		}
+	}
+
+	async fn send(
+		app: &tide::Server<()>,
+		method: Method,
+		origin: Option<&str>,
+		preflight: Option<(&str, &str)>,
+	) -> HttpResponse {
+		let url = Url::parse("http://localhost/api/users").unwrap();
+		let mut req = HttpRequest::new(method, url);
+		if let Some(origin) = origin {
+			req.insert_header("Origin", origin);
+		}
+		if let Some((method, headers)) = preflight {
+			req.insert_header("Access-Control-Request-Method", method);
+			req.insert_header("Access-Control-Request-Headers", headers);
+		}
+		app.respond(req).await.unwrap()
+	}
+
+	#[test]
+	fn test_origin_rules() {
+		let mw = CorsMiddleware::new(&cfg(&[
+			"https://app.example.com",
+			"https://*.example.org",
+			r"/https://pr-\d+\.example\.net/",
+		]))
+		.unwrap();
+		assert!(mw.origin_allowed("https://app.example.com"));
+		assert!(mw.origin_allowed("https://a.example.org"));
+		assert!(mw.origin_allowed("https://pr-42.example.net"));
+		assert!(!mw.origin_allowed("https://pr-x.example.net"));
+		assert!(!mw.origin_allowed("https://evil.com"));
+		assert!(!mw.origin_allowed("https://app.example.com.evil.com"));
+	}
+
+	#[test]
+	fn test_invalid_regex_is_rejected() {
+		assert!(CorsMiddleware::new(&cfg(&["/(/"])).is_err());
+	}
+
+	#[test]
+	fn test_wildcard_with_credentials_is_rejected() {
+		let cfg = CorsConfig {
+			allow_credentials: true,
+			..cfg(&["https://app.example.com", "*"])
+		};
+		assert!(cfg.validate().is_err());
+		assert!(CorsMiddleware::new(&cfg).is_err());
+	}
+
+	#[async_std::test]
+	async fn test_allowed_origin_is_echoed() {
+		let app = app(cfg(&["https://app.example.com"]));
+		let resp = send(&app, Method::Get, Some("https://app.example.com"), None).await;
+		assert_eq!(resp.status(), StatusCode::Ok);
+		assert_eq!(
+			resp["Access-Control-Allow-Origin"].as_str(),
+			"https://app.example.com"
+		);
+		assert_eq!(resp["Access-Control-Allow-Credentials"].as_str(), "true");
+		assert_eq!(resp["Vary"].as_str(), "Origin");
+
+		let resp = send(&app, Method::Get, Some("https://evil.com"), None).await;
+		assert_eq!(resp.status(), StatusCode::Ok);
+		assert!(resp.header("Access-Control-Allow-Origin").is_none());
+	}
+
+	#[async_std::test]
+	async fn test_preflight() {
+		let app = app(cfg(&["https://app.example.com"]));
+		let resp = send(
+			&app,
+			Method::Options,
+			Some("https://app.example.com"),
+			Some(("PATCH", "content-type, authorization")),
+		)
+		.await;
+		assert_eq!(resp.status(), StatusCode::NoContent);
+		assert_eq!(resp["Access-Control-Max-Age"].as_str(), "7200");
+
+		let resp = send(
+			&app,
+			Method::Options,
+			Some("https://evil.com"),
+			Some(("GET", "content-type")),
+		)
+		.await;
+		assert_eq!(resp.status(), StatusCode::Forbidden);
+
+		let resp = send(
+			&app,
+			Method::Options,
+			Some("https://app.example.com"),
+			Some(("GET", "x-custom")),
+		)
+		.await;
+		assert_eq!(resp.status(), StatusCode::Forbidden);
+	}
+
+	#[async_std::test]
+	async fn test_wildcard_without_credentials() {
+		let app = app(CorsConfig {
+			allow_credentials: false,
+			..cfg(&["*"])
+		});
+		let resp = send(&app, Method::Get, Some("https://any.com"), None).await;
+		assert_eq!(resp["Access-Control-Allow-Origin"].as_str(), "*");
+		assert!(resp.header("Access-Control-Allow-Credentials").is_none());
+	}
+}
@@ -44,8 +44,39 @@ pub fn init_database(db_url: Option<&str>) -> Result<()> {
 }
 
 /// Get the global database connection
-pub fn get_db_conn() -> &'static DatabaseConnection {
-	DB_CONN.get().expect("Database not initialized")
+///
+/// Returns an error instead of panicking when no database is configured, so request
+/// handlers can turn it into a response.
+pub fn get_db_conn() -> Result<&'static DatabaseConnection> {
+	DB_CONN.get().context("database not initialized")
+}
+
+/// A throwaway, migrated SQLite database as the global connection, shared by all tests
+#[cfg(test)]
+pub fn init_test_database() {
+	static INIT: std::sync::Once = std::sync::Once::new();
+	INIT.call_once(|| {
+		let path =
+			std::env::temp_dir().join(format!("rust_tide_template_test_{}.db", std::process::id()));
+		let _ = std::fs::remove_file(&path);
+		let url = format!("sqlite://{}", path.display());
+		// tests run inside an executor already, where `block_on` must not nest
+		std::thread::spawn(move || init_database(Some(&url)))
+			.join()
+			.unwrap()
+			.unwrap();
+	});
+}
+
+/// Close the pooled connections on shutdown, queries fail afterwards
+pub async fn close_database() -> Result<()> {
+	if let Some(db) = DB_CONN.get() {
+		db.close_by_ref()
+			.await
+			.context("failed to close database connection")?;
+		info!("Database connection closed");
+	}
+	Ok(())
 }
 
 async fn ensure_db_file(db_url: &str) -> Result<()> {
@@ -0,0 +1,41 @@
+use sea_orm::entity::prelude::*;
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq)]
+#[sea_orm(table_name = "api_key")]
+pub struct Model {
+	#[sea_orm(primary_key)]
+	pub id: i32,
+	/// Public part of the key, used to look it up
+	#[sea_orm(unique)]
+	pub prefix: String,
+	/// Hex encoded SHA-256 of the whole key
+	pub key_hash: String,
+	pub owner_id: i32,
+	pub name: String,
+	/// Space separated permissions, `*` for everything the owner has
+	pub scopes: String,
+	/// Unix seconds
+	pub created_at: i64,
+	pub last_used_at: Option<i64>,
+	pub expires_at: Option<i64>,
+	pub revoked_at: Option<i64>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+	#[sea_orm(
+		belongs_to = "super::user::Entity",
+		from = "Column::OwnerId",
+		to = "super::user::Column::Id",
+		on_delete = "Cascade"
+	)]
+	User,
+}
+
+impl Related<super::user::Entity> for Entity {
+	fn to() -> RelationDef {
+		Relation::User.def()
+	}
+}
+
+impl ActiveModelBehavior for ActiveModel {}
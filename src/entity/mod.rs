@@ -1,3 +1,9 @@
+pub mod api_key;
+pub mod password_reset;
+pub mod permission;
+pub mod role;
+pub mod role_permission;
 pub mod user;
+pub mod user_role;
 
 pub use user::Model as User;
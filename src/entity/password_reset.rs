@@ -0,0 +1,36 @@
+use sea_orm::entity::prelude::*;
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq)]
+#[sea_orm(table_name = "password_reset")]
+pub struct Model {
+	#[sea_orm(primary_key)]
+	pub id: i32,
+	pub user_id: i32,
+	/// Hex encoded SHA-256 of the token
+	#[sea_orm(unique)]
+	pub token_hash: String,
+	/// Unix seconds
+	pub created_at: i64,
+	pub expires_at: i64,
+	/// Set once the token has been used, tokens are single-use
+	pub used_at: Option<i64>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+	#[sea_orm(
+		belongs_to = "super::user::Entity",
+		from = "Column::UserId",
+		to = "super::user::Column::Id",
+		on_delete = "Cascade"
+	)]
+	User,
+}
+
+impl Related<super::user::Entity> for Entity {
+	fn to() -> RelationDef {
+		Relation::User.def()
+	}
+}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -0,0 +1,25 @@
+use sea_orm::entity::prelude::*;
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq)]
+#[sea_orm(table_name = "permission")]
+pub struct Model {
+	#[sea_orm(primary_key)]
+	pub id: i32,
+	/// e.g. `log:write`, `*` grants everything
+	#[sea_orm(unique)]
+	pub name: String,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+	#[sea_orm(has_many = "super::role_permission::Entity")]
+	RolePermission,
+}
+
+impl Related<super::role_permission::Entity> for Entity {
+	fn to() -> RelationDef {
+		Relation::RolePermission.def()
+	}
+}
+
+impl ActiveModelBehavior for ActiveModel {}
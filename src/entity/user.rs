@@ -7,9 +7,23 @@ pub struct Model {
 	pub id: i32,
 	#[sea_orm(column_type = "Text", column_name = "username")]
 	pub username: String,
-	#[sea_orm(column_type = "Text", column_name = "password")]
-	pub password: String,
+	/// Argon2id hash in PHC string format, never the plaintext password
+	#[sea_orm(column_type = "Text", column_name = "password_hash")]
+	pub password_hash: String,
 	pub age: i32,
+	/// Consecutive failed logins, reset on success
+	pub failed_login_count: i32,
+	/// Unix seconds
+	pub last_failed_login_at: Option<i64>,
+	/// Unix seconds, logins are refused until then
+	pub locked_until: Option<i64>,
+	/// Issuer and subject of the OpenID Connect identity the user signs in with
+	#[sea_orm(column_type = "Text", nullable)]
+	pub oidc_issuer: Option<String>,
+	#[sea_orm(column_type = "Text", nullable)]
+	pub oidc_subject: Option<String>,
+	/// Unix milliseconds, sessions and bearer tokens obtained earlier are rejected
+	pub credentials_changed_at: Option<i64>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -0,0 +1,212 @@
+use std::fmt;
+
+use serde_json::{Map, Value, json};
+use tide::{Response, StatusCode};
+
+use crate::utils;
+
+/// An error answered to the client, rendered as
+/// `{"code": "...", "message": "...", "request_id": "..."}` plus any `details`.
+///
+/// `code` is a stable snake_case identifier clients can match on, `message` is meant
+/// for humans. Internal errors never end up here, they are logged and answered with a
+/// generic `internal_error` by the error middleware.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AppError {
+	pub status: StatusCode,
+	pub code: &'static str,
+	pub message: String,
+	/// Extra members of the body, e.g. the invalid `field`
+	pub details: Map<String, Value>,
+	headers: Vec<(&'static str, String)>,
+}
+
+impl AppError {
+	pub fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+		Self {
+			status,
+			code,
+			message: message.into(),
+			details: Map::new(),
+			headers: Vec::new(),
+		}
+	}
+
+	pub fn bad_request(code: &'static str, message: impl Into<String>) -> Self {
+		Self::new(StatusCode::BadRequest, code, message)
+	}
+
+	pub fn unauthorized(code: &'static str, message: impl Into<String>) -> Self {
+		Self::new(StatusCode::Unauthorized, code, message)
+	}
+
+	pub fn forbidden(code: &'static str, message: impl Into<String>) -> Self {
+		Self::new(StatusCode::Forbidden, code, message)
+	}
+
+	pub fn not_found(code: &'static str, message: impl Into<String>) -> Self {
+		Self::new(StatusCode::NotFound, code, message)
+	}
+
+	pub fn conflict(code: &'static str, message: impl Into<String>) -> Self {
+		Self::new(StatusCode::Conflict, code, message)
+	}
+
+	pub fn too_many_requests(
+		code: &'static str,
+		message: impl Into<String>,
+		retry_after_secs: u64,
+	) -> Self {
+		Self::new(StatusCode::TooManyRequests, code, message)
+			.with_header("Retry-After", retry_after_secs.to_string())
+	}
+
+	/// A generic error for `status`, used for errors that carry no [`AppError`]
+	pub fn from_status(status: StatusCode, message: impl Into<String>) -> Self {
+		Self::new(status, default_code(status), message)
+	}
+
+	pub fn with_detail(mut self, key: &str, value: impl Into<Value>) -> Self {
+		self.details.insert(key.to_owned(), value.into());
+		self
+	}
+
+	pub fn with_header(mut self, name: &'static str, value: impl Into<String>) -> Self {
+		self.headers.push((name, value.into()));
+		self
+	}
+
+	pub fn to_json(&self, request_id: &str) -> Value {
+		let mut body = json!({
+			"code": self.code,
+			"message": self.message,
+			"request_id": request_id,
+		});
+		if let Some(body) = body.as_object_mut() {
+			body.extend(self.details.clone());
+		}
+		body
+	}
+
+	/// RFC 7807 problem details, `code`, `request_id` and `details` become extension members
+	pub fn to_problem_json(&self, request_id: &str) -> Value {
+		let mut body = json!({
+			"type": "about:blank",
+			"title": self.status.canonical_reason(),
+			"status": self.status as u16,
+			"detail": self.message,
+			"code": self.code,
+			"request_id": request_id,
+		});
+		if let Some(body) = body.as_object_mut() {
+			body.extend(self.details.clone());
+		}
+		body
+	}
+
+	/// Put status, headers and the rendered body of this error into `resp`
+	pub fn render(&self, resp: &mut Response, problem_json: bool) {
+		let request_id = utils::get_req_id();
+		resp.set_status(self.status);
+		for (name, value) in &self.headers {
+			resp.insert_header(*name, value.as_str());
+		}
+		if problem_json {
+			resp.set_body(self.to_problem_json(&request_id));
+			resp.set_content_type("application/problem+json");
+		} else {
+			resp.set_body(self.to_json(&request_id));
+		}
+	}
+}
+
+impl fmt::Display for AppError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{} {}: {}", self.status as u16, self.code, self.message)
+	}
+}
+
+impl std::error::Error for AppError {}
+
+impl From<AppError> for Response {
+	fn from(err: AppError) -> Self {
+		let mut resp = Response::new(err.status);
+		err.render(&mut resp, false);
+		// kept on the response so the error middleware can render it as problem+json
+		resp.set_error(tide::Error::new(err.status, err));
+		resp
+	}
+}
+
+fn default_code(status: StatusCode) -> &'static str {
+	match status {
+		StatusCode::BadRequest => "bad_request",
+		StatusCode::Unauthorized => "unauthenticated",
+		StatusCode::Forbidden => "forbidden",
+		StatusCode::NotFound => "not_found",
+		StatusCode::MethodNotAllowed => "method_not_allowed",
+		StatusCode::RequestTimeout => "request_timeout",
+		StatusCode::Conflict => "conflict",
+		StatusCode::PayloadTooLarge => "payload_too_large",
+		StatusCode::UnsupportedMediaType => "unsupported_media_type",
+		StatusCode::UnprocessableEntity => "unprocessable_entity",
+		StatusCode::TooManyRequests => "too_many_requests",
+		StatusCode::InternalServerError => "internal_error",
+		StatusCode::BadGateway => "bad_gateway",
+		StatusCode::ServiceUnavailable => "service_unavailable",
+		StatusCode::GatewayTimeout => "gateway_timeout",
+		status if status.is_client_error() => "client_error",
+		_ => "server_error",
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_json_body() {
+		let err = AppError::bad_request("invalid_field", "age is out of range")
+			.with_detail("field", "age");
+		assert_eq!(
+			err.to_json("abc1234"),
+			json!({
+				"code": "invalid_field",
+				"message": "age is out of range",
+				"request_id": "abc1234",
+				"field": "age",
+			})
+		);
+	}
+
+	#[test]
+	fn test_problem_json_body() {
+		let err = AppError::not_found("user_not_found", "user 7 not found");
+		let body = err.to_problem_json("abc1234");
+		assert_eq!(body["type"], "about:blank");
+		assert_eq!(body["title"], "Not Found");
+		assert_eq!(body["status"], 404);
+		assert_eq!(body["detail"], "user 7 not found");
+		assert_eq!(body["code"], "user_not_found");
+		assert_eq!(body["request_id"], "abc1234");
+	}
+
+	#[test]
+	fn test_into_response_keeps_error_and_headers() {
+		let resp: Response = AppError::too_many_requests("login_locked", "retry later", 30).into();
+		assert_eq!(resp.status(), StatusCode::TooManyRequests);
+		assert_eq!(resp.header("Retry-After").unwrap().as_str(), "30");
+		let err = resp.downcast_error::<AppError>().unwrap();
+		assert_eq!(err.code, "login_locked");
+	}
+
+	#[test]
+	fn test_default_codes() {
+		assert_eq!(
+			default_code(StatusCode::UnprocessableEntity),
+			"unprocessable_entity"
+		);
+		assert_eq!(default_code(StatusCode::ImATeapot), "client_error");
+		assert_eq!(default_code(StatusCode::NotImplemented), "server_error");
+	}
+}
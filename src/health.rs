@@ -0,0 +1,231 @@
+use std::sync::LazyLock;
+use std::time::Instant;
+
+use anyhow_ext::{Context, Result, bail};
+use sea_orm_migration::MigratorTrait;
+use serde::Serialize;
+use tide::{Request, StatusCode};
+
+use migration::Migrator;
+
+use crate::{config, database, server::make_resp, shutdown};
+
+static STARTED_AT: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+/// Remember when the process started, uptime in `/api/status` is counted from here
+pub fn record_start() {
+	LazyLock::force(&STARTED_AT);
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+	Ok,
+	Failed,
+	/// The dependency is not configured, e.g. no `db_url`
+	Skipped,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct CheckResult {
+	pub name: &'static str,
+	pub status: CheckStatus,
+	pub latency_ms: f64,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub error: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct Status {
+	status: CheckStatus,
+	version: &'static str,
+	env: String,
+	uptime_secs: u64,
+	checks: Vec<CheckResult>,
+}
+
+/// `GET /healthz`, answers as long as the process can serve requests
+pub async fn healthz<State: Clone + Send + Sync + 'static>(_req: Request<State>) -> tide::Result {
+	Ok(make_resp(
+		200,
+		tide::Body::from_json(&serde_json::json!({ "status": "ok" }))?,
+	))
+}
+
+/// `GET /readyz`, 503 until the config is loaded and the database is reachable and
+/// migrated, and again once shutdown has started
+pub async fn readyz<State: Clone + Send + Sync + 'static>(_req: Request<State>) -> tide::Result {
+	let checks = run_checks().await;
+	let status = overall(&checks);
+	let body = serde_json::json!({ "status": status, "checks": checks });
+	Ok(make_resp(
+		http_status(status),
+		tide::Body::from_json(&body)?,
+	))
+}
+
+/// `GET /api/status`, the readiness checks plus build and runtime details
+pub async fn status<State: Clone + Send + Sync + 'static>(_req: Request<State>) -> tide::Result {
+	let checks = run_checks().await;
+	let status = overall(&checks);
+	let body = Status {
+		status,
+		version: env!("CARGO_PKG_VERSION"),
+		env: config::cfg().await.env.to_string(),
+		uptime_secs: STARTED_AT.elapsed().as_secs(),
+		checks,
+	};
+	Ok(make_resp(
+		http_status(status),
+		tide::Body::from_json(&body)?,
+	))
+}
+
+fn overall(checks: &[CheckResult]) -> CheckStatus {
+	if checks.iter().any(|c| c.status == CheckStatus::Failed) {
+		CheckStatus::Failed
+	} else {
+		CheckStatus::Ok
+	}
+}
+
+fn http_status(status: CheckStatus) -> StatusCode {
+	match status {
+		CheckStatus::Failed => StatusCode::ServiceUnavailable,
+		CheckStatus::Ok | CheckStatus::Skipped => StatusCode::Ok,
+	}
+}
+
+async fn run_checks() -> Vec<CheckResult> {
+	let db_configured = config::cfg().await.db_url.is_some();
+	let mut checks = vec![
+		timed("config", async { check_config() }).await,
+		timed("shutdown", async { check_not_draining() }).await,
+	];
+	if db_configured {
+		checks.push(timed("database", check_database()).await);
+		checks.push(timed("migrations", check_migrations()).await);
+	} else {
+		checks.push(skipped("database"));
+		checks.push(skipped("migrations"));
+	}
+	checks
+}
+
+async fn timed(name: &'static str, check: impl Future<Output = Result<()>>) -> CheckResult {
+	let start = Instant::now();
+	let result = check.await;
+	let latency_ms = start.elapsed().as_secs_f64() * 1000.0;
+	match result {
+		Ok(()) => CheckResult {
+			name,
+			status: CheckStatus::Ok,
+			latency_ms,
+			error: None,
+		},
+		Err(err) => {
+			tracing::warn!(check = name, ?err, "health check failed");
+			CheckResult {
+				name,
+				status: CheckStatus::Failed,
+				latency_ms,
+				// only the outermost context, the chain can hold connection details
+				error: Some(err.to_string()),
+			}
+		}
+	}
+}
+
+fn skipped(name: &'static str) -> CheckResult {
+	CheckResult {
+		name,
+		status: CheckStatus::Skipped,
+		latency_ms: 0.0,
+		error: None,
+	}
+}
+
+fn check_config() -> Result<()> {
+	if !config::is_loaded() {
+		bail!("config not loaded");
+	}
+	Ok(())
+}
+
+fn check_not_draining() -> Result<()> {
+	if shutdown::is_draining() {
+		bail!("shutting down");
+	}
+	Ok(())
+}
+
+async fn check_database() -> Result<()> {
+	let db = database::get_db_conn()?;
+	db.ping().await.context("database ping failed")?;
+	Ok(())
+}
+
+async fn check_migrations() -> Result<()> {
+	let db = database::get_db_conn()?;
+	let pending = Migrator::get_pending_migrations(db)
+		.await
+		.context("failed to read applied migrations")?;
+	if !pending.is_empty() {
+		bail!("{} migrations pending", pending.len());
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use tide::http::{Method, Request as HttpRequest, Response as HttpResponse, Url};
+
+	use super::*;
+
+	async fn get(path: &str) -> (StatusCode, serde_json::Value) {
+		let mut app = tide::new();
+		app.at("/healthz").get(healthz);
+		app.at("/api/status").get(status);
+		let url = Url::parse(&format!("http://localhost{path}")).unwrap();
+		let mut resp: HttpResponse = app
+			.respond(HttpRequest::new(Method::Get, url))
+			.await
+			.unwrap();
+		(resp.status(), resp.body_json().await.unwrap())
+	}
+
+	#[test]
+	fn test_overall_status() {
+		assert_eq!(overall(&[skipped("database")]), CheckStatus::Ok);
+		let failed = CheckResult {
+			name: "database",
+			status: CheckStatus::Failed,
+			latency_ms: 1.0,
+			error: Some("database ping failed".to_string()),
+		};
+		assert_eq!(
+			overall(&[skipped("migrations"), failed]),
+			CheckStatus::Failed
+		);
+	}
+
+	#[async_std::test]
+	async fn test_healthz() {
+		let (status, body) = get("/healthz").await;
+		assert_eq!(status, StatusCode::Ok);
+		assert_eq!(body["status"], "ok");
+	}
+
+	#[async_std::test]
+	async fn test_status_reports_checks() {
+		let (_, body) = get("/api/status").await;
+		assert_eq!(body["version"], env!("CARGO_PKG_VERSION"));
+		let names: Vec<&str> = body["checks"]
+			.as_array()
+			.unwrap()
+			.iter()
+			.map(|c| c["name"].as_str().unwrap())
+			.collect();
+		assert_eq!(names, ["config", "shutdown", "database", "migrations"]);
+	}
+}
@@ -0,0 +1,306 @@
+use std::sync::LazyLock;
+
+use anyhow_ext::{Context, Result};
+use dashmap::DashMap;
+use sea_orm::{ActiveModelTrait, ColumnTrait, EntityTrait, QueryFilter, Set, sea_query::Expr};
+use tracing::warn;
+
+use crate::{config, config::LockoutConfig, database, entity::user, utils::unix_now};
+
+/// Failure states of client IPs and of usernames that don't exist, known users keep
+/// theirs in the `user` table so it survives restarts.
+static FAILURES: LazyLock<DashMap<String, FailureState>> = LazyLock::new(DashMap::new);
+
+/// Entries kept in [`FAILURES`] before stale ones get pruned
+const MAX_TRACKED_KEYS: usize = 10_000;
+
+/// Failed login accounting of one username or client IP, timestamps are unix seconds
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FailureState {
+	pub count: u32,
+	pub last_failed_at: Option<i64>,
+	pub locked_until: Option<i64>,
+}
+
+impl FailureState {
+	fn from_user(user: &user::Model) -> Self {
+		Self {
+			count: user.failed_login_count.max(0) as u32,
+			last_failed_at: user.last_failed_login_at,
+			locked_until: user.locked_until,
+		}
+	}
+
+	/// Seconds until the lockout ends, None when not locked
+	pub fn remaining_lock(&self, now: i64) -> Option<u64> {
+		match self.locked_until {
+			Some(until) if until > now => Some((until - now) as u64),
+			_ => None,
+		}
+	}
+
+	/// State after one more failure at `now`, plus the length of the lockout it
+	/// started, if any.
+	///
+	/// Failures are forgotten `failure_window_secs` after the later of the last failure
+	/// and the end of the last lockout, so the backoff keeps growing across lockouts
+	/// longer than the window.
+	pub fn after_failure(self, now: i64, cfg: &LockoutConfig) -> (Self, Option<u64>) {
+		let count = match self.last_failed_at {
+			Some(at) => {
+				let since = self.locked_until.map_or(at, |until| until.max(at));
+				if now - since <= cfg.failure_window_secs as i64 {
+					self.count.saturating_add(1)
+				} else {
+					1
+				}
+			}
+			None => 1,
+		};
+		let lock_secs = lock_secs(count, cfg);
+		let state = Self {
+			count,
+			last_failed_at: Some(now),
+			locked_until: lock_secs
+				.map(|secs| now + secs as i64)
+				.or(self.locked_until),
+		};
+		(state, lock_secs)
+	}
+}
+
+/// Length of the lockout started by the `count`th failure, if any
+fn lock_secs(count: u32, cfg: &LockoutConfig) -> Option<u64> {
+	(count >= cfg.max_failures).then(|| {
+		let doublings = (count - cfg.max_failures).min(32);
+		cfg.base_lockout_secs
+			.saturating_mul(1u64 << doublings)
+			.min(cfg.max_lockout_secs)
+	})
+}
+
+/// Seconds the caller has to wait before trying again, None when neither the
+/// username nor the client IP is locked.
+pub async fn check(user: Option<&user::Model>, username: &str, ip: Option<&str>) -> Option<u64> {
+	if !config::cfg().await.auth.lockout.enabled {
+		return None;
+	}
+	let now = unix_now();
+	let user_lock = match user {
+		Some(user) => FailureState::from_user(user).remaining_lock(now),
+		None => memory_state(&user_key(username)).remaining_lock(now),
+	};
+	let ip_lock = ip.and_then(|ip| memory_state(&ip_key(ip)).remaining_lock(now));
+	user_lock.max(ip_lock)
+}
+
+pub async fn record_failure(
+	user: Option<&user::Model>,
+	username: &str,
+	ip: Option<&str>,
+) -> Result<()> {
+	let cfg = config::cfg().await.auth.lockout.clone();
+	if !cfg.enabled {
+		return Ok(());
+	}
+	let now = unix_now();
+
+	if let Some(ip) = ip {
+		record_memory_failure(&ip_key(ip), now, &cfg);
+	}
+	match user {
+		Some(user) => record_user_failure(user.id, username, now, &cfg)
+			.await
+			.dot()?,
+		// unknown usernames are locked just like real ones, so lockouts don't reveal
+		// which accounts exist
+		None => record_memory_failure(&user_key(username), now, &cfg),
+	}
+	Ok(())
+}
+
+/// Counts a failure of a known user in a single `UPDATE`, so concurrent failures can't
+/// all read the same count and write back the same count + 1.
+///
+/// The count follows [`FailureState::after_failure`].
+async fn record_user_failure(id: i32, username: &str, now: i64, cfg: &LockoutConfig) -> Result<()> {
+	let db = database::get_db_conn().dot()?;
+	let count = Expr::cust_with_values(
+		"CASE WHEN last_failed_login_at IS NOT NULL \
+			AND ? - MAX(last_failed_login_at, COALESCE(locked_until, last_failed_login_at)) <= ? \
+			THEN failed_login_count + 1 ELSE 1 END",
+		[now, cfg.failure_window_secs as i64],
+	);
+	let updated = user::Entity::update_many()
+		.col_expr(user::Column::FailedLoginCount, count)
+		.col_expr(user::Column::LastFailedLoginAt, Expr::value(now))
+		.filter(user::Column::Id.eq(id))
+		.exec_with_returning(db)
+		.await
+		.dot()?;
+	let Some(user) = updated.first() else {
+		// deleted in the meantime
+		return Ok(());
+	};
+	let state = FailureState::from_user(user);
+	let Some(secs) = lock_secs(state.count, cfg) else {
+		return Ok(());
+	};
+	let until = now + secs as i64;
+	// a concurrent failure may have started a longer lockout already
+	user::Entity::update_many()
+		.col_expr(
+			user::Column::LockedUntil,
+			Expr::cust_with_values("MAX(COALESCE(locked_until, 0), ?)", [until]),
+		)
+		.filter(user::Column::Id.eq(id))
+		.exec(db)
+		.await
+		.dot()?;
+	let state = FailureState {
+		locked_until: Some(until.max(state.locked_until.unwrap_or_default())),
+		..state
+	};
+	log_lockout(&user_key(username), &state, secs);
+	Ok(())
+}
+
+/// Reset the failure count of `user` after a successful login
+pub async fn record_success(user: &user::Model) -> Result<()> {
+	if user.failed_login_count == 0 && user.locked_until.is_none() {
+		return Ok(());
+	}
+	let mut active: user::ActiveModel = user.clone().into();
+	active.failed_login_count = Set(0);
+	active.last_failed_login_at = Set(None);
+	active.locked_until = Set(None);
+	let db = database::get_db_conn().dot()?;
+	active.update(db).await.dot()?;
+	Ok(())
+}
+
+fn memory_state(key: &str) -> FailureState {
+	FAILURES.get(key).map(|s| *s.value()).unwrap_or_default()
+}
+
+fn record_memory_failure(key: &str, now: i64, cfg: &LockoutConfig) {
+	if FAILURES.len() >= MAX_TRACKED_KEYS {
+		let window = cfg.failure_window_secs as i64;
+		FAILURES.retain(|_, state| {
+			state.remaining_lock(now).is_some()
+				|| state.last_failed_at.is_some_and(|at| now - at <= window)
+		});
+	}
+	// the entry keeps its shard locked, concurrent failures of a key are counted in turn
+	let mut entry = FAILURES.entry(key.to_owned()).or_default();
+	let (state, lock_secs) = entry.after_failure(now, cfg);
+	*entry = state;
+	drop(entry);
+	if let Some(secs) = lock_secs {
+		log_lockout(key, &state, secs);
+	}
+}
+
+fn log_lockout(key: &str, state: &FailureState, lock_secs: u64) {
+	warn!(
+		target: "security",
+		event = "login_lockout",
+		key,
+		failures = state.count,
+		lock_secs,
+		locked_until = state.locked_until,
+		"locked out after repeated failed logins"
+	);
+}
+
+fn user_key(username: &str) -> String {
+	format!("user:{username}")
+}
+
+fn ip_key(ip: &str) -> String {
+	format!("ip:{ip}")
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn cfg() -> LockoutConfig {
+		LockoutConfig {
+			max_failures: 3,
+			base_lockout_secs: 60,
+			max_lockout_secs: 300,
+			failure_window_secs: 600,
+			..Default::default()
+		}
+	}
+
+	fn fail_n(n: u32, now: i64) -> (FailureState, Option<u64>) {
+		let mut result = (FailureState::default(), None);
+		for _ in 0..n {
+			result = result.0.after_failure(now, &cfg());
+		}
+		result
+	}
+
+	#[test]
+	fn test_no_lock_below_threshold() {
+		let (state, lock) = fail_n(2, 1000);
+		assert_eq!(state.count, 2);
+		assert_eq!(lock, None);
+		assert_eq!(state.remaining_lock(1000), None);
+	}
+
+	#[test]
+	fn test_lock_at_threshold() {
+		let (state, lock) = fail_n(3, 1000);
+		assert_eq!(lock, Some(60));
+		assert_eq!(state.locked_until, Some(1060));
+		assert_eq!(state.remaining_lock(1000), Some(60));
+		assert_eq!(state.remaining_lock(1060), None);
+	}
+
+	#[test]
+	fn test_lock_doubles_up_to_max() {
+		assert_eq!(fail_n(4, 1000).1, Some(120));
+		assert_eq!(fail_n(5, 1000).1, Some(240));
+		assert_eq!(fail_n(6, 1000).1, Some(300));
+		assert_eq!(fail_n(100, 1000).1, Some(300));
+	}
+
+	#[test]
+	fn test_failures_forgotten_after_window() {
+		let (state, _) = fail_n(2, 1000);
+		let (state, lock) = state.after_failure(1000 + 601, &cfg());
+		assert_eq!(state.count, 1);
+		assert_eq!(lock, None);
+	}
+
+	#[test]
+	fn test_concurrent_memory_failures_all_count() {
+		let cfg = LockoutConfig {
+			max_failures: 1000,
+			..cfg()
+		};
+		let key = ip_key("192.0.2.77");
+		std::thread::scope(|scope| {
+			for _ in 0..8 {
+				scope.spawn(|| {
+					for _ in 0..50 {
+						record_memory_failure(&key, 1000, &cfg);
+					}
+				});
+			}
+		});
+		assert_eq!(memory_state(&key).count, 400);
+	}
+
+	#[test]
+	fn test_window_counts_from_lock_end() {
+		let (state, _) = fail_n(3, 1000);
+		// still within the window measured from the end of the lockout at 1060
+		let (state, lock) = state.after_failure(1060 + 600, &cfg());
+		assert_eq!(state.count, 4);
+		assert_eq!(lock, Some(120));
+	}
+}
@@ -46,6 +46,12 @@ pub(crate) async fn setup_logger() -> Result<()> {
 	Ok(())
 }
 
+/// Flush log lines still buffered in stdout, called right before the process exits
+pub fn flush() {
+	use std::io::Write;
+	let _ = std::io::stdout().flush();
+}
+
 /// Updates the global log level using a tracing directive.
 ///
 /// This function allows runtime modification of log levels without restarting the application.
@@ -121,6 +127,13 @@ pub(crate) async fn setup_logger() -> Result<()> {
 /// - Empty directives are ignored with a warning
 /// - Changes take effect immediately for all subsequent log statements
 pub fn update_global_log_level(directive: &str) -> Result<()> {
+	let result = reload_global_log_level(directive);
+	let outcome = if result.is_ok() { "ok" } else { "error" };
+	metrics::counter!("log_reloads_total", "result" => outcome).increment(1);
+	result
+}
+
+fn reload_global_log_level(directive: &str) -> Result<()> {
 	// for some reason, if set to "trace", the async-std will panic
 	// TODO: change to smol
 	if directive.starts_with("trace") {
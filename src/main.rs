@@ -1,15 +1,38 @@
+mod admin;
+mod api_key;
 mod auth;
+mod body_limit;
 mod cli;
 mod config;
+mod cors;
 mod database;
 mod entity;
+mod error;
+mod health;
+mod jwt;
+mod listener;
+mod lockout;
 mod logger;
+mod notify;
+mod oidc;
+mod password;
+mod proxy;
+mod rate_limit;
+mod rbac;
+mod route_pattern;
+mod security_headers;
 mod server;
+mod shutdown;
+mod telemetry;
+mod timeout;
+mod tls;
+mod upload;
+mod users;
 mod utils;
 
 use anyhow_ext::{Context, Result};
 use clap::Parser;
-use cli::Cli;
+use cli::{Cli, Commands};
 use server::init_http_server_blocking;
 
 #[global_allocator]
@@ -19,14 +42,21 @@ static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
 async fn main() -> Result<()> {
 	let cli = Cli::parse();
 
-	config::load_config(cli.config, cli.config_file.as_deref())
+	config::load_config(cli.env, cli.config, cli.config_file.as_deref())
 		.await
 		.dot()?;
 
 	logger::setup_logger().await.dot()?;
 
+	telemetry::init_metrics().dot()?;
+
+	jwt::init_jwt(&config::cfg().await.jwt).dot()?;
+
 	database::init_database(config::cfg().await.db_url.clone().as_deref()).dot()?;
 
-	init_http_server_blocking().await?;
+	match cli.command {
+		Some(Commands::ApiKey { command }) => api_key::run_command(command).await.dot()?,
+		_ => init_http_server_blocking().await?,
+	}
 	Ok(())
 }
@@ -0,0 +1,139 @@
+use std::io::Write;
+
+use anyhow_ext::{Context, Result, ensure};
+use serde::Serialize;
+use tracing::debug;
+
+use crate::cli::Env;
+use crate::config::{NotifierKind, PasswordResetConfig};
+
+/// A message for a user, e.g. a password reset token
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Notification {
+	pub recipient: String,
+	pub subject: String,
+	pub body: String,
+	/// Unix seconds
+	pub created_at: i64,
+}
+
+/// Delivers notifications to users. Implement this to plug in mail, SMS, etc.
+pub trait Notifier: Send + Sync {
+	fn send(&self, notification: &Notification) -> Result<()>;
+}
+
+/// Writes notifications to the debug log, for development. Refuses to in `prd`, where
+/// logs must not carry reset tokens.
+pub struct LogNotifier {
+	refuse: bool,
+}
+
+impl Notifier for LogNotifier {
+	fn send(&self, notification: &Notification) -> Result<()> {
+		ensure!(
+			!self.refuse,
+			"the log notifier is disabled in prd, configure another notifier"
+		);
+		debug!(
+			target: "notify",
+			recipient = notification.recipient,
+			subject = notification.subject,
+			"{}",
+			notification.body
+		);
+		Ok(())
+	}
+}
+
+/// Appends notifications as JSON lines to a file, an offline outbox another
+/// process can pick up
+pub struct FileNotifier {
+	path: String,
+}
+
+impl FileNotifier {
+	pub fn new(path: &str) -> Self {
+		Self {
+			path: path.to_owned(),
+		}
+	}
+}
+
+impl Notifier for FileNotifier {
+	fn send(&self, notification: &Notification) -> Result<()> {
+		let mut line = serde_json::to_string(notification).dot()?;
+		line.push('\n');
+		std::fs::OpenOptions::new()
+			.create(true)
+			.append(true)
+			.open(&self.path)
+			.and_then(|mut file| file.write_all(line.as_bytes()))
+			.dot()
+			.context(format!(
+				"failed to write notification, path={:?}",
+				self.path
+			))?;
+		Ok(())
+	}
+}
+
+pub fn from_config(cfg: &PasswordResetConfig, env: Env) -> Box<dyn Notifier> {
+	match cfg.notifier {
+		NotifierKind::Log => Box::new(LogNotifier {
+			refuse: env == Env::Prd,
+		}),
+		NotifierKind::File => Box::new(FileNotifier::new(&cfg.outbox_file)),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_log_notifier_refuses_in_prd() {
+		let notification = Notification {
+			recipient: "alice".to_string(),
+			subject: "hello".to_string(),
+			body: "secret token".to_string(),
+			created_at: 1,
+		};
+		let cfg = PasswordResetConfig::default();
+		assert!(from_config(&cfg, Env::Local).send(&notification).is_ok());
+		assert!(from_config(&cfg, Env::Prd).send(&notification).is_err());
+	}
+
+	#[test]
+	fn test_file_notifier_appends_json_lines() {
+		let dir = std::env::temp_dir().join("rust_tide_template_test_file_notifier");
+		std::fs::create_dir_all(&dir).unwrap();
+		let path = dir.join("outbox.jsonl");
+		std::fs::remove_file(&path).ok();
+
+		let notifier = FileNotifier::new(path.to_str().unwrap());
+		let notification = Notification {
+			recipient: "alice".to_string(),
+			subject: "hello".to_string(),
+			body: "first".to_string(),
+			created_at: 1,
+		};
+		notifier.send(&notification).unwrap();
+		notifier
+			.send(&Notification {
+				body: "second".to_string(),
+				..notification
+			})
+			.unwrap();
+
+		let content = std::fs::read_to_string(&path).unwrap();
+		let lines: Vec<serde_json::Value> = content
+			.lines()
+			.map(|line| serde_json::from_str(line).unwrap())
+			.collect();
+		assert_eq!(lines.len(), 2);
+		assert_eq!(lines[0]["recipient"], "alice");
+		assert_eq!(lines[1]["body"], "second");
+
+		std::fs::remove_dir_all(&dir).ok();
+	}
+}
@@ -0,0 +1,725 @@
+use std::sync::{Arc, LazyLock};
+
+use anyhow_ext::{Context, Result, anyhow, bail};
+use async_std::sync::{Mutex, RwLock};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation, decode, decode_header, jwk::JwkSet};
+use sea_orm::{ActiveModelTrait, ColumnTrait, EntityTrait, QueryFilter, Set};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use sha2::{Digest, Sha256};
+use tide::{Redirect, Request};
+use tracing::{info, warn};
+use url::{Url, form_urlencoded};
+
+use crate::{
+	auth, config, config::OidcConfig, database, entity::user, error::AppError, users, utils,
+};
+
+/// The provider is discovered on the first login rather than on startup, so the
+/// server comes up while the identity provider is unreachable
+static PROVIDER: LazyLock<Mutex<Option<Arc<Provider>>>> = LazyLock::new(|| Mutex::new(None));
+
+/// Session key holding the [`AuthFlow`] between the redirect and the callback
+const SESSION_FLOW_KEY: &str = "oidc_flow";
+/// How long a user may take at the provider, in seconds
+const FLOW_TTL_SECS: i64 = 10 * 60;
+/// Allowed clock skew in seconds when checking the ID token
+const ID_TOKEN_LEEWAY_SECS: u64 = 60;
+/// Stored as the password hash of provisioned users, it's no PHC string so password
+/// logins always fail for them
+const NO_PASSWORD: &str = "!";
+
+/// The parts of the provider's discovery document we use
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ProviderMetadata {
+	pub issuer: String,
+	pub authorization_endpoint: String,
+	pub token_endpoint: String,
+	pub jwks_uri: String,
+}
+
+/// State, nonce and PKCE verifier of one login attempt, kept in the session
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AuthFlow {
+	pub state: String,
+	pub nonce: String,
+	pub code_verifier: String,
+	/// Unix seconds
+	pub created_at: i64,
+}
+
+impl AuthFlow {
+	pub fn new() -> Self {
+		Self {
+			state: utils::gen_n_random_str(32),
+			nonce: utils::gen_n_random_str(32),
+			// RFC 7636 wants 43 to 128 characters
+			code_verifier: utils::gen_n_random_str(64),
+			created_at: utils::unix_now(),
+		}
+	}
+
+	/// The S256 PKCE challenge of the verifier
+	pub fn code_challenge(&self) -> String {
+		pkce_challenge(&self.code_verifier)
+	}
+}
+
+fn pkce_challenge(verifier: &str) -> String {
+	base64_simd::URL_SAFE_NO_PAD.encode_to_string(Sha256::digest(verifier.as_bytes()))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TokenResponse {
+	pub id_token: String,
+}
+
+/// Validated ID token claims, `extra` holds everything besides `iss`, `sub` and `nonce`
+#[derive(Deserialize, Debug, Clone)]
+pub struct IdTokenClaims {
+	pub iss: String,
+	pub sub: String,
+	pub nonce: Option<String>,
+	#[serde(flatten)]
+	pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+impl IdTokenClaims {
+	/// The string claim `name`, falling back to `sub`
+	pub fn username(&self, claim: &str) -> &str {
+		self.extra
+			.get(claim)
+			.and_then(|value| value.as_str())
+			.filter(|value| !value.is_empty())
+			.unwrap_or(&self.sub)
+	}
+}
+
+pub struct Provider {
+	cfg: OidcConfig,
+	metadata: ProviderMetadata,
+	jwks: RwLock<JwkSet>,
+}
+
+impl Provider {
+	/// Load the discovery document and the signing keys of `cfg.issuer_url`
+	pub async fn discover(cfg: &OidcConfig) -> Result<Self> {
+		let issuer = cfg.issuer_url.trim_end_matches('/');
+		let metadata: ProviderMetadata =
+			get_json(&format!("{issuer}/.well-known/openid-configuration")).await?;
+		if metadata.issuer.trim_end_matches('/') != issuer {
+			bail!(
+				"discovery document is for issuer {:?}, expected {:?}",
+				metadata.issuer,
+				cfg.issuer_url
+			);
+		}
+		let jwks = get_json(&metadata.jwks_uri).await?;
+		Ok(Self {
+			cfg: cfg.clone(),
+			metadata,
+			jwks: RwLock::new(jwks),
+		})
+	}
+
+	/// Where to send the browser to start `flow`
+	pub fn authorization_url(&self, flow: &AuthFlow) -> Result<String> {
+		let mut url = Url::parse(&self.metadata.authorization_endpoint).dot()?;
+		url.query_pairs_mut()
+			.append_pair("response_type", "code")
+			.append_pair("client_id", &self.cfg.client_id)
+			.append_pair("redirect_uri", &self.cfg.redirect_url)
+			.append_pair("scope", &self.cfg.scopes.join(" "))
+			.append_pair("state", &flow.state)
+			.append_pair("nonce", &flow.nonce)
+			.append_pair("code_challenge", &flow.code_challenge())
+			.append_pair("code_challenge_method", "S256");
+		Ok(url.into())
+	}
+
+	/// Redeem an authorization code at the token endpoint
+	pub async fn exchange_code(&self, code: &str, code_verifier: &str) -> Result<TokenResponse> {
+		let form = form_urlencoded::Serializer::new(String::new())
+			.append_pair("grant_type", "authorization_code")
+			.append_pair("code", code)
+			.append_pair("redirect_uri", &self.cfg.redirect_url)
+			.append_pair("client_id", &self.cfg.client_id)
+			.append_pair("code_verifier", code_verifier)
+			.finish();
+		let mut req = surf::post(&self.metadata.token_endpoint)
+			.header("Accept", "application/json")
+			.content_type("application/x-www-form-urlencoded")
+			.body_string(form);
+		if let Some(secret) = &self.cfg.client_secret {
+			// client_secret_basic, both parts form encoded as RFC 6749 section 2.3.1 wants
+			let userpass = format!(
+				"{}:{}",
+				form_urlencode(&self.cfg.client_id),
+				form_urlencode(secret)
+			);
+			req = req.header(
+				"Authorization",
+				format!("Basic {}", base64_simd::STANDARD.encode_to_string(userpass)),
+			);
+		}
+		let mut resp = req
+			.await
+			.map_err(|err| anyhow!("token request failed: {err}"))?;
+		if !resp.status().is_success() {
+			let body = resp.body_string().await.unwrap_or_default();
+			bail!("token endpoint answered {}: {body}", resp.status());
+		}
+		resp.body_json()
+			.await
+			.map_err(|err| anyhow!("invalid token response: {err}"))
+	}
+
+	/// Check signature, issuer, audience, expiry and nonce of an ID token
+	pub async fn validate_id_token(&self, id_token: &str, nonce: &str) -> Result<IdTokenClaims> {
+		let header = decode_header(id_token).dot()?;
+		if !matches!(
+			header.alg,
+			Algorithm::RS256
+				| Algorithm::RS384
+				| Algorithm::RS512
+				| Algorithm::PS256
+				| Algorithm::PS384
+				| Algorithm::PS512
+				| Algorithm::ES256
+				| Algorithm::ES384
+				| Algorithm::EdDSA
+		) {
+			bail!("unsupported ID token algorithm {:?}", header.alg);
+		}
+		let key = self.decoding_key(header.kid.as_deref()).await?;
+
+		let mut validation = Validation::new(header.alg);
+		validation.set_issuer(&[&self.metadata.issuer]);
+		validation.set_audience(&[&self.cfg.client_id]);
+		validation.set_required_spec_claims(&["iss", "sub", "aud", "exp"]);
+		validation.leeway = ID_TOKEN_LEEWAY_SECS;
+		let claims = decode::<IdTokenClaims>(id_token, &key, &validation)
+			.dot()?
+			.claims;
+		if claims.nonce.as_deref() != Some(nonce) {
+			bail!("ID token nonce mismatch");
+		}
+		Ok(claims)
+	}
+
+	/// The JWKS key with id `kid`, the key set is reloaded once when it's missing
+	/// since providers rotate their keys
+	async fn decoding_key(&self, kid: Option<&str>) -> Result<DecodingKey> {
+		if let Some(key) = find_key(&*self.jwks.read().await, kid)? {
+			return Ok(key);
+		}
+		let jwks: JwkSet = get_json(&self.metadata.jwks_uri).await?;
+		let key = find_key(&jwks, kid)?;
+		*self.jwks.write().await = jwks;
+		key.context(format!("no JWKS key matches the ID token, kid={kid:?}"))
+	}
+}
+
+fn find_key(jwks: &JwkSet, kid: Option<&str>) -> Result<Option<DecodingKey>> {
+	let jwk = match kid {
+		Some(kid) => jwks.find(kid),
+		// without a kid only an unambiguous key set can be used
+		None if jwks.keys.len() == 1 => jwks.keys.first(),
+		None => None,
+	};
+	jwk.map(DecodingKey::from_jwk).transpose().dot()
+}
+
+fn form_urlencode(value: &str) -> String {
+	form_urlencoded::byte_serialize(value.as_bytes()).collect()
+}
+
+async fn get_json<T: DeserializeOwned>(url: &str) -> Result<T> {
+	let mut resp = surf::get(url)
+		.header("Accept", "application/json")
+		.await
+		.map_err(|err| anyhow!("GET {url} failed: {err}"))?;
+	if !resp.status().is_success() {
+		bail!("GET {url} answered {}", resp.status());
+	}
+	resp.body_json()
+		.await
+		.map_err(|err| anyhow!("GET {url} returned invalid JSON: {err}"))
+}
+
+/// The discovered provider, discovery is retried on every call until it succeeds
+pub async fn provider() -> Result<Arc<Provider>> {
+	let mut provider = PROVIDER.lock().await;
+	if let Some(provider) = provider.as_ref() {
+		return Ok(provider.clone());
+	}
+	let cfg = config::cfg().await.oidc.clone();
+	let discovered = Arc::new(Provider::discover(&cfg).await.dot()?);
+	*provider = Some(discovered.clone());
+	Ok(discovered)
+}
+
+/// Forget the discovered provider, the next login discovers it again, e.g. after its
+/// endpoints moved. Returns whether one was cached.
+pub async fn forget_provider() -> bool {
+	PROVIDER.lock().await.take().is_some()
+}
+
+/// `GET /api/oidc/login`, redirects the browser to the provider
+pub async fn login<State: Clone + Send + Sync + 'static>(mut req: Request<State>) -> tide::Result {
+	let provider = provider().await?;
+	let flow = AuthFlow::new();
+	let url = provider.authorization_url(&flow)?;
+	req.session_mut().insert(SESSION_FLOW_KEY, &flow)?;
+	Ok(Redirect::new(url).into())
+}
+
+#[derive(Deserialize, Debug)]
+struct CallbackQuery {
+	code: Option<String>,
+	state: Option<String>,
+	error: Option<String>,
+	error_description: Option<String>,
+}
+
+/// `GET /api/oidc/callback`, where the provider sends the browser back with a code
+pub async fn callback<State: Clone + Send + Sync + 'static>(
+	mut req: Request<State>,
+) -> tide::Result {
+	let query: CallbackQuery = req.query()?;
+	let session = req.session_mut();
+	let flow = session.get::<AuthFlow>(SESSION_FLOW_KEY);
+	session.remove(SESSION_FLOW_KEY);
+
+	if let Some(error) = query.error {
+		info!(
+			error,
+			description = query.error_description,
+			"OIDC provider refused the login"
+		);
+		return Ok(login_failed("the identity provider refused the login"));
+	}
+	let Some(flow) = flow else {
+		return Ok(login_failed("no login in progress"));
+	};
+	if utils::unix_now() - flow.created_at > FLOW_TTL_SECS {
+		return Ok(login_failed("the login took too long"));
+	}
+	if query.state.as_deref() != Some(flow.state.as_str()) {
+		return Ok(login_failed("state mismatch"));
+	}
+	let Some(code) = query.code else {
+		return Ok(login_failed("no authorization code"));
+	};
+
+	let provider = provider().await?;
+	let claims = match exchange_and_validate(&provider, &code, &flow).await {
+		Ok(claims) => claims,
+		Err(err) => {
+			warn!("OIDC login failed: {err:?}");
+			return Ok(login_failed("the identity provider's answer was invalid"));
+		}
+	};
+	let cfg = &provider.cfg;
+	let user = match provision_user(&claims, cfg).await? {
+		Ok(user) => user,
+		Err(err) => return Ok(err.into()),
+	};
+
+	auth::start_session(req.session_mut(), &user.username)?;
+	info!(
+		target: "security",
+		event = "oidc_login",
+		username = user.username,
+		sub = claims.sub,
+		"signed in through OIDC"
+	);
+	Ok(Redirect::new(&cfg.post_login_redirect).into())
+}
+
+async fn exchange_and_validate(
+	provider: &Provider,
+	code: &str,
+	flow: &AuthFlow,
+) -> Result<IdTokenClaims> {
+	let tokens = provider.exchange_code(code, &flow.code_verifier).await?;
+	provider
+		.validate_id_token(&tokens.id_token, &flow.nonce)
+		.await
+}
+
+fn login_failed(reason: &str) -> tide::Response {
+	AppError::bad_request("oidc_login_failed", format!("OIDC login failed: {reason}")).into()
+}
+
+/// The user linked to the identity in `claims`, created on its first login.
+///
+/// Refused when the username isn't a valid local one, is taken by a local user which
+/// must not be linked, or when an unknown identity must not be provisioned.
+async fn provision_user(
+	claims: &IdTokenClaims,
+	cfg: &OidcConfig,
+) -> Result<Result<user::Model, AppError>> {
+	let db = database::get_db_conn().dot()?;
+	let linked = user::Entity::find()
+		.filter(user::Column::OidcIssuer.eq(&claims.iss))
+		.filter(user::Column::OidcSubject.eq(&claims.sub))
+		.one(db)
+		.await
+		.dot()?;
+	if let Some(linked) = linked {
+		return Ok(Ok(linked));
+	}
+
+	let username = claims.username(&cfg.username_claim);
+	if let Err(invalid) = users::validate_username(username) {
+		warn!(
+			username,
+			sub = claims.sub,
+			"OIDC login with an invalid username"
+		);
+		return Ok(Err(AppError::bad_request(
+			"invalid_username",
+			format!(
+				"the identity provider's username is invalid: {}",
+				invalid.message
+			),
+		)
+		.with_detail("claim", cfg.username_claim.as_str())));
+	}
+	if let Some(existing) = auth::find_user(username).await.dot()? {
+		if !cfg.link_existing_users || existing.oidc_subject.is_some() {
+			warn!(
+				username,
+				sub = claims.sub,
+				"OIDC login collides with a local user"
+			);
+			return Ok(Err(AppError::conflict(
+				"username_taken",
+				"a local user of the same name exists",
+			)));
+		}
+		let mut active: user::ActiveModel = existing.into();
+		active.oidc_issuer = Set(Some(claims.iss.clone()));
+		active.oidc_subject = Set(Some(claims.sub.clone()));
+		return Ok(Ok(active.update(db).await.dot()?));
+	}
+	if !cfg.auto_provision {
+		info!(
+			username,
+			sub = claims.sub,
+			"OIDC login of an unknown identity refused"
+		);
+		return Ok(Err(AppError::forbidden(
+			"not_provisioned",
+			"no user is linked to this identity",
+		)));
+	}
+
+	let active = user::ActiveModel {
+		username: Set(username.to_owned()),
+		password_hash: Set(NO_PASSWORD.to_owned()),
+		age: Set(0),
+		failed_login_count: Set(0),
+		oidc_issuer: Set(Some(claims.iss.clone())),
+		oidc_subject: Set(Some(claims.sub.clone())),
+		credentials_changed_at: Set(Some(utils::unix_now_millis())),
+		..Default::default()
+	};
+	let user = active.insert(db).await.dot()?;
+	info!(
+		username,
+		sub = claims.sub,
+		"provisioned user from OIDC login"
+	);
+	Ok(Ok(user))
+}
+
+#[cfg(test)]
+mod tests {
+	use std::net::TcpListener;
+
+	use jsonwebtoken::{EncodingKey, Header, encode, get_current_timestamp};
+	use tide::StatusCode;
+
+	use super::*;
+	use crate::jwt::tests::ED25519_PRIVATE_KEY;
+	use crate::server::make_resp;
+
+	/// Public half of [`ED25519_PRIVATE_KEY`] as JWK `x`
+	const ED25519_JWK_X: &str = "LmEByVpq_4l36NsYXXR2NWME-_xFEc8QqI3T9y_PqBs";
+	const CLIENT_ID: &str = "test-client";
+
+	#[derive(Deserialize)]
+	struct TokenForm {
+		code: String,
+		code_verifier: String,
+	}
+
+	/// Serves discovery, JWKS and a token endpoint. Codes have the form
+	/// `<pkce challenge>.<nonce>`, the issued ID token carries that nonce.
+	async fn start_mock_issuer() -> String {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let issuer = format!("http://{}", listener.local_addr().unwrap());
+		let mut app = tide::with_state(issuer.clone());
+		app.at("/.well-known/openid-configuration")
+			.get(|req: Request<String>| async move {
+				let issuer = req.state();
+				Ok(serde_json::json!({
+					"issuer": issuer,
+					"authorization_endpoint": format!("{issuer}/authorize"),
+					"token_endpoint": format!("{issuer}/token"),
+					"jwks_uri": format!("{issuer}/jwks"),
+				}))
+			});
+		app.at("/jwks").get(|_| async move {
+			Ok(serde_json::json!({
+				"keys": [{
+					"kty": "OKP",
+					"crv": "Ed25519",
+					"x": ED25519_JWK_X,
+					"kid": "test-key",
+					"use": "sig",
+				}]
+			}))
+		});
+		app.at("/token")
+			.post(|mut req: Request<String>| async move {
+				let form: TokenForm = req.body_form().await?;
+				let Some((challenge, nonce)) = form.code.split_once('.') else {
+					return Ok(make_resp(
+						400,
+						serde_json::json!({"error": "invalid_grant"}),
+					));
+				};
+				if pkce_challenge(&form.code_verifier) != challenge {
+					return Ok(make_resp(
+						400,
+						serde_json::json!({"error": "invalid_grant"}),
+					));
+				}
+				let now = get_current_timestamp();
+				let claims = serde_json::json!({
+					"iss": req.state(),
+					"sub": "mock-sub-1",
+					"aud": CLIENT_ID,
+					"iat": now,
+					"exp": now + 300,
+					"nonce": nonce,
+					"preferred_username": "mock-user",
+				});
+				let mut header = Header::new(Algorithm::EdDSA);
+				header.kid = Some("test-key".to_string());
+				let key = EncodingKey::from_ed_pem(ED25519_PRIVATE_KEY.as_bytes()).unwrap();
+				let id_token = encode(&header, &claims, &key).unwrap();
+				Ok(make_resp(
+					200,
+					serde_json::json!({"id_token": id_token, "token_type": "Bearer"}),
+				))
+			});
+		async_std::task::spawn(app.listen(listener));
+		issuer
+	}
+
+	fn test_config(issuer: &str) -> OidcConfig {
+		OidcConfig {
+			enabled: true,
+			issuer_url: issuer.to_string(),
+			client_id: CLIENT_ID.to_string(),
+			client_secret: Some("s3cret".to_string()),
+			..Default::default()
+		}
+	}
+
+	fn code_for(flow: &AuthFlow) -> String {
+		format!("{}.{}", flow.code_challenge(), flow.nonce)
+	}
+
+	#[test]
+	fn test_pkce_challenge_rfc7636_vector() {
+		assert_eq!(
+			pkce_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+			"E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+		);
+	}
+
+	#[async_std::test]
+	async fn test_discovery_and_authorization_url() {
+		let issuer = start_mock_issuer().await;
+		let provider = Provider::discover(&test_config(&issuer)).await.unwrap();
+		assert_eq!(provider.metadata.token_endpoint, format!("{issuer}/token"));
+
+		let flow = AuthFlow::new();
+		let url = Url::parse(&provider.authorization_url(&flow).unwrap()).unwrap();
+		assert_eq!(url.path(), "/authorize");
+		let query: std::collections::HashMap<_, _> = url.query_pairs().into_owned().collect();
+		assert_eq!(query["response_type"], "code");
+		assert_eq!(query["client_id"], CLIENT_ID);
+		assert_eq!(query["scope"], "openid profile email");
+		assert_eq!(query["state"], flow.state);
+		assert_eq!(query["nonce"], flow.nonce);
+		assert_eq!(query["code_challenge"], flow.code_challenge());
+		assert_eq!(query["code_challenge_method"], "S256");
+	}
+
+	#[async_std::test]
+	async fn test_code_exchange_and_id_token_validation() {
+		let issuer = start_mock_issuer().await;
+		let provider = Provider::discover(&test_config(&issuer)).await.unwrap();
+		let flow = AuthFlow::new();
+		let tokens = provider
+			.exchange_code(&code_for(&flow), &flow.code_verifier)
+			.await
+			.unwrap();
+		let claims = provider
+			.validate_id_token(&tokens.id_token, &flow.nonce)
+			.await
+			.unwrap();
+		assert_eq!(claims.iss, issuer);
+		assert_eq!(claims.sub, "mock-sub-1");
+		assert_eq!(claims.username("preferred_username"), "mock-user");
+		assert_eq!(claims.username("email"), "mock-sub-1");
+	}
+
+	#[async_std::test]
+	async fn test_exchange_rejects_wrong_code_verifier() {
+		let issuer = start_mock_issuer().await;
+		let provider = Provider::discover(&test_config(&issuer)).await.unwrap();
+		let flow = AuthFlow::new();
+		let other = AuthFlow::new();
+		let result = provider
+			.exchange_code(&code_for(&flow), &other.code_verifier)
+			.await;
+		assert!(result.is_err());
+	}
+
+	#[async_std::test]
+	async fn test_id_token_rejects_wrong_nonce_and_audience() {
+		let issuer = start_mock_issuer().await;
+		let provider = Provider::discover(&test_config(&issuer)).await.unwrap();
+		let flow = AuthFlow::new();
+		let tokens = provider
+			.exchange_code(&code_for(&flow), &flow.code_verifier)
+			.await
+			.unwrap();
+		assert!(
+			provider
+				.validate_id_token(&tokens.id_token, "other nonce")
+				.await
+				.is_err()
+		);
+
+		let other_client = Provider::discover(&OidcConfig {
+			client_id: "other-client".to_string(),
+			..test_config(&issuer)
+		})
+		.await
+		.unwrap();
+		assert!(
+			other_client
+				.validate_id_token(&tokens.id_token, &flow.nonce)
+				.await
+				.is_err()
+		);
+	}
+
+	fn claims(sub: &str, username: Option<&str>) -> IdTokenClaims {
+		let mut extra = serde_json::Map::new();
+		if let Some(username) = username {
+			extra.insert("preferred_username".to_string(), username.into());
+		}
+		IdTokenClaims {
+			iss: "https://idp.test".to_string(),
+			sub: sub.to_string(),
+			nonce: None,
+			extra,
+		}
+	}
+
+	async fn create_local_user(username: &str) {
+		let db = database::get_db_conn().unwrap();
+		user::ActiveModel {
+			username: Set(username.to_owned()),
+			password_hash: Set(NO_PASSWORD.to_owned()),
+			age: Set(0),
+			failed_login_count: Set(0),
+			..Default::default()
+		}
+		.insert(db)
+		.await
+		.unwrap();
+	}
+
+	#[async_std::test]
+	async fn test_provision_validates_the_username() {
+		database::init_test_database();
+		let cfg = OidcConfig::default();
+
+		let err = provision_user(&claims("sub-invalid-1", Some("bad name!")), &cfg)
+			.await
+			.unwrap()
+			.unwrap_err();
+		assert_eq!(err.status, StatusCode::BadRequest);
+		assert_eq!(err.code, "invalid_username");
+
+		// a missing claim falls back to `sub`, which has to be a valid username too
+		let err = provision_user(&claims("auth0|64f1c2", None), &cfg)
+			.await
+			.unwrap()
+			.unwrap_err();
+		assert_eq!(err.code, "invalid_username");
+		let user = provision_user(&claims("sub-missing-claim", None), &cfg)
+			.await
+			.unwrap()
+			.unwrap();
+		assert_eq!(user.username, "sub-missing-claim");
+		assert_eq!(user.oidc_subject.as_deref(), Some("sub-missing-claim"));
+	}
+
+	#[async_std::test]
+	async fn test_provision_refuses_conflicting_usernames() {
+		database::init_test_database();
+		create_local_user("oidc-local-alice").await;
+		let cfg = OidcConfig::default();
+		let alice = claims("sub-alice", Some("oidc-local-alice"));
+
+		let err = provision_user(&alice, &cfg).await.unwrap().unwrap_err();
+		assert_eq!(err.status, StatusCode::Conflict);
+		assert_eq!(err.code, "username_taken");
+
+		let linking = OidcConfig {
+			link_existing_users: true,
+			..Default::default()
+		};
+		let user = provision_user(&alice, &linking).await.unwrap().unwrap();
+		assert_eq!(user.username, "oidc-local-alice");
+		assert_eq!(user.oidc_subject.as_deref(), Some("sub-alice"));
+		// another identity can't take over the linked user
+		let mallory = claims("sub-mallory", Some("oidc-local-alice"));
+		let err = provision_user(&mallory, &linking)
+			.await
+			.unwrap()
+			.unwrap_err();
+		assert_eq!(err.code, "username_taken");
+	}
+
+	#[async_std::test]
+	async fn test_provision_disabled_only_admits_linked_identities() {
+		database::init_test_database();
+		let cfg = OidcConfig {
+			auto_provision: false,
+			..Default::default()
+		};
+		let bob = claims("sub-bob", Some("oidc-bob"));
+		let err = provision_user(&bob, &cfg).await.unwrap().unwrap_err();
+		assert_eq!(err.status, StatusCode::Forbidden);
+		assert_eq!(err.code, "not_provisioned");
+		assert!(auth::find_user("oidc-bob").await.unwrap().is_none());
+
+		let created = provision_user(&bob, &OidcConfig::default())
+			.await
+			.unwrap()
+			.unwrap();
+		let user = provision_user(&bob, &cfg).await.unwrap().unwrap();
+		assert_eq!(user.id, created.id);
+	}
+}
@@ -0,0 +1,302 @@
+use std::sync::LazyLock;
+
+use anyhow_ext::{Context, Result};
+use sea_orm::{ActiveModelTrait, ColumnTrait, EntityTrait, QueryFilter, Set, sea_query::Expr};
+use serde::Deserialize;
+use tide::Request;
+use tracing::{error, info};
+
+use crate::{
+	auth::{self, AuthMethod, Cred, CredCheck, Identity},
+	config, database,
+	entity::{password_reset, user},
+	error::AppError,
+	notify::{self, Notification},
+	proxy,
+	rate_limit::{MemoryStore, Quota, RateLimitStore},
+	server::make_resp,
+	users::{self, InvalidField},
+	utils,
+};
+
+const RESET_TOKEN_LEN: u8 = 40;
+/// Buckets of the per-username limit on reset requests
+const RESET_LIMIT_MAX_KEYS: usize = 10_000;
+
+static RESET_LIMIT: LazyLock<MemoryStore> =
+	LazyLock::new(|| MemoryStore::new(RESET_LIMIT_MAX_KEYS));
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct ChangePassword {
+	current_password: String,
+	new_password: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct ResetPassword {
+	new_password: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct ResetRequest {
+	username: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct ResetConfirm {
+	token: String,
+	new_password: String,
+}
+
+fn validate_new_password(password: &str) -> Result<(), InvalidField> {
+	users::validate_password(password).map_err(|invalid| InvalidField {
+		field: "new_password",
+		..invalid
+	})
+}
+
+/// `POST /api/users/me/password`, the caller proves the current password.
+///
+/// A session used for the request is moved to a fresh session id and stays logged in,
+/// every other session and bearer token of the user ends.
+pub async fn change_own<State: Clone + Send + Sync + 'static>(
+	mut req: Request<State>,
+) -> tide::Result {
+	let Some(identity) = req.ext::<Identity>().cloned() else {
+		return Ok(AppError::unauthorized("unauthenticated", "authentication is required").into());
+	};
+	let input: ChangePassword = match users::read_json(&mut req).await {
+		Ok(input) => input,
+		Err(resp) => return Ok(resp),
+	};
+	if let Err(invalid) = validate_new_password(&input.new_password) {
+		return Ok(invalid.into_resp());
+	}
+
+	let cred = Cred {
+		username: identity.username.clone(),
+		password: input.current_password,
+	};
+	let ip = proxy::client_ip(&req);
+	let check = auth::verify_cred(&cred, ip.as_deref()).await?;
+	if check == CredCheck::Invalid {
+		return Ok(AppError::forbidden(
+			"invalid_current_password",
+			"current password is incorrect",
+		)
+		.into());
+	}
+	if let Some(resp) = auth::cred_check_failure_resp(check) {
+		return Ok(resp);
+	}
+	let Some(user) = auth::find_user(&identity.username).await? else {
+		return Ok(AppError::unauthorized("unauthenticated", "authentication is required").into());
+	};
+
+	set_password(user, input.new_password).await?;
+	if identity.method == AuthMethod::Session {
+		auth::start_session(req.session_mut(), &identity.username)?;
+	}
+	Ok(make_resp(204, ""))
+}
+
+/// `POST /api/users/:id/password`, an administrator sets a new password
+pub async fn admin_reset<State: Clone + Send + Sync + 'static>(
+	mut req: Request<State>,
+) -> tide::Result {
+	let Some(id) = users::user_id(&req) else {
+		return Ok(users::invalid_id());
+	};
+	let input: ResetPassword = match users::read_json(&mut req).await {
+		Ok(input) => input,
+		Err(resp) => return Ok(resp),
+	};
+	if let Err(invalid) = validate_new_password(&input.new_password) {
+		return Ok(invalid.into_resp());
+	}
+	let Some(user) = users::find_by_id(id).await? else {
+		return Ok(users::not_found(id));
+	};
+	set_password(user, input.new_password).await?;
+	Ok(make_resp(204, ""))
+}
+
+/// `POST /api/password-reset/request`, sends a reset token to the user.
+///
+/// Always answers 202 right away and does the work in the background, so neither the
+/// response nor its timing reveals which usernames exist.
+pub async fn request_reset<State: Clone + Send + Sync + 'static>(
+	mut req: Request<State>,
+) -> tide::Result {
+	let input: ResetRequest = match users::read_json(&mut req).await {
+		Ok(input) => input,
+		Err(resp) => return Ok(resp),
+	};
+	async_std::task::spawn(async move {
+		if let Err(err) = send_reset_token(&input.username).await {
+			error!(
+				username = input.username,
+				"failed to send password reset token: {err:?}"
+			);
+		}
+	});
+	Ok(make_resp(202, ""))
+}
+
+async fn send_reset_token(username: &str) -> Result<()> {
+	let config = config::cfg().await;
+	let cfg = config.auth.password_reset.clone();
+	let env = config.env;
+	drop(config);
+	if cfg.requests_per_user_per_hour > 0 {
+		let quota = Quota {
+			burst: cfg.requests_per_user_per_hour,
+			per_sec: cfg.requests_per_user_per_hour as f64 / 3600.0,
+		};
+		if !RESET_LIMIT.acquire(username, quota).await?.allowed {
+			info!(
+				target: "security",
+				event = "password_reset_limited",
+				username,
+				"too many password reset requests"
+			);
+			return Ok(());
+		}
+	}
+	let Some(user) = auth::find_user(username).await? else {
+		return Ok(());
+	};
+	let token = create_reset_token(&user, cfg.token_ttl_secs).await?;
+	let notification = Notification {
+		recipient: user.username.clone(),
+		subject: "Password reset".to_string(),
+		body: format!(
+			"Use this token to reset your password within {} minutes: {token}",
+			cfg.token_ttl_secs / 60
+		),
+		created_at: utils::unix_now(),
+	};
+	let notifier = notify::from_config(&cfg, env);
+	async_std::task::spawn_blocking(move || notifier.send(&notification)).await
+}
+
+/// `POST /api/password-reset/confirm`, sets a new password with a reset token
+pub async fn confirm_reset<State: Clone + Send + Sync + 'static>(
+	mut req: Request<State>,
+) -> tide::Result {
+	let input: ResetConfirm = match users::read_json(&mut req).await {
+		Ok(input) => input,
+		Err(resp) => return Ok(resp),
+	};
+	if let Err(invalid) = validate_new_password(&input.new_password) {
+		return Ok(invalid.into_resp());
+	}
+	let Some(user) = consume_reset_token(&input.token).await? else {
+		return Ok(
+			AppError::bad_request("invalid_token", "reset token is invalid or expired").into(),
+		);
+	};
+	set_password(user, input.new_password).await?;
+	Ok(make_resp(204, ""))
+}
+
+/// Store a new reset token for `user`, returns the plaintext token
+async fn create_reset_token(user: &user::Model, ttl_secs: u64) -> Result<String> {
+	let token = utils::gen_n_random_str(RESET_TOKEN_LEN);
+	let now = utils::unix_now();
+	let model = password_reset::ActiveModel {
+		user_id: Set(user.id),
+		token_hash: Set(utils::sha256_hex(&token)),
+		created_at: Set(now),
+		expires_at: Set(now + ttl_secs as i64),
+		..Default::default()
+	};
+	let db = database::get_db_conn().dot()?;
+	model.insert(db).await.dot()?;
+	Ok(token)
+}
+
+/// Mark a valid reset token used, together with every other open token of its
+/// user, and return that user. None for unknown, used or expired tokens.
+async fn consume_reset_token(token: &str) -> Result<Option<user::Model>> {
+	let db = database::get_db_conn().dot()?;
+	let now = utils::unix_now();
+	let Some(reset) = password_reset::Entity::find()
+		.filter(password_reset::Column::TokenHash.eq(utils::sha256_hex(token)))
+		.filter(password_reset::Column::UsedAt.is_null())
+		.filter(password_reset::Column::ExpiresAt.gt(now))
+		.one(db)
+		.await
+		.dot()?
+	else {
+		return Ok(None);
+	};
+	// the `used_at` condition makes concurrent uses of the same token race for this
+	// update, only one of them changes the row
+	let claimed = password_reset::Entity::update_many()
+		.col_expr(password_reset::Column::UsedAt, Expr::value(now))
+		.filter(password_reset::Column::Id.eq(reset.id))
+		.filter(password_reset::Column::UsedAt.is_null())
+		.exec(db)
+		.await
+		.dot()?;
+	if claimed.rows_affected != 1 {
+		return Ok(None);
+	}
+	password_reset::Entity::update_many()
+		.col_expr(password_reset::Column::UsedAt, Expr::value(now))
+		.filter(password_reset::Column::UserId.eq(reset.user_id))
+		.filter(password_reset::Column::UsedAt.is_null())
+		.exec(db)
+		.await
+		.dot()?;
+	users::find_by_id(reset.user_id).await
+}
+
+/// Store the hash of `password` for `user`, clear its lockout and revoke its cached
+/// credential, sessions and bearer tokens
+async fn set_password(user: user::Model, password: String) -> Result<()> {
+	let password_hash =
+		async_std::task::spawn_blocking(move || auth::hash_password(&password)).await?;
+	let username = user.username.clone();
+	let mut active: user::ActiveModel = user.into();
+	active.password_hash = Set(password_hash);
+	active.failed_login_count = Set(0);
+	active.last_failed_login_at = Set(None);
+	active.locked_until = Set(None);
+	active.credentials_changed_at = Set(Some(utils::unix_now_millis()));
+	let db = database::get_db_conn().dot()?;
+	active.update(db).await.dot()?;
+	auth::invalidate_cached_cred(&username);
+	info!(target: "security", event = "password_changed", username, "password changed");
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_validate_new_password_names_field() {
+		assert!(validate_new_password("long enough").is_ok());
+		assert_eq!(
+			validate_new_password("short").unwrap_err().field,
+			"new_password"
+		);
+	}
+
+	#[test]
+	fn test_change_password_requires_both_fields() {
+		assert!(serde_json::from_str::<ChangePassword>(r#"{"new_password": "x"}"#).is_err());
+		assert!(
+			serde_json::from_str::<ChangePassword>(
+				r#"{"current_password": "a", "new_password": "b"}"#
+			)
+			.is_ok()
+		);
+	}
+}
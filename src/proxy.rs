@@ -0,0 +1,420 @@
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow_ext::{Context, Result, bail};
+use tide::{Middleware, Next, Request};
+
+use crate::config::ProxyConfig;
+
+/// Where a request came from, behind trusted proxies as told by their forwarding
+/// headers. Set as a request extension by [`ForwardedMiddleware`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientInfo {
+	/// `None` for peers without an IP, like those on Unix sockets
+	pub ip: Option<IpAddr>,
+	/// `http` or `https`
+	pub scheme: String,
+	/// The `Host` the client asked for
+	pub host: Option<String>,
+}
+
+/// An address range written as `10.0.0.0/8`, a bare address is a range of one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct IpNet {
+	addr: IpAddr,
+	prefix: u8,
+}
+
+impl IpNet {
+	fn parse(s: &str) -> Result<Self> {
+		let (addr, prefix) = match s.split_once('/') {
+			Some((addr, prefix)) => (addr, Some(prefix)),
+			None => (s, None),
+		};
+		let addr: IpAddr = addr
+			.parse()
+			.with_context(|| format!("invalid address {addr:?}"))?;
+		let max = if addr.is_ipv4() { 32 } else { 128 };
+		let prefix = match prefix {
+			Some(prefix) => prefix
+				.parse::<u8>()
+				.ok()
+				.filter(|prefix| *prefix <= max)
+				.with_context(|| format!("invalid prefix length {prefix:?}"))?,
+			None => max,
+		};
+		Ok(Self { addr, prefix })
+	}
+
+	fn contains(&self, ip: IpAddr) -> bool {
+		match (self.addr, ip.to_canonical()) {
+			(IpAddr::V4(net), IpAddr::V4(ip)) => {
+				let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+				u32::from(net) & mask == u32::from(ip) & mask
+			}
+			(IpAddr::V6(net), IpAddr::V6(ip)) => {
+				let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+				u128::from(net) & mask == u128::from(ip) & mask
+			}
+			_ => false,
+		}
+	}
+}
+
+/// The parsed [`ProxyConfig::trusted_proxies`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrustedProxies {
+	nets: Vec<IpNet>,
+	unix: bool,
+}
+
+impl TrustedProxies {
+	pub fn parse(entries: &[String]) -> Result<Self> {
+		let mut proxies = Self::default();
+		for entry in entries {
+			let entry = entry.trim();
+			if entry == "unix" {
+				proxies.unix = true;
+				continue;
+			}
+			if entry.is_empty() {
+				bail!("empty trusted proxy entry");
+			}
+			let net =
+				IpNet::parse(entry).with_context(|| format!("invalid trusted proxy {entry:?}"))?;
+			proxies.nets.push(net);
+		}
+		Ok(proxies)
+	}
+
+	fn is_empty(&self) -> bool {
+		self.nets.is_empty() && !self.unix
+	}
+
+	fn contains(&self, ip: IpAddr) -> bool {
+		self.nets.iter().any(|net| net.contains(ip))
+	}
+
+	/// Whether the peer of the connection may set forwarding headers
+	fn trusts_peer(&self, peer: Option<&str>) -> bool {
+		match peer {
+			// our listeners leave the peer of Unix socket connections unset
+			None => self.unix,
+			Some(peer) => parse_node(peer).is_some_and(|ip| self.contains(ip)),
+		}
+	}
+}
+
+/// One proxy hop of the forwarding headers
+#[derive(Debug, Default)]
+struct Hop<'a> {
+	/// `None` for `unknown` and obfuscated identifiers like `_hidden`
+	ip: Option<IpAddr>,
+	proto: Option<&'a str>,
+	host: Option<&'a str>,
+}
+
+/// An IP with an optional port, `[2001:db8::1]:4711`, `192.0.2.1:80` or a bare IP
+fn parse_node(node: &str) -> Option<IpAddr> {
+	let node = node.trim().trim_matches('"');
+	if let Some(rest) = node.strip_prefix('[') {
+		let (ip, _port) = rest.split_once(']')?;
+		return ip.parse().ok();
+	}
+	node.parse::<IpAddr>()
+		.ok()
+		.or_else(|| node.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+		.map(|ip| ip.to_canonical())
+}
+
+/// Splits at `sep` outside of quoted strings
+fn split_unquoted(s: &str, sep: char) -> Vec<&str> {
+	let mut quoted = false;
+	let mut escaped = false;
+	let mut start = 0;
+	let mut parts = Vec::new();
+	for (i, c) in s.char_indices() {
+		match c {
+			_ if escaped => escaped = false,
+			'\\' if quoted => escaped = true,
+			'"' => quoted = !quoted,
+			c if c == sep && !quoted => {
+				parts.push(&s[start..i]);
+				start = i + c.len_utf8();
+			}
+			_ => {}
+		}
+	}
+	parts.push(&s[start..]);
+	parts
+}
+
+/// Hops of an RFC 7239 `Forwarded` header, nearest to the client first
+fn parse_forwarded(value: &str) -> Vec<Hop<'_>> {
+	split_unquoted(value, ',')
+		.into_iter()
+		.map(|element| {
+			let mut hop = Hop::default();
+			for pair in split_unquoted(element, ';') {
+				let Some((name, value)) = pair.split_once('=') else {
+					continue;
+				};
+				let value = value.trim().trim_matches('"');
+				match name.trim().to_ascii_lowercase().as_str() {
+					"for" => hop.ip = parse_node(value),
+					"proto" => hop.proto = Some(value),
+					"host" => hop.host = Some(value),
+					_ => {}
+				}
+			}
+			hop
+		})
+		.collect()
+}
+
+/// Hops of `X-Forwarded-For`, the proto and host only come with the one nearest to us
+fn parse_x_forwarded<'a>(
+	forwarded_for: &'a str,
+	proto: Option<&'a str>,
+	host: Option<&'a str>,
+) -> Vec<Hop<'a>> {
+	let mut hops: Vec<Hop> = forwarded_for
+		.split(',')
+		.map(|node| Hop {
+			ip: parse_node(node),
+			..Default::default()
+		})
+		.collect();
+	// proxies appending to these lists leave the value they received on the left
+	let last = |value: Option<&'a str>| value.and_then(|v| v.rsplit(',').next()).map(str::trim);
+	if let Some(hop) = hops.last_mut() {
+		hop.proto = last(proto);
+		hop.host = last(host);
+	}
+	hops
+}
+
+/// Joins repeated headers the way a list header is meant to be read
+fn header_value<State>(req: &Request<State>, name: &str) -> Option<String> {
+	let values = req.header(name)?;
+	let joined = values
+		.iter()
+		.map(|value| value.as_str())
+		.collect::<Vec<_>>()
+		.join(",");
+	(!joined.trim().is_empty()).then_some(joined)
+}
+
+fn valid_proto(proto: &str) -> Option<String> {
+	let proto = proto.trim().to_ascii_lowercase();
+	matches!(proto.as_str(), "http" | "https").then_some(proto)
+}
+
+fn valid_host(host: &str) -> Option<String> {
+	let host = host.trim();
+	let valid = !host.is_empty()
+		&& host
+			.bytes()
+			.all(|b| b.is_ascii_alphanumeric() || b".-:[]_".contains(&b));
+	valid.then(|| host.to_owned())
+}
+
+/// Resolves the client of `req` from the forwarding headers of trusted proxies.
+///
+/// Hops are walked from the nearest one back towards the client, the first address
+/// that isn't a trusted proxy is the client. `Forwarded` wins over `X-Forwarded-*`
+/// when both are present. A hop of `unknown` or an obfuscated identifier ends the
+/// walk, the address of the proxy that reported it is kept.
+fn resolve<State>(req: &Request<State>, proxies: &TrustedProxies) -> ClientInfo {
+	let peer = req.peer_addr();
+	let mut client = ClientInfo {
+		ip: peer.and_then(parse_node),
+		scheme: req.url().scheme().to_owned(),
+		// not `req.host()`, which believes forwarding headers from anyone
+		host: req
+			.header("Host")
+			.map(|host| host.as_str().to_owned())
+			.or_else(|| req.url().host_str().map(str::to_owned)),
+	};
+	if proxies.is_empty() || !proxies.trusts_peer(peer) {
+		return client;
+	}
+
+	let forwarded = header_value(req, "Forwarded");
+	let forwarded_for = header_value(req, "X-Forwarded-For");
+	let proto = header_value(req, "X-Forwarded-Proto");
+	let host = header_value(req, "X-Forwarded-Host");
+	let hops = match forwarded {
+		Some(ref forwarded) => parse_forwarded(forwarded),
+		None if forwarded_for.is_some() || proto.is_some() || host.is_some() => {
+			// a proxy may only set `X-Forwarded-Proto`, the hop without an IP keeps the peer
+			let forwarded_for = forwarded_for.as_deref().unwrap_or_default();
+			parse_x_forwarded(forwarded_for, proto.as_deref(), host.as_deref())
+		}
+		None => return client,
+	};
+
+	for hop in hops.iter().rev() {
+		if let Some(value) = hop.proto.and_then(valid_proto) {
+			client.scheme = value;
+		}
+		if let Some(value) = hop.host.and_then(valid_host) {
+			client.host = Some(value);
+		}
+		let Some(ip) = hop.ip else {
+			break;
+		};
+		client.ip = Some(ip);
+		if !proxies.contains(ip) {
+			break;
+		}
+	}
+	client
+}
+
+/// Resolves the real client behind trusted reverse proxies into a [`ClientInfo`]
+/// extension, see [`ProxyConfig`]. Without trusted proxies the forwarding headers are
+/// ignored and the peer address is the client.
+pub struct ForwardedMiddleware {
+	proxies: TrustedProxies,
+}
+
+impl ForwardedMiddleware {
+	pub fn new(cfg: &ProxyConfig) -> Result<Self> {
+		Ok(Self {
+			proxies: TrustedProxies::parse(&cfg.trusted_proxies)?,
+		})
+	}
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for ForwardedMiddleware {
+	async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+		let client = resolve(&req, &self.proxies);
+		req.set_ext(client);
+		Ok(next.run(req).await)
+	}
+}
+
+/// The client IP as resolved by [`ForwardedMiddleware`], otherwise the peer address
+pub fn client_ip<State>(req: &Request<State>) -> Option<String> {
+	if let Some(client) = req.ext::<ClientInfo>() {
+		return client.ip.map(|ip| ip.to_string());
+	}
+	let peer = req.peer_addr()?;
+	match parse_node(peer) {
+		Some(ip) => Some(ip.to_string()),
+		None => Some(peer.to_owned()),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use tide::http::{Method, Request as HttpRequest, Url};
+
+	use super::*;
+
+	fn proxies(entries: &[&str]) -> TrustedProxies {
+		let entries: Vec<String> = entries.iter().map(|e| e.to_string()).collect();
+		TrustedProxies::parse(&entries).unwrap()
+	}
+
+	fn request(peer: Option<&str>, headers: &[(&str, &str)]) -> Request<()> {
+		let url = Url::parse("http://app.internal/api/users").unwrap();
+		let mut req = HttpRequest::new(Method::Get, url);
+		req.set_peer_addr(peer);
+		for (name, value) in headers {
+			req.append_header(*name, *value);
+		}
+		req.into()
+	}
+
+	#[test]
+	fn test_ip_net() {
+		let net = IpNet::parse("10.1.0.0/16").unwrap();
+		assert!(net.contains("10.1.200.3".parse().unwrap()));
+		assert!(!net.contains("10.2.0.1".parse().unwrap()));
+		assert!(net.contains("::ffff:10.1.0.9".parse().unwrap()));
+		assert!(
+			IpNet::parse("0.0.0.0/0")
+				.unwrap()
+				.contains("8.8.8.8".parse().unwrap())
+		);
+		let v6 = IpNet::parse("fd00::/8").unwrap();
+		assert!(v6.contains("fd12::1".parse().unwrap()));
+		assert!(!v6.contains("10.1.0.1".parse().unwrap()));
+
+		assert!(IpNet::parse("10.0.0.0/33").is_err());
+		assert!(IpNet::parse("proxy.internal").is_err());
+	}
+
+	#[test]
+	fn test_untrusted_peer_headers_are_ignored() {
+		let req = request(
+			Some("203.0.113.9:5555"),
+			&[
+				("X-Forwarded-For", "1.2.3.4"),
+				("X-Forwarded-Proto", "https"),
+			],
+		);
+		let client = resolve(&req, &proxies(&["10.0.0.0/8"]));
+		assert_eq!(client.ip, Some("203.0.113.9".parse().unwrap()));
+		assert_eq!(client.scheme, "http");
+		assert_eq!(client.host.as_deref(), Some("app.internal"));
+	}
+
+	#[test]
+	fn test_x_forwarded_for_skips_trusted_hops() {
+		let req = request(
+			Some("10.0.0.2:5555"),
+			&[
+				// the leftmost entry is whatever the client claimed
+				("X-Forwarded-For", "6.6.6.6, 198.51.100.7, 10.0.0.1"),
+				("X-Forwarded-Proto", "https"),
+				("X-Forwarded-Host", "api.example.com"),
+			],
+		);
+		let client = resolve(&req, &proxies(&["10.0.0.0/8"]));
+		assert_eq!(client.ip, Some("198.51.100.7".parse().unwrap()));
+		assert_eq!(client.scheme, "https");
+		assert_eq!(client.host.as_deref(), Some("api.example.com"));
+	}
+
+	#[test]
+	fn test_forwarded_header() {
+		let req = request(
+			None,
+			&[
+				(
+					"Forwarded",
+					r#"for="[2001:db8:cafe::17]:4711";proto=https;host="api.example.com""#,
+				),
+				("Forwarded", "for=10.0.0.1;proto=http"),
+				("X-Forwarded-For", "6.6.6.6"),
+			],
+		);
+		let client = resolve(&req, &proxies(&["unix", "10.0.0.0/8"]));
+		assert_eq!(client.ip, Some("2001:db8:cafe::17".parse().unwrap()));
+		assert_eq!(client.scheme, "https");
+		assert_eq!(client.host.as_deref(), Some("api.example.com"));
+
+		// Unix socket peers are only trusted when listed
+		let client = resolve(&req, &proxies(&["10.0.0.0/8"]));
+		assert_eq!(client.ip, None);
+	}
+
+	#[test]
+	fn test_unknown_hop_keeps_the_proxy() {
+		let req = request(
+			Some("10.0.0.2:5555"),
+			&[("Forwarded", "for=unknown, for=10.0.0.1")],
+		);
+		let client = resolve(&req, &proxies(&["10.0.0.0/8"]));
+		assert_eq!(client.ip, Some("10.0.0.1".parse().unwrap()));
+	}
+
+	#[test]
+	fn test_split_unquoted() {
+		let parts = split_unquoted(r#"a="x,\"y";b, c"#, ',');
+		assert_eq!(parts, [r#"a="x,\"y";b"#, " c"]);
+	}
+}
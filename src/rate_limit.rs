@@ -0,0 +1,470 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow_ext::Result;
+use dashmap::DashMap;
+use metrics::counter;
+use tide::{Middleware, Next, Request, Response};
+use tracing::warn;
+
+use crate::api_key::ApiKeyInfo;
+use crate::auth::Identity;
+use crate::config::{RateLimitConfig, RateLimitKey, RouteRateLimit};
+use crate::error::AppError;
+use crate::proxy;
+use crate::route_pattern::RoutePattern;
+
+/// Size and refill rate of a token bucket
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quota {
+	pub burst: u32,
+	pub per_sec: f64,
+}
+
+impl Quota {
+	/// Seconds an empty bucket takes to fill up
+	fn window_secs(&self) -> u64 {
+		(self.burst as f64 / self.per_sec).ceil() as u64
+	}
+}
+
+/// Outcome of taking a token from a bucket
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Decision {
+	pub allowed: bool,
+	/// Whole tokens left in the bucket
+	pub remaining: u32,
+	/// Seconds until the bucket is full again
+	pub reset_secs: u64,
+	/// Seconds until the next token is available, 0 when the request was allowed
+	pub retry_after_secs: u64,
+}
+
+/// Where the buckets live. [`MemoryStore`] limits each process on its own, implement
+/// this on a shared store like Redis to limit across instances.
+#[tide::utils::async_trait]
+pub trait RateLimitStore: Send + Sync {
+	/// Take one token from the bucket of `key`, creating a full one if there is none
+	async fn acquire(&self, key: &str, quota: Quota) -> Result<Decision>;
+}
+
+struct Bucket {
+	tokens: f64,
+	updated: Instant,
+	/// When the bucket is full again, full buckets are what pruning drops
+	full_at: Instant,
+}
+
+/// Each prune frees at least `max_keys / PRUNE_FRACTION` buckets, so the scan over
+/// all of them runs once per that many new keys
+const PRUNE_FRACTION: usize = 10;
+
+/// Buckets in a [`DashMap`] of at most `max_keys`. Once full, full buckets are dropped
+/// and then the least recently used ones.
+pub struct MemoryStore {
+	buckets: DashMap<String, Bucket>,
+	max_keys: usize,
+	pruning: Mutex<()>,
+}
+
+impl MemoryStore {
+	pub fn new(max_keys: usize) -> Self {
+		Self {
+			buckets: DashMap::new(),
+			max_keys: max_keys.max(1),
+			pruning: Mutex::new(()),
+		}
+	}
+
+	fn prune(&self, now: Instant) {
+		// one prune at a time is enough, the others go on with their request
+		let Ok(_guard) = self.pruning.try_lock() else {
+			return;
+		};
+		self.buckets.retain(|_, bucket| bucket.full_at > now);
+		let target = self.max_keys - (self.max_keys / PRUNE_FRACTION).max(1);
+		let excess = self.buckets.len().saturating_sub(target);
+		if excess == 0 {
+			return;
+		}
+		let mut updated: Vec<Instant> = self.buckets.iter().map(|b| b.updated).collect();
+		let (_, cutoff, _) = updated.select_nth_unstable(excess - 1);
+		let cutoff = *cutoff;
+		self.buckets.retain(|_, bucket| bucket.updated > cutoff);
+	}
+
+	fn take(&self, key: &str, quota: Quota, now: Instant) -> Decision {
+		if self.buckets.len() >= self.max_keys && !self.buckets.contains_key(key) {
+			self.prune(now);
+		}
+		let mut bucket = self.buckets.entry(key.to_owned()).or_insert(Bucket {
+			tokens: quota.burst as f64,
+			updated: now,
+			full_at: now,
+		});
+		let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+		let tokens = (bucket.tokens + elapsed * quota.per_sec).min(quota.burst as f64);
+		let allowed = tokens >= 1.0;
+		let tokens = if allowed { tokens - 1.0 } else { tokens };
+		let missing = quota.burst as f64 - tokens;
+		bucket.tokens = tokens;
+		bucket.updated = now;
+		bucket.full_at = now + Duration::from_secs_f64(missing / quota.per_sec);
+		Decision {
+			allowed,
+			remaining: tokens as u32,
+			reset_secs: (missing / quota.per_sec).ceil() as u64,
+			retry_after_secs: if allowed {
+				0
+			} else {
+				(((1.0 - tokens) / quota.per_sec).ceil() as u64).max(1)
+			},
+		}
+	}
+}
+
+#[tide::utils::async_trait]
+impl RateLimitStore for MemoryStore {
+	async fn acquire(&self, key: &str, quota: Quota) -> Result<Decision> {
+		Ok(self.take(key, quota, Instant::now()))
+	}
+}
+
+/// The global limit or one of the route limits, each with buckets of its own
+struct Rule {
+	name: String,
+	key: RateLimitKey,
+	quota: Quota,
+}
+
+/// Answers 429 once a client used up its bucket, see [`RateLimitConfig`].
+///
+/// Mounted twice: [`RateLimitMiddleware::before_auth`] ahead of
+/// [`crate::auth::AuthMiddleware`] with the limits keyed by IP, and
+/// [`RateLimitMiddleware::new`] after it with the limits keyed by username or API key.
+/// Every limited response carries the `RateLimit-*` headers of the IETF draft.
+pub struct RateLimitMiddleware {
+	store: Arc<dyn RateLimitStore>,
+	global: Rule,
+	/// `None` for routes whose limit is checked by the other pass
+	routes: Vec<(RoutePattern, Option<Rule>)>,
+}
+
+fn route_rule(route: &RouteRateLimit, key: RateLimitKey) -> Rule {
+	let methods = if route.route.methods.is_empty() {
+		"*".to_string()
+	} else {
+		route.route.methods.join(",")
+	};
+	Rule {
+		name: format!("{methods} {}", route.route.path),
+		key,
+		quota: Quota {
+			burst: route.burst,
+			per_sec: route.requests_per_sec,
+		},
+	}
+}
+
+impl RateLimitMiddleware {
+	/// The limits after authentication with buckets in memory
+	pub fn new(cfg: &RateLimitConfig) -> Self {
+		let store = Arc::new(MemoryStore::new(cfg.max_tracked_keys));
+		Self::with_store(cfg, store)
+	}
+
+	pub fn with_store(cfg: &RateLimitConfig, store: Arc<dyn RateLimitStore>) -> Self {
+		let routes = cfg
+			.routes
+			.iter()
+			.map(|route| {
+				let key = route.key.unwrap_or(cfg.key);
+				let rule = (key != RateLimitKey::Ip).then(|| route_rule(route, key));
+				(route.route.clone(), rule)
+			})
+			.collect();
+		Self {
+			store,
+			global: Rule {
+				name: "global".to_string(),
+				key: cfg.key,
+				quota: Quota {
+					burst: cfg.burst,
+					per_sec: cfg.requests_per_sec,
+				},
+			},
+			routes,
+		}
+	}
+
+	/// The limits keyed by IP with buckets in memory, checked before authentication
+	pub fn before_auth(cfg: &RateLimitConfig) -> Self {
+		let store = Arc::new(MemoryStore::new(cfg.max_tracked_keys));
+		let routes = cfg
+			.routes
+			.iter()
+			.filter(|route| route.burst == 0 || route.key.unwrap_or(cfg.key) == RateLimitKey::Ip)
+			.map(|route| {
+				(
+					route.route.clone(),
+					Some(route_rule(route, RateLimitKey::Ip)),
+				)
+			})
+			.collect();
+		Self {
+			store,
+			global: Rule {
+				name: "ip".to_string(),
+				key: RateLimitKey::Ip,
+				quota: Quota {
+					burst: cfg.ip_burst,
+					per_sec: cfg.ip_requests_per_sec,
+				},
+			},
+			routes,
+		}
+	}
+
+	fn rule<State>(&self, req: &Request<State>) -> Option<&Rule> {
+		self.routes
+			.iter()
+			.find(|(route, _)| route.matches(req.method(), req.url().path()))
+			.map_or(Some(&self.global), |(_, rule)| rule.as_ref())
+	}
+
+	fn client<State>(&self, req: &Request<State>, key: RateLimitKey) -> String {
+		let identity = match key {
+			RateLimitKey::Ip => None,
+			RateLimitKey::User => req
+				.ext::<Identity>()
+				.map(|identity| format!("user:{}", identity.username)),
+			RateLimitKey::ApiKey => req.ext::<ApiKeyInfo>().map(|key| format!("key:{}", key.id)),
+		};
+		identity.unwrap_or_else(|| {
+			let ip = proxy::client_ip(req);
+			format!("ip:{}", ip.as_deref().unwrap_or("-"))
+		})
+	}
+}
+
+/// Sets the `RateLimit-*` headers, unless the other pass set those of a bucket closer
+/// to running out
+fn insert_headers(resp: &mut Response, quota: Quota, decision: &Decision) {
+	let remaining = resp
+		.header("RateLimit-Remaining")
+		.and_then(|value| value.as_str().parse::<u32>().ok());
+	if remaining.is_some_and(|remaining| remaining <= decision.remaining) {
+		return;
+	}
+	resp.insert_header("RateLimit-Limit", quota.burst.to_string());
+	resp.insert_header("RateLimit-Remaining", decision.remaining.to_string());
+	resp.insert_header("RateLimit-Reset", decision.reset_secs.to_string());
+	resp.insert_header(
+		"RateLimit-Policy",
+		format!("{};w={}", quota.burst, quota.window_secs()),
+	);
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for RateLimitMiddleware {
+	async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+		let Some(rule) = self.rule(&req).filter(|rule| rule.quota.burst > 0) else {
+			return Ok(next.run(req).await);
+		};
+		let bucket = format!("{}|{}", rule.name, self.client(&req, rule.key));
+		let decision = match self.store.acquire(&bucket, rule.quota).await {
+			Ok(decision) => decision,
+			Err(err) => {
+				// a broken shared store must not take the whole API down with it
+				warn!(?err, "rate limit store failed, request let through");
+				return Ok(next.run(req).await);
+			}
+		};
+		if !decision.allowed {
+			counter!("http_rate_limited_total", "rule" => rule.name.clone()).increment(1);
+			let mut resp: Response = AppError::too_many_requests(
+				"rate_limited",
+				"too many requests",
+				decision.retry_after_secs,
+			)
+			.into();
+			insert_headers(&mut resp, rule.quota, &decision);
+			return Ok(resp);
+		}
+		let mut resp = next.run(req).await;
+		insert_headers(&mut resp, rule.quota, &decision);
+		Ok(resp)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use tide::StatusCode;
+	use tide::http::{Method, Request as HttpRequest, Response as HttpResponse, Url};
+
+	use super::*;
+
+	#[test]
+	fn test_bucket_refills() {
+		let store = MemoryStore::new(100);
+		let quota = Quota {
+			burst: 2,
+			per_sec: 0.5,
+		};
+		let start = Instant::now();
+		assert!(store.take("a", quota, start).allowed);
+		let second = store.take("a", quota, start);
+		assert!(second.allowed);
+		assert_eq!(second.remaining, 0);
+		assert_eq!(second.reset_secs, 4);
+
+		let denied = store.take("a", quota, start);
+		assert!(!denied.allowed);
+		assert_eq!(denied.retry_after_secs, 2);
+		// other clients have buckets of their own
+		assert!(store.take("b", quota, start).allowed);
+
+		assert!(
+			store
+				.take("a", quota, start + Duration::from_secs(2))
+				.allowed
+		);
+		assert!(
+			!store
+				.take("a", quota, start + Duration::from_secs(2))
+				.allowed
+		);
+	}
+
+	#[test]
+	fn test_full_buckets_are_pruned() {
+		let store = MemoryStore::new(2);
+		let quota = Quota {
+			burst: 1,
+			per_sec: 1.0,
+		};
+		let start = Instant::now();
+		store.take("a", quota, start);
+		store.take("b", quota, start);
+		store.take("c", quota, start + Duration::from_secs(5));
+		assert_eq!(store.buckets.len(), 1);
+	}
+
+	#[test]
+	fn test_least_recently_used_buckets_are_evicted() {
+		let store = MemoryStore::new(20);
+		let quota = Quota {
+			burst: 10,
+			per_sec: 0.001,
+		};
+		let start = Instant::now();
+		// none of these buckets fills up again, e.g. a client rotating its IPv6 address
+		for i in 0..1000u64 {
+			store.take(&format!("ip:{i}"), quota, start + Duration::from_millis(i));
+			assert!(store.buckets.len() <= 20);
+		}
+		assert!(store.buckets.contains_key("ip:999"));
+		assert!(!store.buckets.contains_key("ip:0"));
+	}
+
+	/// Stands in for `AuthMiddleware`, `/api/private` needs an `Authorization` header
+	struct RequireAuthorization;
+
+	#[tide::utils::async_trait]
+	impl Middleware<()> for RequireAuthorization {
+		async fn handle(&self, req: Request<()>, next: Next<'_, ()>) -> tide::Result {
+			if req.url().path() == "/api/private" && req.header("Authorization").is_none() {
+				return Ok(AppError::unauthorized("unauthenticated", "no").into());
+			}
+			Ok(next.run(req).await)
+		}
+	}
+
+	/// Both passes around the auth stand-in, the way the server mounts them
+	fn app(cfg: RateLimitConfig) -> tide::Server<()> {
+		let mut app = tide::new();
+		app.with(RateLimitMiddleware::before_auth(&cfg));
+		app.with(RequireAuthorization);
+		app.with(RateLimitMiddleware::new(&cfg));
+		app.at("/api/private").get(|_| async move { Ok("private") });
+		app.at("/api/users").get(|_| async move { Ok("users") });
+		app.at("/api/login").post(|_| async move { Ok("welcome") });
+		app
+	}
+
+	async fn send(app: &tide::Server<()>, method: Method, path: &str, ip: &str) -> HttpResponse {
+		let url = Url::parse(&format!("http://localhost{path}")).unwrap();
+		let mut req = HttpRequest::new(method, url);
+		req.set_peer_addr(Some(format!("{ip}:40000")));
+		app.respond(req).await.unwrap()
+	}
+
+	#[async_std::test]
+	async fn test_limit_per_client_ip() {
+		let app = app(RateLimitConfig {
+			requests_per_sec: 0.1,
+			burst: 2,
+			..Default::default()
+		});
+		let resp = send(&app, Method::Get, "/api/users", "10.0.0.1").await;
+		assert_eq!(resp.status(), StatusCode::Ok);
+		assert_eq!(resp["RateLimit-Limit"].as_str(), "2");
+		assert_eq!(resp["RateLimit-Remaining"].as_str(), "1");
+		assert_eq!(resp["RateLimit-Policy"].as_str(), "2;w=20");
+		send(&app, Method::Get, "/api/users", "10.0.0.1").await;
+
+		let resp = send(&app, Method::Get, "/api/users", "10.0.0.1").await;
+		assert_eq!(resp.status(), StatusCode::TooManyRequests);
+		assert_eq!(resp["Retry-After"].as_str(), "10");
+		assert_eq!(resp["RateLimit-Remaining"].as_str(), "0");
+
+		let resp = send(&app, Method::Get, "/api/users", "10.0.0.2").await;
+		assert_eq!(resp.status(), StatusCode::Ok);
+	}
+
+	#[async_std::test]
+	async fn test_route_overrides() {
+		let app = app(RateLimitConfig {
+			requests_per_sec: 0.1,
+			burst: 1,
+			routes: vec![
+				RouteRateLimit {
+					route: RoutePattern::new("/api/login", &["POST"]),
+					requests_per_sec: 1.0,
+					burst: 3,
+					key: None,
+				},
+				RouteRateLimit {
+					route: RoutePattern::new("/api/users", &[]),
+					requests_per_sec: 0.0,
+					burst: 0,
+					key: None,
+				},
+			],
+			..Default::default()
+		});
+		for _ in 0..5 {
+			let resp = send(&app, Method::Get, "/api/users", "10.0.0.1").await;
+			assert_eq!(resp.status(), StatusCode::Ok);
+			assert!(resp.header("RateLimit-Limit").is_none());
+		}
+		let resp = send(&app, Method::Post, "/api/login", "10.0.0.1").await;
+		assert_eq!(resp["RateLimit-Limit"].as_str(), "3");
+	}
+
+	#[async_std::test]
+	async fn test_failed_auth_counts_towards_the_ip_limit() {
+		let app = app(RateLimitConfig {
+			ip_requests_per_sec: 0.1,
+			ip_burst: 2,
+			..Default::default()
+		});
+		for _ in 0..2 {
+			let resp = send(&app, Method::Get, "/api/private", "10.0.0.1").await;
+			assert_eq!(resp.status(), StatusCode::Unauthorized);
+		}
+		let resp = send(&app, Method::Get, "/api/private", "10.0.0.1").await;
+		assert_eq!(resp.status(), StatusCode::TooManyRequests);
+		assert_eq!(resp["RateLimit-Limit"].as_str(), "2");
+	}
+}
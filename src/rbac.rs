@@ -0,0 +1,156 @@
+use std::collections::HashSet;
+
+use anyhow_ext::{Context, Result};
+use sea_orm::{ColumnTrait, EntityTrait, QueryFilter};
+
+use crate::{
+	database,
+	entity::{permission, role, role_permission, user, user_role},
+	error::AppError,
+};
+
+/// Permission that grants everything, held by the seeded `admin` role
+pub const WILDCARD_PERMISSION: &str = "*";
+
+/// Roles and permissions of the authenticated caller, set into the request extensions
+/// by [`crate::auth::AuthMiddleware`].
+#[derive(Debug, Clone, Default)]
+pub struct Grants {
+	pub roles: Vec<String>,
+	pub permissions: HashSet<String>,
+}
+
+impl Grants {
+	pub fn has_permission(&self, permission: &str) -> bool {
+		self.permissions.contains(WILDCARD_PERMISSION) || self.permissions.contains(permission)
+	}
+
+	/// Keep only the permissions in `scopes`, so an API key never exceeds its owner
+	pub fn restrict_to(self, scopes: &[String]) -> Self {
+		if scopes.iter().any(|scope| scope == WILDCARD_PERMISSION) {
+			return self;
+		}
+		let permissions = scopes
+			.iter()
+			.filter(|scope| self.has_permission(scope))
+			.cloned()
+			.collect();
+		Self {
+			roles: self.roles,
+			permissions,
+		}
+	}
+}
+
+/// Load the roles of `username` and the permissions they grant.
+///
+/// Unknown users, e.g. the subject of a token whose account was deleted, get no grants.
+pub async fn load_grants(username: &str) -> Result<Grants> {
+	let db = database::get_db_conn().dot()?;
+	let user = user::Entity::find()
+		.filter(user::Column::Username.eq(username))
+		.one(db)
+		.await
+		.dot()?;
+	let Some(user) = user else {
+		return Ok(Grants::default());
+	};
+
+	let roles = role::Entity::find()
+		.inner_join(user_role::Entity)
+		.filter(user_role::Column::UserId.eq(user.id))
+		.all(db)
+		.await
+		.dot()?;
+	let role_ids: Vec<i32> = roles.iter().map(|role| role.id).collect();
+	let permissions = permission::Entity::find()
+		.inner_join(role_permission::Entity)
+		.filter(role_permission::Column::RoleId.is_in(role_ids))
+		.all(db)
+		.await
+		.dot()?;
+
+	Ok(Grants {
+		roles: roles.into_iter().map(|role| role.name).collect(),
+		permissions: permissions.into_iter().map(|p| p.name).collect(),
+	})
+}
+
+/// Route-level guard, must be mounted behind [`crate::auth::AuthMiddleware`].
+///
+/// ```no_run
+/// app.at("/api/log/:directive")
+///     .with(RequirePermission::new("log:write"))
+///     .post(handler);
+/// ```
+pub struct RequirePermission {
+	permission: &'static str,
+}
+
+impl RequirePermission {
+	pub fn new(permission: &'static str) -> Self {
+		Self { permission }
+	}
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> tide::Middleware<State> for RequirePermission {
+	async fn handle(&self, req: tide::Request<State>, next: tide::Next<'_, State>) -> tide::Result {
+		match req.ext::<Grants>() {
+			Some(grants) if grants.has_permission(self.permission) => Ok(next.run(req).await),
+			Some(_) => {
+				let err = AppError::forbidden(
+					"forbidden",
+					format!("permission {} is required", self.permission),
+				)
+				.with_detail("required_permission", self.permission);
+				Ok(err.into())
+			}
+			None => {
+				Ok(AppError::unauthorized("unauthenticated", "authentication is required").into())
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn grants(permissions: &[&str]) -> Grants {
+		Grants {
+			roles: vec![],
+			permissions: permissions.iter().map(|p| p.to_string()).collect(),
+		}
+	}
+
+	#[test]
+	fn test_has_permission() {
+		let grants = grants(&["log:read"]);
+		assert!(grants.has_permission("log:read"));
+		assert!(!grants.has_permission("log:write"));
+	}
+
+	#[test]
+	fn test_restrict_to_scopes() {
+		let scopes = vec!["log:read".to_string(), "user:write".to_string()];
+		let restricted = grants(&["log:read", "log:write"]).restrict_to(&scopes);
+		assert!(restricted.has_permission("log:read"));
+		assert!(!restricted.has_permission("log:write"));
+		assert!(!restricted.has_permission("user:write"));
+
+		let restricted = grants(&["*"]).restrict_to(&scopes);
+		assert!(restricted.has_permission("user:write"));
+		assert!(!restricted.has_permission("log:write"));
+
+		let all = grants(&["log:read"]).restrict_to(&["*".to_string()]);
+		assert!(all.has_permission("log:read"));
+		assert!(!all.has_permission("log:write"));
+	}
+
+	#[test]
+	fn test_wildcard_grants_everything() {
+		assert!(grants(&["*"]).has_permission("log:write"));
+		assert!(!Grants::default().has_permission("log:read"));
+	}
+}
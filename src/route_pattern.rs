@@ -0,0 +1,98 @@
+use serde::{Deserialize, Serialize};
+use tide::http::Method;
+
+/// A path pattern restricted to some HTTP methods, written in the config as
+/// `{ path = "/api/login", methods = ["POST"] }`.
+///
+/// `path` is matched exactly unless it contains `*`, which matches any sequence of
+/// characters including `/`, so `/static/*` matches everything below `/static/`.
+/// An empty `methods` list matches every method.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct RoutePattern {
+	pub path: String,
+	#[serde(default)]
+	pub methods: Vec<String>,
+}
+
+impl RoutePattern {
+	pub fn new(path: &str, methods: &[&str]) -> Self {
+		Self {
+			path: path.to_string(),
+			methods: methods.iter().map(|m| m.to_string()).collect(),
+		}
+	}
+
+	pub fn matches(&self, method: Method, path: &str) -> bool {
+		let method_matched = self.methods.is_empty()
+			|| self
+				.methods
+				.iter()
+				.any(|m| m.eq_ignore_ascii_case(method.as_ref()));
+		method_matched && glob_match(&self.path, path)
+	}
+}
+
+/// Whether any of `patterns` matches the request
+pub fn matches_any(patterns: &[RoutePattern], method: Method, path: &str) -> bool {
+	patterns.iter().any(|p| p.matches(method, path))
+}
+
+pub(crate) fn glob_match(pattern: &str, path: &str) -> bool {
+	let mut parts = pattern.split('*');
+	// split always yields at least one item
+	let first = parts.next().unwrap_or_default();
+	let Some(mut rest) = path.strip_prefix(first) else {
+		return false;
+	};
+	let parts: Vec<&str> = parts.collect();
+	let Some((last, middle)) = parts.split_last() else {
+		// no `*` in the pattern
+		return rest.is_empty();
+	};
+	for part in middle {
+		match rest.find(part) {
+			Some(idx) => rest = &rest[idx + part.len()..],
+			None => return false,
+		}
+	}
+	rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_exact_path() {
+		let p = RoutePattern::new("/api/login", &[]);
+		assert!(p.matches(Method::Post, "/api/login"));
+		assert!(!p.matches(Method::Post, "/api/login/x"));
+		assert!(!p.matches(Method::Post, "/api/log"));
+	}
+
+	#[test]
+	fn test_prefix_path() {
+		let p = RoutePattern::new("/static/*", &[]);
+		assert!(p.matches(Method::Get, "/static/"));
+		assert!(p.matches(Method::Get, "/static/js/app.js"));
+		assert!(!p.matches(Method::Get, "/static"));
+		assert!(!p.matches(Method::Get, "/api/static/x"));
+	}
+
+	#[test]
+	fn test_glob_path() {
+		let p = RoutePattern::new("/api/*/public/*.json", &[]);
+		assert!(p.matches(Method::Get, "/api/v1/public/a.json"));
+		assert!(p.matches(Method::Get, "/api/v1/x/public/a/b.json"));
+		assert!(!p.matches(Method::Get, "/api/v1/public/a.txt"));
+		assert!(RoutePattern::new("*", &[]).matches(Method::Get, "/anything"));
+	}
+
+	#[test]
+	fn test_methods() {
+		let p = RoutePattern::new("/api/token", &["post", "OPTIONS"]);
+		assert!(p.matches(Method::Post, "/api/token"));
+		assert!(p.matches(Method::Options, "/api/token"));
+		assert!(!p.matches(Method::Get, "/api/token"));
+	}
+}
@@ -0,0 +1,271 @@
+use tide::{Middleware, Next, Request, Response};
+
+use crate::config::{FrameOptions, SecurityHeadersConfig};
+use crate::proxy::ClientInfo;
+use crate::route_pattern::RoutePattern;
+
+/// The CSP nonce of the current request, for `nonce` attributes of inline scripts
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CspNonce(pub String);
+
+impl CspNonce {
+	fn generate() -> Self {
+		Self(base64_simd::STANDARD.encode_to_string(rand::random::<[u8; 16]>()))
+	}
+}
+
+/// Headers sent on the routes of one policy
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Policy {
+	/// The CSP with `{nonce}` placeholders, `None` when left out
+	csp: Option<String>,
+	csp_header: &'static str,
+	/// Everything else but HSTS
+	headers: Vec<(&'static str, String)>,
+}
+
+impl Policy {
+	fn new(
+		csp: &str,
+		report_only: bool,
+		frame_options: FrameOptions,
+		nosniff: bool,
+		referrer_policy: &str,
+		permissions_policy: &str,
+	) -> Self {
+		let (x_frame_options, frame_ancestors) = match frame_options {
+			FrameOptions::Deny => (Some("DENY"), Some("'none'")),
+			FrameOptions::SameOrigin => (Some("SAMEORIGIN"), Some("'self'")),
+			FrameOptions::Allow => (None, None),
+		};
+		let csp = csp.trim().trim_end_matches(';');
+		let csp = (!csp.is_empty()).then(|| match frame_ancestors {
+			// browsers ignore `X-Frame-Options` once the CSP has `frame-ancestors`
+			Some(ancestors) if !csp.contains("frame-ancestors") => {
+				format!("{csp}; frame-ancestors {ancestors}")
+			}
+			_ => csp.to_owned(),
+		});
+
+		let mut headers = vec![];
+		if let Some(value) = x_frame_options {
+			headers.push(("X-Frame-Options", value.to_owned()));
+		}
+		if nosniff {
+			headers.push(("X-Content-Type-Options", "nosniff".to_owned()));
+		}
+		for (name, value) in [
+			("Referrer-Policy", referrer_policy),
+			("Permissions-Policy", permissions_policy),
+		] {
+			if !value.trim().is_empty() {
+				headers.push((name, value.trim().to_owned()));
+			}
+		}
+		Self {
+			csp,
+			csp_header: if report_only {
+				"Content-Security-Policy-Report-Only"
+			} else {
+				"Content-Security-Policy"
+			},
+			headers,
+		}
+	}
+}
+
+/// Adds the security headers of [`SecurityHeadersConfig`] to every response.
+///
+/// Must run after [`crate::proxy::ForwardedMiddleware`] to see HTTPS terminated by a
+/// proxy, and before everything answering early so error responses get the headers too.
+pub struct SecurityHeadersMiddleware {
+	hsts: Option<String>,
+	global: Policy,
+	routes: Vec<(RoutePattern, Policy)>,
+}
+
+impl SecurityHeadersMiddleware {
+	pub fn new(cfg: &SecurityHeadersConfig) -> Self {
+		let hsts = (cfg.hsts_max_age_secs > 0).then(|| {
+			let mut hsts = format!("max-age={}", cfg.hsts_max_age_secs);
+			if cfg.hsts_include_subdomains {
+				hsts.push_str("; includeSubDomains");
+			}
+			if cfg.hsts_preload {
+				hsts.push_str("; preload");
+			}
+			hsts
+		});
+		let routes = cfg
+			.routes
+			.iter()
+			.map(|route| {
+				let policy = Policy::new(
+					route
+						.content_security_policy
+						.as_ref()
+						.unwrap_or(&cfg.content_security_policy),
+					route.csp_report_only.unwrap_or(cfg.csp_report_only),
+					route.frame_options.unwrap_or(cfg.frame_options),
+					cfg.content_type_nosniff,
+					route
+						.referrer_policy
+						.as_ref()
+						.unwrap_or(&cfg.referrer_policy),
+					route
+						.permissions_policy
+						.as_ref()
+						.unwrap_or(&cfg.permissions_policy),
+				);
+				(route.route.clone(), policy)
+			})
+			.collect();
+		Self {
+			hsts,
+			global: Policy::new(
+				&cfg.content_security_policy,
+				cfg.csp_report_only,
+				cfg.frame_options,
+				cfg.content_type_nosniff,
+				&cfg.referrer_policy,
+				&cfg.permissions_policy,
+			),
+			routes,
+		}
+	}
+
+	fn policy<State>(&self, req: &Request<State>) -> &Policy {
+		self.routes
+			.iter()
+			.find(|(route, _)| route.matches(req.method(), req.url().path()))
+			.map_or(&self.global, |(_, policy)| policy)
+	}
+}
+
+/// Sets a header unless the handler already did
+fn insert_default(resp: &mut Response, name: &'static str, value: &str) {
+	if resp.header(name).is_none() {
+		resp.insert_header(name, value);
+	}
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for SecurityHeadersMiddleware {
+	async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+		let policy = self.policy(&req);
+		let https = match req.ext::<ClientInfo>() {
+			Some(client) => client.scheme == "https",
+			None => req.url().scheme() == "https",
+		};
+		let csp = policy.csp.as_ref().map(|csp| {
+			if !csp.contains("{nonce}") {
+				return csp.clone();
+			}
+			let nonce = CspNonce::generate();
+			let csp = csp.replace("{nonce}", &nonce.0);
+			req.set_ext(nonce);
+			csp
+		});
+
+		let mut resp = next.run(req).await;
+		if let Some(csp) = csp {
+			insert_default(&mut resp, policy.csp_header, &csp);
+		}
+		for (name, value) in &policy.headers {
+			insert_default(&mut resp, name, value);
+		}
+		// browsers ignore HSTS on plain HTTP, where a MITM could strip it anyway
+		if let Some(hsts) = self.hsts.as_ref().filter(|_| https) {
+			insert_default(&mut resp, "Strict-Transport-Security", hsts);
+		}
+		Ok(resp)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use tide::http::{Method, Request as HttpRequest, Response as HttpResponse, Url};
+
+	use super::*;
+	use crate::cli::Env;
+	use crate::config::RouteSecurityHeaders;
+
+	fn app(cfg: SecurityHeadersConfig) -> tide::Server<()> {
+		let mut app = tide::new();
+		app.with(SecurityHeadersMiddleware::new(&cfg));
+		app.at("/page").get(|req: Request<()>| async move {
+			let nonce = req.ext::<CspNonce>().map(|nonce| nonce.0.clone());
+			Ok(nonce.unwrap_or_default())
+		});
+		app.at("/framed").get(|_| async move {
+			let mut resp = Response::new(200);
+			resp.insert_header("X-Frame-Options", "SAMEORIGIN");
+			Ok(resp)
+		});
+		app
+	}
+
+	async fn get(app: &tide::Server<()>, url: &str) -> HttpResponse {
+		let url = Url::parse(url).unwrap();
+		app.respond(HttpRequest::new(Method::Get, url))
+			.await
+			.unwrap()
+	}
+
+	#[async_std::test]
+	async fn test_default_headers() {
+		let app = app(SecurityHeadersConfig::default());
+		let mut resp = get(&app, "https://localhost/page").await;
+		let nonce = resp.body_string().await.unwrap();
+		assert_eq!(nonce.len(), 24);
+		let csp = resp["Content-Security-Policy"].as_str();
+		assert!(csp.contains(&format!("'nonce-{nonce}'")));
+		assert!(csp.ends_with("; frame-ancestors 'none'"));
+		assert_eq!(resp["X-Frame-Options"].as_str(), "DENY");
+		assert_eq!(resp["X-Content-Type-Options"].as_str(), "nosniff");
+		assert_eq!(
+			resp["Strict-Transport-Security"].as_str(),
+			"max-age=31536000; includeSubDomains"
+		);
+
+		// a nonce is never reused
+		let mut again = get(&app, "https://localhost/page").await;
+		assert_ne!(again.body_string().await.unwrap(), nonce);
+
+		let resp = get(&app, "http://localhost/page").await;
+		assert!(resp.header("Strict-Transport-Security").is_none());
+		// headers set by the handler win
+		let resp = get(&app, "https://localhost/framed").await;
+		assert_eq!(resp["X-Frame-Options"].as_str(), "SAMEORIGIN");
+	}
+
+	#[async_std::test]
+	async fn test_local_defaults() {
+		let app = app(SecurityHeadersConfig::for_env(Env::Local));
+		let resp = get(&app, "https://localhost/page").await;
+		assert!(resp.header("Strict-Transport-Security").is_none());
+		assert!(resp.header("Content-Security-Policy").is_none());
+		assert!(resp.header("Content-Security-Policy-Report-Only").is_some());
+	}
+
+	#[async_std::test]
+	async fn test_route_overrides() {
+		let app = app(SecurityHeadersConfig {
+			routes: vec![RouteSecurityHeaders {
+				route: RoutePattern::new("/page", &["GET"]),
+				content_security_policy: Some(String::new()),
+				csp_report_only: None,
+				frame_options: Some(FrameOptions::Allow),
+				referrer_policy: Some("no-referrer".to_string()),
+				permissions_policy: None,
+			}],
+			..Default::default()
+		});
+		let mut resp = get(&app, "https://localhost/page").await;
+		assert_eq!(resp.body_string().await.unwrap(), "");
+		assert!(resp.header("Content-Security-Policy").is_none());
+		assert!(resp.header("X-Frame-Options").is_none());
+		assert_eq!(resp["Referrer-Policy"].as_str(), "no-referrer");
+		assert!(resp.header("Permissions-Policy").is_some());
+	}
+}
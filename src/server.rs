@@ -1,40 +1,208 @@
-use std::time::Instant;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
 
 use anyhow_ext::{Context, Result, anyhow};
-use tide::http::Method;
+use futures_lite::future;
+use tide::listener::Listener;
 use tide::{Middleware, Next, Request};
 use tide::{Response, StatusCode};
-use tracing::{Instrument, debug, error, info, info_span};
+use tracing::{Instrument, debug, error, info, info_span, warn};
 
-use crate::{auth, config, logger, utils};
+use crate::cli::Env;
+use crate::config::NotifierKind;
+use crate::error::AppError;
+use crate::rbac::RequirePermission;
+use crate::{
+	admin, auth, body_limit, config, cors, database, health, jwt, listener, logger, oidc, password,
+	proxy, rate_limit, security_headers, shutdown, telemetry, timeout, upload, users, utils,
+};
 
 pub async fn init_http_server_blocking() -> Result<()> {
-	// 从配置中读取绑定地址
-	let bind_addr = config::cfg().await.bind.clone();
+	// 从配置中读取监听地址
+	let listeners = config::cfg().await.listeners.clone();
+	let session_cfg = config::cfg().await.session.clone();
+	let public_routes = config::cfg().await.auth.public_routes.clone();
+	let oidc_enabled = config::cfg().await.oidc.enabled;
+	let cors_cfg = config::cfg().await.cors.clone();
+	let drain_timeout_secs = config::cfg().await.drain_timeout_secs;
+	let pre_drain_delay = Duration::from_secs(config::cfg().await.pre_drain_delay_secs);
+	let tls_cfg = config::cfg().await.tls.clone();
+	let admin_cfg = config::cfg().await.admin.clone();
+	let rate_limit_cfg = config::cfg().await.rate_limit.clone();
+	let body_limit_cfg = config::cfg().await.body_limit.clone();
+	let timeout_cfg = config::cfg().await.timeout.clone();
+	let proxy_cfg = config::cfg().await.proxy.clone();
+	let security_headers_cfg = config::cfg().await.security_headers.clone();
+	let env = config::cfg().await.env;
+	let notifier = config::cfg().await.auth.password_reset.notifier;
 
+	health::record_start();
 	let mut app = tide::new();
 	app.with(ErrorHandleMiddleware {});
-	app.with(CorsMiddleware {});
-	app.with(AuthMiddleware {});
+	app.with(proxy::ForwardedMiddleware::new(&proxy_cfg).dot()?);
+	// outside of everything answering early, so 401s, 429s and 413s are logged too
 	app.with(AccessLogMiddleware {});
+	if security_headers_cfg.enabled {
+		app.with(security_headers::SecurityHeadersMiddleware::new(
+			&security_headers_cfg,
+		));
+	}
+	app.with(telemetry::MetricsMiddleware);
+	app.with(shutdown::InFlightMiddleware);
+	app.with(listener::ListenerRoutesMiddleware);
+	mount_cors_and_limits(&mut app, &cors_cfg, &timeout_cfg, &body_limit_cfg).dot()?;
+	app.with(auth::session_middleware(&session_cfg).dot()?);
+	if rate_limit_cfg.enabled {
+		app.with(rate_limit::RateLimitMiddleware::before_auth(
+			&rate_limit_cfg,
+		));
+	}
+	app.with(auth::AuthMiddleware::new(public_routes));
+	if rate_limit_cfg.enabled {
+		app.with(rate_limit::RateLimitMiddleware::new(&rate_limit_cfg));
+	}
 
-	app.at("/")
+	// every path goes through `routes.at` so metrics can label requests by route template
+	let mut routes = Routes::new(&mut app);
+	routes
+		.at("/")
 		.get(|_| async move { Ok("this is a inline handler") });
-	app.at("/user/:name").get(nested_span_handler);
-
-	// Log level management routes
-	app.at("/api/log/:directive")
-		.post(async |req: Request<()>| {
-			let directive = req
-				.param("directive")
-				.map_err(|_e| anyhow!("directive is required"))
-				.dot()?;
-			logger::update_global_log_level(directive).dot()?;
-			Ok(make_resp(200, ""))
+	routes.at("/user/:name").get(nested_span_handler);
+
+	// Cookie session login for the browser frontend
+	routes.at("/api/login").post(auth::login);
+	routes.at("/api/logout").post(auth::logout);
+	// Bearer tokens for service-to-service callers
+	routes.at("/api/token").post(jwt::issue_token);
+	// Single sign-on through the company identity provider
+	if oidc_enabled {
+		routes.at("/api/oidc/login").get(oidc::login);
+		routes.at("/api/oidc/callback").get(oidc::callback);
+	}
+
+	// User management
+	routes
+		.at("/api/users")
+		.with(RequirePermission::new("user:read"))
+		.get(users::list);
+	routes
+		.at("/api/users")
+		.with(RequirePermission::new("user:write"))
+		.post(users::create);
+	routes
+		.at("/api/users/:id")
+		.with(RequirePermission::new("user:read"))
+		.get(users::get);
+	routes
+		.at("/api/users/:id")
+		.with(RequirePermission::new("user:write"))
+		.patch(users::update)
+		.delete(users::delete);
+	routes
+		.at("/api/users/me/password")
+		.post(password::change_own);
+	routes
+		.at("/api/users/:id/password")
+		.with(RequirePermission::new("user:write"))
+		.post(password::admin_reset);
+	if env == Env::Prd && notifier == NotifierKind::Log {
+		warn!("The log notifier doesn't send in prd, password reset tokens won't arrive");
+	}
+	routes
+		.at("/api/password-reset/request")
+		.post(password::request_reset);
+	routes
+		.at("/api/password-reset/confirm")
+		.post(password::confirm_reset);
+
+	// File uploads, the body limit of the route bounds the whole request
+	routes
+		.at("/api/uploads")
+		.with(RequirePermission::new("upload:write"))
+		.post(upload::upload);
+
+	telemetry::set_route_templates(&routes.templates);
+
+	// health, metrics, log level and the like are only served by the admin app
+	let admin_listeners: &[config::ListenerConfig] = if admin_cfg.enabled {
+		&admin_cfg.listeners
+	} else {
+		warn!("The admin app is disabled, /healthz and /readyz aren't served anywhere");
+		&[]
+	};
+	let tls = listener::tls_terminator(listeners.iter().chain(admin_listeners), &tls_cfg).dot()?;
+	let mut listener = listener::build_listeners(&listeners, &tls_cfg, tls.as_ref()).dot()?;
+	listener.bind(app).await.dot()?;
+	for info in listener.info() {
+		info!("server listening on {info}");
+	}
+	let mut admin_listener =
+		listener::build_listeners(admin_listeners, &tls_cfg, tls.as_ref()).dot()?;
+	if admin_cfg.enabled {
+		admin_listener
+			.bind(admin::admin_app(&admin_cfg))
+			.await
+			.dot()?;
+		for info in admin_listener.info() {
+			info!("admin listening on {info}");
+		}
+	}
+	let signal = {
+		let serving = future::or(
+			async {
+				listener.accept().await.dot()?;
+				Err(anyhow!("server stopped accepting connections"))
+			},
+			async {
+				if !admin_cfg.enabled {
+					return future::pending().await;
+				}
+				admin_listener.accept().await.dot()?;
+				Err(anyhow!("admin stopped accepting connections"))
+			},
+		);
+		futures_lite::pin!(serving);
+		let signal = future::or(serving.as_mut(), shutdown::wait_for_signal())
+			.await
+			.dot()?;
+		// `/readyz` fails from now on, keep serving until load balancers have noticed
+		info!(
+			?signal,
+			"shutdown signal received, still accepting for {pre_drain_delay:?}"
+		);
+		future::or(serving.as_mut(), async {
+			async_std::task::sleep(pre_drain_delay).await;
+			Ok(signal)
 		})
-		.get(async |_req| Ok(make_resp(200, logger::get_global_log_level().dot()?)));
+		.await
+		.dot()?
+	};
+	// dropping the accept futures stops accepting, connections already accepted keep
+	// being served by their own tasks
+	drop(listener);
+	drop(admin_listener);
 
-	app.listen(bind_addr).await?;
+	shutdown::graceful_shutdown(signal, Duration::from_secs(drain_timeout_secs)).await;
+	if let Err(err) = database::close_database().await {
+		error!(?err);
+	}
+	info!("server stopped");
+	logger::flush();
+	Ok(())
+}
+
+/// CORS goes outside of the timeout and the body limit, so the 503/504s and 413s they
+/// answer early are readable cross-origin too. Preflights carry no body and are
+/// answered right away.
+fn mount_cors_and_limits(
+	app: &mut tide::Server<()>,
+	cors_cfg: &config::CorsConfig,
+	timeout_cfg: &config::TimeoutConfig,
+	body_limit_cfg: &config::BodyLimitConfig,
+) -> Result<()> {
+	app.with(cors::CorsMiddleware::new(cors_cfg).dot()?);
+	app.with(timeout::TimeoutMiddleware::new(timeout_cfg));
+	app.with(body_limit::BodyLimitMiddleware::new(body_limit_cfg));
 	Ok(())
 }
 
@@ -78,17 +246,25 @@ async fn nested_span_handler(_req: Request<()>) -> tide::Result<Response> {
 	.await
 }
 
-struct AuthMiddleware;
-#[tide::utils::async_trait]
-impl<State: Clone + Send + Sync + 'static> Middleware<State> for AuthMiddleware {
-	async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
-		debug!("request counter");
-		// req.set_ext(RequestCount(count));
+/// Registers routes on the app and remembers their paths as route templates
+struct Routes<'a> {
+	app: &'a mut tide::Server<()>,
+	templates: Vec<&'static str>,
+}
 
-		let res = next.run(req).await;
+impl<'a> Routes<'a> {
+	fn new(app: &'a mut tide::Server<()>) -> Self {
+		Self {
+			app,
+			templates: vec![],
+		}
+	}
 
-		// res.insert_header("request-number", count.to_string());
-		Ok(res)
+	fn at(&mut self, path: &'static str) -> tide::Route<'_, ()> {
+		if !self.templates.contains(&path) {
+			self.templates.push(path);
+		}
+		self.app.at(path)
 	}
 }
 
@@ -102,39 +278,52 @@ where
 	return resp;
 }
 
-struct ErrorHandleMiddleware;
+/// Renders every error response as JSON, or as `application/problem+json` when the
+/// client asks for it. Must be the outermost middleware, it also assigns the request id.
+pub(crate) struct ErrorHandleMiddleware;
 #[tide::utils::async_trait]
 impl<State: Clone + Send + Sync + 'static> Middleware<State> for ErrorHandleMiddleware {
 	async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+		utils::set_req_id();
+		let problem_json = req
+			.header("Accept")
+			.is_some_and(|accept| accept.as_str().contains("application/problem+json"));
 		let mut resp = next.run(req).await;
-		if let Some(err) = resp.error() {
-			error!(?err);
-			resp.set_body(format!("{err:?}"));
+		let status = resp.status();
+		if let Some(err) = resp.take_error() {
+			let app_err = match err.downcast::<AppError>() {
+				Ok(app_err) => app_err,
+				Err(err) if status.is_server_error() => {
+					// the debug chain can hold paths and backtraces, it stays in the log
+					error!(?err);
+					AppError::from_status(status, "internal server error")
+				}
+				Err(err) => {
+					debug!(?err);
+					AppError::from_status(status, err.to_string())
+				}
+			};
+			app_err.render(&mut resp, problem_json);
+		} else if (status.is_client_error() || status.is_server_error())
+			&& resp.is_empty() == Some(true)
+		{
+			// e.g. the router's 404s
+			let message = status.canonical_reason().to_lowercase();
+			AppError::from_status(status, message).render(&mut resp, problem_json);
 		}
+		resp.insert_header("X-Request-Id", utils::get_req_id());
 		Ok(resp)
 	}
 }
 
-struct CorsMiddleware;
-#[tide::utils::async_trait]
-impl<State: Clone + Send + Sync + 'static> Middleware<State> for CorsMiddleware {
-	async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
-		let mut resp = match req.method() {
-			Method::Options => make_resp(200, ""),
-			_ => next.run(req).await,
-		};
-		resp.insert_header("Access-Control-Allow-Origin", "http://localhost:5173");
-		resp.insert_header(
-			"Access-Control-Allow-Headers",
-			"Origin, X-Requested-With, Content-Type, Accept",
-		);
-		resp.insert_header(
-			"Access-Control-Allow-Methods",
-			"GET, POST, PUT, DELETE, OPTIONS",
-		);
-		resp.insert_header("Access-Control-Max-Age", "7200 "); // reduce OPTIONS requests. 7200 is Chrome maximum number
-		resp.insert_header("Access-Control-Allow-Credentials", "true"); // reduce OPTIONS requests
-		return Ok(resp);
+/// The user shown in the access log. [`AccessLogMiddleware`] puts an empty one into the
+/// request, [`auth::AuthMiddleware`] running inside of it fills it in.
+#[derive(Debug, Clone, Default)]
+pub struct LoggedUser(Arc<OnceLock<String>>);
+
+impl LoggedUser {
+	pub fn set(&self, user: String) {
+		let _ = self.0.set(user);
 	}
 }
 
@@ -143,14 +332,16 @@ pub struct AccessLogMiddleware;
 impl AccessLogMiddleware {}
 #[tide::utils::async_trait]
 impl<State: Clone + Send + Sync + 'static> tide::Middleware<State> for AccessLogMiddleware {
-	async fn handle(&self, req: tide::Request<State>, next: tide::Next<'_, State>) -> tide::Result {
+	async fn handle(
+		&self,
+		mut req: tide::Request<State>,
+		next: tide::Next<'_, State>,
+	) -> tide::Result {
 		let path = req.url().path().to_owned();
 		let method = req.method();
-		let ip = req.peer_addr().unwrap_or("-").to_string();
-		let username = auth::read_cred_from_basic_auth(&req)
-			.map(|cred| cred.username)
-			.unwrap_or("-".to_owned());
-		utils::set_req_id();
+		let ip = proxy::client_ip(&req).unwrap_or("-".to_owned());
+		let user = LoggedUser::default();
+		req.set_ext(user.clone());
 		let agent = req.header("user-agent").map(|a| a.as_str()).unwrap_or("-");
 		let agent = agent
 			.split_once(' ')
@@ -169,6 +360,7 @@ impl<State: Clone + Send + Sync + 'static> tide::Middleware<State> for AccessLog
 		};
 		let duration = start.elapsed();
 		let status = response.status();
+		let username = user.0.get().map_or("-", String::as_str);
 
 		let access_log_msg =
 			format!("{ip}|{agent}|{username}|{method}|{status}|{duration:?}|{size}B|{path}",);
@@ -177,3 +369,130 @@ impl<State: Clone + Send + Sync + 'static> tide::Middleware<State> for AccessLog
 		return Ok(response);
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use tide::http::{Method, Request as HttpRequest, Response as HttpResponse, Url};
+
+	use super::*;
+	use crate::route_pattern::RoutePattern;
+
+	fn app() -> tide::Server<()> {
+		let mut app = tide::new();
+		app.with(ErrorHandleMiddleware {});
+		app.at("/internal").get(|_| async move {
+			Err::<Response, _>(anyhow!("secret path /etc/app/db.sqlite").into())
+		});
+		app.at("/app-error").get(|_| async move {
+			Err::<Response, _>(tide::Error::from(AppError::conflict(
+				"username_taken",
+				"taken",
+			)))
+		});
+		app
+	}
+
+	async fn get(path: &str, accept: Option<&str>) -> (StatusCode, serde_json::Value) {
+		let url = Url::parse(&format!("http://localhost{path}")).unwrap();
+		let mut req = HttpRequest::new(Method::Get, url);
+		if let Some(accept) = accept {
+			req.insert_header("Accept", accept);
+		}
+		let mut resp: HttpResponse = app().respond(req).await.unwrap();
+		assert!(resp.header("X-Request-Id").is_some());
+		(resp.status(), resp.body_json().await.unwrap())
+	}
+
+	#[async_std::test]
+	async fn test_internal_error_is_not_leaked() {
+		let (status, body) = get("/internal", None).await;
+		assert_eq!(status, StatusCode::InternalServerError);
+		assert_eq!(body["code"], "internal_error");
+		assert_eq!(body["message"], "internal server error");
+		assert!(!body.to_string().contains("secret"));
+	}
+
+	#[async_std::test]
+	async fn test_app_error_keeps_its_status() {
+		let (status, body) = get("/app-error", None).await;
+		assert_eq!(status, StatusCode::Conflict);
+		assert_eq!(body["code"], "username_taken");
+	}
+
+	#[async_std::test]
+	async fn test_problem_json_and_router_404() {
+		let (status, body) = get("/missing", Some("application/problem+json")).await;
+		assert_eq!(status, StatusCode::NotFound);
+		assert_eq!(body["status"], 404);
+		assert_eq!(body["code"], "not_found");
+	}
+
+	async fn post_cross_origin(
+		app: &tide::Server<()>,
+		path: &str,
+		body: tide::Body,
+	) -> HttpResponse {
+		let url = Url::parse(&format!("http://localhost{path}")).unwrap();
+		let mut req = HttpRequest::new(Method::Post, url);
+		req.insert_header("Origin", "https://app.example.com");
+		req.set_body(body);
+		app.respond(req).await.unwrap()
+	}
+
+	#[async_std::test]
+	async fn test_early_errors_carry_cors_headers() {
+		let mut app = tide::new();
+		app.with(ErrorHandleMiddleware {});
+		mount_cors_and_limits(
+			&mut app,
+			&config::CorsConfig {
+				allowed_origins: vec!["https://app.example.com".to_string()],
+				..Default::default()
+			},
+			// only `/slow` times out, the others may take long on a busy test machine
+			&config::TimeoutConfig {
+				timeout_ms: 0,
+				routes: vec![config::RouteTimeout {
+					route: RoutePattern::new("/slow", &[]),
+					timeout_ms: 50,
+					slow_ms: None,
+				}],
+				..Default::default()
+			},
+			&config::BodyLimitConfig {
+				max_bytes: 10,
+				routes: vec![],
+			},
+		)
+		.unwrap();
+		app.at("/echo")
+			.post(|mut req: Request<()>| async move { req.body_string().await });
+		app.at("/slow").post(|_| async move {
+			async_std::task::sleep(Duration::from_secs(5)).await;
+			Ok("")
+		});
+
+		// refused by its `Content-Length`, and cut off while the handler reads it
+		let chunked = futures_lite::io::Cursor::new(vec![b'x'; 100]);
+		for body in [
+			tide::Body::from("x".repeat(100)),
+			tide::Body::from_reader(chunked, None),
+		] {
+			let mut resp = post_cross_origin(&app, "/echo", body).await;
+			assert_eq!(resp.status(), StatusCode::PayloadTooLarge);
+			assert_eq!(
+				resp["Access-Control-Allow-Origin"].as_str(),
+				"https://app.example.com"
+			);
+			let body: serde_json::Value = resp.body_json().await.unwrap();
+			assert_eq!(body["code"], "payload_too_large");
+		}
+
+		let resp = post_cross_origin(&app, "/slow", tide::Body::empty()).await;
+		assert_eq!(resp.status(), StatusCode::GatewayTimeout);
+		assert_eq!(
+			resp["Access-Control-Allow-Origin"].as_str(),
+			"https://app.example.com"
+		);
+	}
+}
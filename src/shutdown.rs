@@ -0,0 +1,116 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use anyhow_ext::{Context, Result};
+use async_signal::{Signal, Signals};
+use futures_lite::StreamExt;
+use tide::{Middleware, Next, Request};
+use tracing::{info, warn};
+
+/// Set when a shutdown signal arrived, `/readyz` reports not-ready from then on
+static DRAINING: AtomicBool = AtomicBool::new(false);
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+pub fn is_draining() -> bool {
+	DRAINING.load(Ordering::Acquire)
+}
+
+pub fn in_flight() -> usize {
+	IN_FLIGHT.load(Ordering::Acquire)
+}
+
+/// Wait for SIGTERM or SIGINT and start draining
+pub async fn wait_for_signal() -> Result<Signal> {
+	let mut signals =
+		Signals::new([Signal::Term, Signal::Int]).context("failed to install signal handlers")?;
+	let signal = signals
+		.next()
+		.await
+		.context("signal stream ended")?
+		.context("failed to receive signal")?;
+	DRAINING.store(true, Ordering::Release);
+	Ok(signal)
+}
+
+/// Wait until no request is in flight, at most `timeout`. Returns whether every
+/// request finished.
+pub async fn drain(timeout: Duration) -> bool {
+	let start = Instant::now();
+	loop {
+		let in_flight = in_flight();
+		if in_flight == 0 {
+			return true;
+		}
+		if start.elapsed() >= timeout {
+			warn!(
+				in_flight,
+				"drain timeout reached, abandoning in-flight requests"
+			);
+			return false;
+		}
+		async_std::task::sleep(DRAIN_POLL_INTERVAL).await;
+	}
+}
+
+struct InFlightGuard;
+
+impl InFlightGuard {
+	fn new() -> Self {
+		IN_FLIGHT.fetch_add(1, Ordering::AcqRel);
+		Self
+	}
+}
+
+impl Drop for InFlightGuard {
+	fn drop(&mut self) {
+		IN_FLIGHT.fetch_sub(1, Ordering::AcqRel);
+	}
+}
+
+/// Counts in-flight requests for [`drain`]. While draining it asks clients to close
+/// their keep-alive connections, so they reconnect to another instance.
+#[derive(Debug, Default, Clone)]
+pub struct InFlightMiddleware;
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for InFlightMiddleware {
+	async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+		let _guard = InFlightGuard::new();
+		let mut resp = next.run(req).await;
+		if is_draining() {
+			resp.insert_header("Connection", "close");
+		}
+		Ok(resp)
+	}
+}
+
+/// Wait for in-flight requests once the listeners are closed
+pub async fn graceful_shutdown(signal: Signal, timeout: Duration) {
+	info!(
+		?signal,
+		in_flight = in_flight(),
+		"stopped accepting connections, draining for up to {timeout:?}"
+	);
+	drain(timeout).await;
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[async_std::test]
+	async fn test_drain_waits_for_in_flight_requests() {
+		let guard = InFlightGuard::new();
+		let handle = async_std::task::spawn(async move {
+			async_std::task::sleep(Duration::from_millis(100)).await;
+			drop(guard);
+		});
+		assert!(drain(Duration::from_secs(5)).await);
+		handle.await;
+
+		let _guard = InFlightGuard::new();
+		assert!(!drain(Duration::from_millis(100)).await);
+	}
+}
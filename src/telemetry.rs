@@ -0,0 +1,273 @@
+use std::sync::OnceLock;
+use std::time::Instant;
+
+use anyhow_ext::{Context, Result};
+use metrics::{counter, gauge, histogram};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use route_recognizer::Router;
+use tide::{Middleware, Next, Request, Response};
+
+use crate::{database, utils};
+
+static PROMETHEUS: OnceLock<PrometheusHandle> = OnceLock::new();
+static ROUTE_TEMPLATES: OnceLock<Router<&'static str>> = OnceLock::new();
+static STARTED_AT_UNIX: OnceLock<i64> = OnceLock::new();
+
+/// Label for requests that match none of the registered routes, the raw path would
+/// give every scanner probe its own series
+const UNMATCHED_ROUTE: &str = "unmatched";
+
+const DURATION_BUCKETS: &[f64] = &[
+	0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+const SIZE_BUCKETS: &[f64] = &[
+	100.0,
+	1_000.0,
+	10_000.0,
+	100_000.0,
+	1_000_000.0,
+	10_000_000.0,
+];
+
+/// Install the global recorder, metrics recorded before this are dropped
+pub fn init_metrics() -> Result<()> {
+	STARTED_AT_UNIX.get_or_init(utils::unix_now);
+	if PROMETHEUS.get().is_some() {
+		return Ok(());
+	}
+	let handle = PrometheusBuilder::new()
+		.set_buckets_for_metric(
+			Matcher::Full("http_request_duration_seconds".to_string()),
+			DURATION_BUCKETS,
+		)
+		.dot()?
+		.set_buckets_for_metric(
+			Matcher::Full("http_response_size_bytes".to_string()),
+			SIZE_BUCKETS,
+		)
+		.dot()?
+		.install_recorder()
+		.context("failed to install the metrics recorder")?;
+	let _ = PROMETHEUS.set(handle);
+	Ok(())
+}
+
+/// Route templates like `/api/users/:id` used as the `route` label, set once all
+/// routes are registered
+pub fn set_route_templates(templates: &[&'static str]) {
+	let mut router = Router::new();
+	for template in templates {
+		router.add(template, *template);
+	}
+	let _ = ROUTE_TEMPLATES.set(router);
+}
+
+/// The registered route template matching `path`, [`UNMATCHED_ROUTE`] if there is none
+pub(crate) fn route_template(path: &str) -> &'static str {
+	ROUTE_TEMPLATES
+		.get()
+		.and_then(|router| router.recognize(path).ok())
+		.map(|m| *m.handler)
+		.unwrap_or(UNMATCHED_ROUTE)
+}
+
+fn status_class(status: tide::StatusCode) -> &'static str {
+	match status as u16 {
+		100..=199 => "1xx",
+		200..=299 => "2xx",
+		300..=399 => "3xx",
+		400..=499 => "4xx",
+		_ => "5xx",
+	}
+}
+
+/// `GET /metrics`, every metric in the Prometheus text exposition format
+pub async fn render<State: Clone + Send + Sync + 'static>(_req: Request<State>) -> tide::Result {
+	let Some(handle) = PROMETHEUS.get() else {
+		return Ok(Response::new(404));
+	};
+	record_db_pool();
+	record_process();
+	handle.run_upkeep();
+	let mut body = handle.render();
+	// counters of the recorder are integers, CPU time needs fractions of a second
+	if let Some(cpu_secs) = process_cpu_secs() {
+		body.push_str(&format!(
+			"# TYPE process_cpu_seconds_total counter\nprocess_cpu_seconds_total {cpu_secs}\n"
+		));
+	}
+	let mut resp = Response::new(200);
+	resp.set_body(body);
+	resp.set_content_type("text/plain; version=0.0.4; charset=utf-8");
+	Ok(resp)
+}
+
+fn record_db_pool() {
+	let Ok(db) = database::get_db_conn() else {
+		return;
+	};
+	let pool = db.get_sqlite_connection_pool();
+	let idle = pool.num_idle() as f64;
+	gauge!("db_pool_connections", "state" => "idle").set(idle);
+	gauge!("db_pool_connections", "state" => "in_use").set(pool.size() as f64 - idle);
+}
+
+#[cfg(target_os = "linux")]
+fn record_process() {
+	if let Some(started_at) = STARTED_AT_UNIX.get() {
+		gauge!("process_start_time_seconds").set(*started_at as f64);
+	}
+	if let Ok(status) = std::fs::read_to_string("/proc/self/status") {
+		for line in status.lines() {
+			let Some((key, value)) = line.split_once(':') else {
+				continue;
+			};
+			let value = value.trim().trim_end_matches(" kB");
+			match (key, value.parse::<f64>()) {
+				("VmRSS", Ok(kb)) => gauge!("process_resident_memory_bytes").set(kb * 1024.0),
+				("VmSize", Ok(kb)) => gauge!("process_virtual_memory_bytes").set(kb * 1024.0),
+				("Threads", Ok(threads)) => gauge!("process_threads").set(threads),
+				_ => {}
+			}
+		}
+	}
+	if let Ok(fds) = std::fs::read_dir("/proc/self/fd") {
+		gauge!("process_open_fds").set(fds.count() as f64);
+	}
+}
+
+/// User and system CPU time of the process in seconds
+#[cfg(target_os = "linux")]
+fn process_cpu_secs() -> Option<f64> {
+	let stat = std::fs::read_to_string("/proc/self/stat").ok()?;
+	// the fields after the parenthesized command name, utime and stime are the 12th
+	// and 13th of them, in clock ticks of 1/100 s on every common kernel
+	let (_, rest) = stat.rsplit_once(')')?;
+	let mut fields = rest.split_whitespace().skip(11);
+	let utime = fields.next()?.parse::<f64>().ok()?;
+	let stime = fields.next()?.parse::<f64>().ok()?;
+	Some((utime + stime) / 100.0)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn process_cpu_secs() -> Option<f64> {
+	None
+}
+
+#[cfg(not(target_os = "linux"))]
+fn record_process() {
+	if let Some(started_at) = STARTED_AT_UNIX.get() {
+		gauge!("process_start_time_seconds").set(*started_at as f64);
+	}
+}
+
+/// Holds one request in `http_requests_in_flight`, also when the request future is
+/// dropped by a timeout or a client that hung up
+struct InFlight(metrics::Gauge);
+
+impl InFlight {
+	fn start() -> Self {
+		let gauge = gauge!("http_requests_in_flight");
+		gauge.increment(1.0);
+		Self(gauge)
+	}
+}
+
+impl Drop for InFlight {
+	fn drop(&mut self) {
+		self.0.decrement(1.0);
+	}
+}
+
+/// Counts requests and records their latency and response size, labelled by method,
+/// route template and status class. Registered right inside
+/// `ErrorHandleMiddleware` so requests rejected by other middleware are counted too.
+#[derive(Debug, Default, Clone)]
+pub struct MetricsMiddleware;
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for MetricsMiddleware {
+	async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+		let method = req.method().to_string();
+		let route = route_template(req.url().path());
+
+		let in_flight = InFlight::start();
+		let start = Instant::now();
+		let resp = next.run(req).await;
+		let duration = start.elapsed();
+		drop(in_flight);
+
+		let labels = [
+			("method", method),
+			("route", route.to_string()),
+			("status", status_class(resp.status()).to_string()),
+		];
+		counter!("http_requests_total", &labels).increment(1);
+		histogram!("http_request_duration_seconds", &labels).record(duration.as_secs_f64());
+		if let Some(size) = resp.len() {
+			histogram!("http_response_size_bytes", &labels).record(size as f64);
+		}
+		Ok(resp)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use async_std::future::{self, timeout};
+	use tide::http::{Method, Request as HttpRequest, Response as HttpResponse, Url};
+
+	use super::*;
+
+	#[test]
+	fn test_status_class() {
+		assert_eq!(status_class(tide::StatusCode::Ok), "2xx");
+		assert_eq!(status_class(tide::StatusCode::NoContent), "2xx");
+		assert_eq!(status_class(tide::StatusCode::NotFound), "4xx");
+		assert_eq!(status_class(tide::StatusCode::ServiceUnavailable), "5xx");
+	}
+
+	#[async_std::test]
+	async fn test_requests_are_labelled_by_route_template() {
+		init_metrics().unwrap();
+		set_route_templates(&["/metrics", "/api/users/:id"]);
+
+		let mut app = tide::new();
+		app.with(MetricsMiddleware);
+		app.at("/metrics").get(render);
+		app.at("/api/users/:id").get(|_| async move { Ok("user") });
+		app.at("/slow")
+			.get(|_| async move { future::pending::<tide::Result>().await });
+		for path in ["/api/users/1", "/api/users/2", "/wp-login.php", "/metrics"] {
+			let url = Url::parse(&format!("http://localhost{path}")).unwrap();
+			let _: HttpResponse = app
+				.respond(HttpRequest::new(Method::Get, url))
+				.await
+				.unwrap();
+		}
+
+		let url = Url::parse("http://localhost/metrics").unwrap();
+		let mut resp: HttpResponse = app
+			.respond(HttpRequest::new(Method::Get, url))
+			.await
+			.unwrap();
+		let body = resp.body_string().await.unwrap();
+		assert!(body.contains(
+			r#"http_requests_total{method="GET",route="/api/users/:id",status="2xx"} 2"#
+		));
+		assert!(body.contains(r#"route="unmatched",status="4xx""#));
+		assert!(!body.contains("/api/users/1"));
+		assert!(body.contains("http_request_duration_seconds_bucket"));
+		assert!(body.contains("http_requests_in_flight"));
+		#[cfg(target_os = "linux")]
+		assert!(body.contains("# TYPE process_cpu_seconds_total counter"));
+
+		// a request dropped before it's answered leaves the in-flight gauge
+		let url = Url::parse("http://localhost/slow").unwrap();
+		let slow = app.respond::<_, HttpResponse>(HttpRequest::new(Method::Get, url));
+		assert!(timeout(Duration::from_millis(50), slow).await.is_err());
+		let body = PROMETHEUS.get().unwrap().render();
+		assert!(body.contains("http_requests_in_flight 0\n"));
+	}
+}
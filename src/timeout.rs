@@ -0,0 +1,188 @@
+use std::time::{Duration, Instant};
+
+use metrics::counter;
+use tide::{Middleware, Next, Request, StatusCode};
+use tracing::warn;
+
+use crate::config::TimeoutConfig;
+use crate::error::AppError;
+use crate::route_pattern::RoutePattern;
+use crate::telemetry;
+
+/// Timeout and slow threshold of a request, `None` when disabled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Limits {
+	timeout: Option<Duration>,
+	slow: Option<Duration>,
+}
+
+fn limits(timeout_ms: u64, slow_ms: u64) -> Limits {
+	let duration = |ms: u64| (ms > 0).then(|| Duration::from_millis(ms));
+	Limits {
+		timeout: duration(timeout_ms),
+		slow: duration(slow_ms),
+	}
+}
+
+/// Aborts requests running longer than their timeout and logs slow ones, see
+/// [`TimeoutConfig`].
+///
+/// The handler future is dropped on timeout, which cancels whatever it was awaiting,
+/// e.g. a database query. Tasks it spawned keep running. The warnings carry the
+/// request id through the log format, so it must run inside `ErrorHandleMiddleware`.
+pub struct TimeoutMiddleware {
+	global: Limits,
+	status: StatusCode,
+	routes: Vec<(RoutePattern, Limits)>,
+}
+
+impl TimeoutMiddleware {
+	pub fn new(cfg: &TimeoutConfig) -> Self {
+		Self {
+			global: limits(cfg.timeout_ms, cfg.slow_ms),
+			status: if cfg.status == 503 {
+				StatusCode::ServiceUnavailable
+			} else {
+				StatusCode::GatewayTimeout
+			},
+			routes: cfg
+				.routes
+				.iter()
+				.map(|route| {
+					let slow_ms = route.slow_ms.unwrap_or(cfg.slow_ms);
+					(route.route.clone(), limits(route.timeout_ms, slow_ms))
+				})
+				.collect(),
+		}
+	}
+
+	fn limits<State>(&self, req: &Request<State>) -> Limits {
+		self.routes
+			.iter()
+			.find(|(route, _)| route.matches(req.method(), req.url().path()))
+			.map_or(self.global, |(_, limits)| *limits)
+	}
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for TimeoutMiddleware {
+	async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+		let limits = self.limits(&req);
+		let method = req.method();
+		let route = telemetry::route_template(req.url().path());
+		let start = Instant::now();
+
+		let resp = match limits.timeout {
+			Some(timeout) => match async_std::future::timeout(timeout, next.run(req)).await {
+				Ok(resp) => resp,
+				Err(_) => {
+					counter!("http_request_timeouts_total", "route" => route).increment(1);
+					warn!(%method, route, timeout_ms = timeout.as_millis(), "request timed out");
+					return Ok(AppError::new(
+						self.status,
+						"request_timeout",
+						format!("request did not finish within {} ms", timeout.as_millis()),
+					)
+					.into());
+				}
+			},
+			None => next.run(req).await,
+		};
+
+		let elapsed = start.elapsed();
+		if let Some(slow) = limits.slow
+			&& elapsed >= slow
+		{
+			counter!("http_slow_requests_total", "route" => route).increment(1);
+			warn!(
+				%method,
+				route,
+				status = resp.status() as u16,
+				elapsed_ms = elapsed.as_millis(),
+				"slow request"
+			);
+		}
+		Ok(resp)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use tide::http::{Method, Request as HttpRequest, Response as HttpResponse, Url};
+
+	use super::*;
+	use crate::config::RouteTimeout;
+
+	fn app(cfg: TimeoutConfig) -> tide::Server<()> {
+		let mut app = tide::new();
+		app.with(TimeoutMiddleware::new(&cfg));
+		for path in ["/stuck", "/export"] {
+			app.at(path).get(|_| async move {
+				async_std::task::sleep(Duration::from_millis(200)).await;
+				Ok("done")
+			});
+		}
+		app.at("/fast").get(|_| async move { Ok("fast") });
+		app
+	}
+
+	async fn get(app: &tide::Server<()>, path: &str) -> HttpResponse {
+		let url = Url::parse(&format!("http://localhost{path}")).unwrap();
+		app.respond(HttpRequest::new(Method::Get, url))
+			.await
+			.unwrap()
+	}
+
+	fn cfg() -> TimeoutConfig {
+		TimeoutConfig {
+			timeout_ms: 50,
+			slow_ms: 10,
+			status: 504,
+			routes: vec![RouteTimeout {
+				route: RoutePattern::new("/export", &["GET"]),
+				timeout_ms: 0,
+				slow_ms: None,
+			}],
+		}
+	}
+
+	#[async_std::test]
+	async fn test_stuck_request_times_out() {
+		let app = app(cfg());
+		let start = Instant::now();
+		let resp = get(&app, "/stuck").await;
+		assert_eq!(resp.status(), StatusCode::GatewayTimeout);
+		assert!(start.elapsed() < Duration::from_millis(200));
+
+		assert_eq!(get(&app, "/fast").await.status(), StatusCode::Ok);
+	}
+
+	#[async_std::test]
+	async fn test_route_override_and_status() {
+		let gateway = app(cfg());
+		assert_eq!(get(&gateway, "/export").await.status(), StatusCode::Ok);
+
+		let unavailable = app(TimeoutConfig {
+			status: 503,
+			..cfg()
+		});
+		let resp = get(&unavailable, "/stuck").await;
+		assert_eq!(resp.status(), StatusCode::ServiceUnavailable);
+	}
+
+	#[test]
+	fn test_zero_disables() {
+		let mw = TimeoutMiddleware::new(&TimeoutConfig {
+			timeout_ms: 0,
+			slow_ms: 0,
+			..cfg()
+		});
+		assert_eq!(
+			mw.global,
+			Limits {
+				timeout: None,
+				slow: None
+			}
+		);
+	}
+}
@@ -0,0 +1,274 @@
+use std::io::BufReader;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, RwLock};
+use std::task::{Context as TaskContext, Poll};
+use std::time::{Duration, SystemTime};
+
+use anyhow_ext::{Context, Result, bail};
+use futures_lite::{AsyncRead, AsyncWrite};
+use futures_rustls::TlsAcceptor;
+use futures_rustls::server::TlsStream;
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::CertificateDer;
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::{RootCertStore, ServerConfig};
+use tracing::{info, warn};
+
+use crate::config::{TlsConfig, TlsVersion};
+
+/// Terminates TLS for the listeners, see [`crate::listener::AppListener`].
+///
+/// The certificate and key are re-read when their modification time changes, new
+/// handshakes use the new certificate while established connections keep the old one.
+pub struct TlsTerminator {
+	acceptor: TlsAcceptor,
+	resolver: Arc<ReloadingCertResolver>,
+	reload_interval: Duration,
+	handshake_timeout: Duration,
+}
+
+impl TlsTerminator {
+	/// Load the certificate, key and client CA now, so a broken setup fails on startup
+	pub fn new(cfg: &TlsConfig) -> Result<Self> {
+		let provider = Arc::new(rustls::crypto::ring::default_provider());
+		let resolver = Arc::new(ReloadingCertResolver::load(cfg, provider.clone()).dot()?);
+
+		let versions: &[&rustls::SupportedProtocolVersion] = match cfg.min_version {
+			TlsVersion::Tls12 => &[&rustls::version::TLS13, &rustls::version::TLS12],
+			TlsVersion::Tls13 => &[&rustls::version::TLS13],
+		};
+		let builder = ServerConfig::builder_with_provider(provider.clone())
+			.with_protocol_versions(versions)
+			.context("unsupported TLS protocol versions")?;
+		let builder = match &cfg.client_ca_file {
+			Some(ca_file) => {
+				let mut roots = RootCertStore::empty();
+				for cert in load_certs(ca_file).dot()? {
+					roots
+						.add(cert)
+						.with_context(|| format!("invalid CA certificate in {ca_file}"))?;
+				}
+				let verifier =
+					WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+				let verifier = if cfg.client_cert_required {
+					verifier.build()
+				} else {
+					verifier.allow_unauthenticated().build()
+				}
+				.context("failed to build the client certificate verifier")?;
+				builder.with_client_cert_verifier(verifier)
+			}
+			None => builder.with_no_client_auth(),
+		};
+		let mut server_config = builder.with_cert_resolver(resolver.clone());
+		server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+		Ok(Self {
+			acceptor: TlsAcceptor::from(Arc::new(server_config)),
+			resolver,
+			reload_interval: Duration::from_secs(cfg.reload_interval_secs),
+			handshake_timeout: Duration::from_secs(cfg.handshake_timeout_secs),
+		})
+	}
+
+	/// Start watching the certificate files, does nothing when the reload is disabled
+	pub fn spawn_reload(&self) {
+		if self.reload_interval.is_zero() {
+			return;
+		}
+		let resolver = self.resolver.clone();
+		let interval = self.reload_interval;
+		async_std::task::spawn(async move {
+			loop {
+				async_std::task::sleep(interval).await;
+				resolver.reload_if_changed();
+			}
+		});
+	}
+
+	/// How long [`Self::accept`] may take, enforced by the caller
+	pub fn handshake_timeout(&self) -> Duration {
+		self.handshake_timeout
+	}
+
+	pub async fn accept<S>(&self, stream: S) -> std::io::Result<SharedTlsStream<S>>
+	where
+		S: AsyncRead + AsyncWrite + Unpin,
+	{
+		let stream = self.acceptor.accept(stream).await?;
+		Ok(SharedTlsStream(Arc::new(Mutex::new(stream))))
+	}
+}
+
+/// `async_h1::accept` needs a `Clone` stream to read and write from separate tasks
+pub struct SharedTlsStream<S>(Arc<Mutex<TlsStream<S>>>);
+
+impl<S> Clone for SharedTlsStream<S> {
+	fn clone(&self) -> Self {
+		Self(self.0.clone())
+	}
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> SharedTlsStream<S> {
+	fn with<T>(&self, f: impl FnOnce(Pin<&mut TlsStream<S>>) -> T) -> T {
+		let mut stream = self
+			.0
+			.lock()
+			.unwrap_or_else(|poisoned| poisoned.into_inner());
+		f(Pin::new(&mut *stream))
+	}
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for SharedTlsStream<S> {
+	fn poll_read(
+		self: Pin<&mut Self>,
+		cx: &mut TaskContext<'_>,
+		buf: &mut [u8],
+	) -> Poll<std::io::Result<usize>> {
+		self.with(|stream| stream.poll_read(cx, buf))
+	}
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for SharedTlsStream<S> {
+	fn poll_write(
+		self: Pin<&mut Self>,
+		cx: &mut TaskContext<'_>,
+		buf: &[u8],
+	) -> Poll<std::io::Result<usize>> {
+		self.with(|stream| stream.poll_write(cx, buf))
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+		self.with(|stream| stream.poll_flush(cx))
+	}
+
+	fn poll_close(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+		self.with(|stream| stream.poll_close(cx))
+	}
+}
+
+/// Hands out the current certificate for every handshake
+#[derive(Debug)]
+struct ReloadingCertResolver {
+	cert_file: String,
+	key_file: String,
+	provider: Arc<CryptoProvider>,
+	current: RwLock<Loaded>,
+}
+
+#[derive(Debug)]
+struct Loaded {
+	key: Arc<CertifiedKey>,
+	modified: (Option<SystemTime>, Option<SystemTime>),
+}
+
+impl ReloadingCertResolver {
+	fn load(cfg: &TlsConfig, provider: Arc<CryptoProvider>) -> Result<Self> {
+		let loaded = load_files(&cfg.cert_file, &cfg.key_file, &provider).dot()?;
+		Ok(Self {
+			cert_file: cfg.cert_file.clone(),
+			key_file: cfg.key_file.clone(),
+			provider,
+			current: RwLock::new(loaded),
+		})
+	}
+
+	/// Keeps serving the old certificate when the new files can't be loaded, e.g.
+	/// while only one of them has been replaced
+	fn reload_if_changed(&self) {
+		let unchanged = {
+			let current = self.current.read().unwrap_or_else(|p| p.into_inner());
+			current.modified == modified(&self.cert_file, &self.key_file)
+		};
+		if unchanged {
+			return;
+		}
+		match load_files(&self.cert_file, &self.key_file, &self.provider) {
+			Ok(loaded) => {
+				*self.current.write().unwrap_or_else(|p| p.into_inner()) = loaded;
+				info!(cert_file = %self.cert_file, "TLS certificate reloaded");
+			}
+			Err(err) => warn!(
+				?err,
+				"failed to reload TLS certificate, keeping the old one"
+			),
+		}
+	}
+}
+
+impl ResolvesServerCert for ReloadingCertResolver {
+	fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+		let current = self.current.read().unwrap_or_else(|p| p.into_inner());
+		Some(current.key.clone())
+	}
+}
+
+fn modified(cert_file: &str, key_file: &str) -> (Option<SystemTime>, Option<SystemTime>) {
+	let mtime = |path: &str| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+	(mtime(cert_file), mtime(key_file))
+}
+
+fn load_files(cert_file: &str, key_file: &str, provider: &CryptoProvider) -> Result<Loaded> {
+	// taken before reading, a write racing with the read is picked up next time
+	let modified = modified(cert_file, key_file);
+	let certs = load_certs(cert_file).dot()?;
+	let key = load_key(key_file).dot()?;
+	let signing_key = provider
+		.key_provider
+		.load_private_key(key)
+		.with_context(|| format!("unsupported private key in {key_file}"))?;
+	let key = CertifiedKey::new(certs, signing_key);
+	key.keys_match()
+		.with_context(|| format!("{key_file} does not match {cert_file}"))?;
+	Ok(Loaded {
+		key: Arc::new(key),
+		modified,
+	})
+}
+
+fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>> {
+	let file = std::fs::File::open(path).with_context(|| format!("failed to open {path}"))?;
+	let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+		.collect::<std::io::Result<Vec<_>>>()
+		.with_context(|| format!("failed to parse certificates in {path}"))?;
+	if certs.is_empty() {
+		bail!("no certificate found in {path}");
+	}
+	Ok(certs)
+}
+
+fn load_key(path: &str) -> Result<rustls::pki_types::PrivateKeyDer<'static>> {
+	let file = std::fs::File::open(path).with_context(|| format!("failed to open {path}"))?;
+	rustls_pemfile::private_key(&mut BufReader::new(file))
+		.with_context(|| format!("failed to parse private key in {path}"))?
+		.with_context(|| format!("no private key found in {path}"))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_missing_and_empty_pem_files_are_rejected() {
+		let dir = std::env::temp_dir().join("rust_tide_template_test_tls");
+		std::fs::create_dir_all(&dir).unwrap();
+		let empty = dir.join("empty.pem");
+		std::fs::write(&empty, "not a pem file\n").unwrap();
+		let empty = empty.to_str().unwrap();
+
+		assert!(load_certs("/nonexistent/cert.pem").is_err());
+		assert!(load_certs(empty).is_err());
+		assert!(load_key(empty).is_err());
+
+		let cfg = TlsConfig {
+			enabled: true,
+			cert_file: empty.to_string(),
+			key_file: empty.to_string(),
+			..Default::default()
+		};
+		assert!(TlsTerminator::new(&cfg).is_err());
+
+		std::fs::remove_dir_all(&dir).ok();
+	}
+}
@@ -0,0 +1,653 @@
+use std::path::{Path, PathBuf};
+
+use anyhow_ext::{Context, Result, bail, ensure};
+use async_std::fs;
+use futures_lite::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use memchr::memmem;
+use serde::Serialize;
+use tide::{Request, StatusCode};
+use tracing::{error, info};
+
+use crate::config::{self, UploadConfig};
+use crate::error::AppError;
+use crate::route_pattern;
+use crate::server::make_resp;
+use crate::utils;
+
+const READ_CHUNK: usize = 16 * 1024;
+/// Part headers are a few hundred bytes, anything beyond this is garbage
+const MAX_PART_HEADER_BYTES: usize = 8 * 1024;
+/// File signatures checked for types that have one, see [`UploadConfig`]
+const SIGNATURES: &[(&str, &[u8])] = &[
+	("image/png", b"\x89PNG\r\n\x1a\n"),
+	("image/jpeg", b"\xff\xd8\xff"),
+	("image/gif", b"GIF8"),
+	("application/pdf", b"%PDF-"),
+];
+const SNIFF_LEN: usize = 8;
+
+/// Headers of one part of a `multipart/form-data` body
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PartHeaders {
+	pub name: String,
+	/// Only set for file fields
+	pub filename: Option<String>,
+	pub content_type: Option<String>,
+}
+
+/// Reads a `multipart/form-data` body part by part, part data is handed out in chunks
+/// as it arrives instead of being buffered
+pub struct Multipart<R> {
+	reader: R,
+	/// `\r\n--<boundary>`, the body is read as if it started with `\r\n`
+	delimiter: Vec<u8>,
+	buf: Vec<u8>,
+	eof: bool,
+	/// Whether the data of the current part has been read up to the next delimiter
+	part_done: bool,
+	finished: bool,
+}
+
+impl<R: AsyncRead + Unpin> Multipart<R> {
+	pub fn new(reader: R, boundary: &str) -> Self {
+		Self {
+			reader,
+			delimiter: format!("\r\n--{boundary}").into_bytes(),
+			buf: b"\r\n".to_vec(),
+			eof: false,
+			part_done: true,
+			finished: false,
+		}
+	}
+
+	/// Read more of the body into `buf`, false at its end
+	async fn fill(&mut self) -> Result<bool> {
+		if self.eof {
+			return Ok(false);
+		}
+		let start = self.buf.len();
+		self.buf.resize(start + READ_CHUNK, 0);
+		let read = self.reader.read(&mut self.buf[start..]).await;
+		let n = *read.as_ref().unwrap_or(&0);
+		self.buf.truncate(start + n);
+		read.context("failed to read the request body")?;
+		self.eof = n == 0;
+		Ok(n > 0)
+	}
+
+	async fn fill_or_fail(&mut self) -> Result<()> {
+		if !self.fill().await? {
+			bail!("multipart body ends without its closing boundary");
+		}
+		Ok(())
+	}
+
+	/// Headers of the next part, None after the last one. Data of the current part
+	/// that wasn't read is skipped.
+	pub async fn next_part(&mut self) -> Result<Option<PartHeaders>> {
+		while self.read_chunk().await?.is_some() {}
+		if self.finished {
+			return Ok(None);
+		}
+
+		// anything in front of the first delimiter is a preamble to be ignored
+		let pos = loop {
+			if let Some(pos) = memmem::find(&self.buf, &self.delimiter) {
+				break pos;
+			}
+			let keep = self.delimiter.len() - 1;
+			if self.buf.len() > keep {
+				self.buf.drain(..self.buf.len() - keep);
+			}
+			self.fill_or_fail().await?;
+		};
+		self.buf.drain(..pos + self.delimiter.len());
+		while self.buf.len() < 2 {
+			self.fill_or_fail().await?;
+		}
+		if self.buf.starts_with(b"--") {
+			self.finished = true;
+			return Ok(None);
+		}
+		ensure!(
+			self.buf.starts_with(b"\r\n"),
+			"malformed multipart boundary"
+		);
+
+		// the CRLF ending the boundary line is kept, so no headers at all is found too
+		let end = loop {
+			if let Some(end) = memmem::find(&self.buf, b"\r\n\r\n") {
+				break end;
+			}
+			ensure!(
+				self.buf.len() <= MAX_PART_HEADER_BYTES,
+				"multipart part headers are too large"
+			);
+			self.fill_or_fail().await?;
+		};
+		ensure!(
+			end <= MAX_PART_HEADER_BYTES,
+			"multipart part headers are too large"
+		);
+		let headers = parse_part_headers(self.buf.get(2..end).unwrap_or_default())?;
+		self.buf.drain(..end + 4);
+		self.part_done = false;
+		Ok(Some(headers))
+	}
+
+	/// The next piece of data of the current part, None at its end
+	pub async fn read_chunk(&mut self) -> Result<Option<Vec<u8>>> {
+		if self.part_done {
+			return Ok(None);
+		}
+		loop {
+			if let Some(pos) = memmem::find(&self.buf, &self.delimiter) {
+				self.part_done = true;
+				return Ok((pos > 0).then(|| self.buf.drain(..pos).collect()));
+			}
+			// the tail could be the start of a delimiter split across reads
+			let keep = self.delimiter.len() - 1;
+			if self.buf.len() > keep {
+				return Ok(Some(self.buf.drain(..self.buf.len() - keep).collect()));
+			}
+			self.fill_or_fail().await?;
+		}
+	}
+}
+
+fn parse_part_headers(raw: &[u8]) -> Result<PartHeaders> {
+	let raw = std::str::from_utf8(raw).context("multipart part headers are not UTF-8")?;
+	let mut name = None;
+	let mut headers = PartHeaders::default();
+	for line in raw.split("\r\n").filter(|line| !line.is_empty()) {
+		let Some((key, value)) = line.split_once(':') else {
+			bail!("malformed multipart part header {line:?}");
+		};
+		if key.trim().eq_ignore_ascii_case("content-type") {
+			headers.content_type = Some(value.trim().to_string());
+		} else if key.trim().eq_ignore_ascii_case("content-disposition") {
+			let mut params = value.split(';').map(str::trim);
+			ensure!(
+				params
+					.next()
+					.is_some_and(|kind| kind.eq_ignore_ascii_case("form-data")),
+				"multipart part is not form-data"
+			);
+			for param in params {
+				match param.split_once('=') {
+					Some((key, value)) if key.eq_ignore_ascii_case("name") => {
+						name = Some(value.trim_matches('"').to_string());
+					}
+					Some((key, value)) if key.eq_ignore_ascii_case("filename") => {
+						headers.filename = Some(value.trim_matches('"').to_string());
+					}
+					_ => {}
+				}
+			}
+		}
+	}
+	headers.name = name.context("multipart part without a name")?;
+	Ok(headers)
+}
+
+/// The boundary of a `multipart/form-data` content type
+pub fn boundary(content_type: &str) -> Option<&str> {
+	let mut params = content_type.split(';').map(str::trim);
+	if !params.next()?.eq_ignore_ascii_case("multipart/form-data") {
+		return None;
+	}
+	params
+		.filter_map(|param| param.split_once('='))
+		.find(|(key, _)| key.trim().eq_ignore_ascii_case("boundary"))
+		.map(|(_, value)| value.trim().trim_matches('"'))
+		.filter(|boundary| (1..=70).contains(&boundary.len()))
+}
+
+/// A file written to `upload.dir`
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct SavedFile {
+	pub field: String,
+	/// The name the client sent, never used as a path
+	pub filename: String,
+	/// Name of the file in `upload.dir`
+	pub stored_as: String,
+	pub content_type: String,
+	pub size: u64,
+}
+
+/// `POST /api/uploads`, stores the files of a `multipart/form-data` body in
+/// `upload.dir`. Form fields without a filename are ignored.
+pub async fn upload<State: Clone + Send + Sync + 'static>(mut req: Request<State>) -> tide::Result {
+	let cfg = config::cfg().await.upload.clone();
+	let content_type = req
+		.header("Content-Type")
+		.map(|value| value.as_str().to_string())
+		.unwrap_or_default();
+	let Some(boundary) = boundary(&content_type) else {
+		return Ok(AppError::new(
+			StatusCode::UnsupportedMediaType,
+			"expected_multipart",
+			"expected a multipart/form-data body",
+		)
+		.into());
+	};
+	let mut multipart = Multipart::new(req.take_body(), boundary);
+	match save_uploads(&mut multipart, &cfg).await {
+		Ok(files) => {
+			info!(count = files.len(), "files uploaded");
+			Ok(make_resp(
+				StatusCode::Created,
+				tide::Body::from_json(&serde_json::json!({ "files": files }))?,
+			))
+		}
+		Err(err) => Ok(err.into()),
+	}
+}
+
+/// Files written for one request, removed when dropped uncommitted. Covers refused
+/// uploads as well as the handler future being dropped by a timeout or a client that
+/// hung up.
+#[derive(Default)]
+struct Staged {
+	paths: Vec<PathBuf>,
+	committed: bool,
+}
+
+impl Drop for Staged {
+	fn drop(&mut self) {
+		if self.committed {
+			return;
+		}
+		for path in &self.paths {
+			let _ = std::fs::remove_file(path);
+		}
+	}
+}
+
+/// Stream every file of `multipart` into `cfg.dir`. Nothing is kept when one of them
+/// is refused or the returned future is dropped before it completes.
+pub async fn save_uploads<R: AsyncRead + Unpin>(
+	multipart: &mut Multipart<R>,
+	cfg: &UploadConfig,
+) -> Result<Vec<SavedFile>, AppError> {
+	let mut staged = Staged::default();
+	let mut saved = vec![];
+	save_all(multipart, cfg, &mut staged, &mut saved).await?;
+	staged.committed = true;
+	Ok(saved)
+}
+
+async fn save_all<R: AsyncRead + Unpin>(
+	multipart: &mut Multipart<R>,
+	cfg: &UploadConfig,
+	staged: &mut Staged,
+	saved: &mut Vec<SavedFile>,
+) -> Result<(), AppError> {
+	let dir = Path::new(&cfg.dir);
+	fs::create_dir_all(dir)
+		.await
+		.with_context(|| format!("failed to create {}", cfg.dir))
+		.map_err(store_failed)?;
+	while let Some(part) = multipart.next_part().await.map_err(invalid_multipart)? {
+		// plain form fields
+		let Some(filename) = part.filename else {
+			continue;
+		};
+		if saved.len() >= cfg.max_files {
+			return Err(AppError::bad_request(
+				"too_many_files",
+				format!("at most {} files can be uploaded at once", cfg.max_files),
+			)
+			.with_detail("max_files", cfg.max_files));
+		}
+		let content_type = part
+			.content_type
+			.unwrap_or_else(|| "application/octet-stream".to_string());
+		if !mime_allowed(&cfg.allowed_mime_types, &content_type) {
+			return Err(AppError::new(
+				StatusCode::UnsupportedMediaType,
+				"file_type_not_allowed",
+				format!("{content_type} files are not accepted"),
+			)
+			.with_detail("field", part.name));
+		}
+
+		let stored_as = format!("{}{}", utils::gen_n_random_str(32), extension(&filename));
+		// written under a hidden name first, so a partial file never shows up
+		let partial = dir.join(format!(".{stored_as}.part"));
+		staged.paths.push(partial.clone());
+		let size = write_part(multipart, &partial, &content_type, cfg.max_file_bytes).await?;
+		let stored = dir.join(&stored_as);
+		fs::rename(&partial, &stored)
+			.await
+			.with_context(|| format!("failed to move {}", partial.display()))
+			.map_err(store_failed)?;
+		staged.paths.pop();
+		staged.paths.push(stored);
+		saved.push(SavedFile {
+			field: part.name,
+			filename,
+			stored_as,
+			content_type,
+			size,
+		});
+	}
+	Ok(())
+}
+
+async fn write_part<R: AsyncRead + Unpin>(
+	multipart: &mut Multipart<R>,
+	path: &Path,
+	content_type: &str,
+	max_bytes: u64,
+) -> Result<u64, AppError> {
+	let mut file = fs::File::create(path)
+		.await
+		.with_context(|| format!("failed to create {}", path.display()))
+		.map_err(store_failed)?;
+	let mut size = 0u64;
+	let mut head = Vec::with_capacity(SNIFF_LEN);
+	while let Some(chunk) = multipart.read_chunk().await.map_err(invalid_multipart)? {
+		size += chunk.len() as u64;
+		if size > max_bytes {
+			return Err(AppError::new(
+				StatusCode::PayloadTooLarge,
+				"file_too_large",
+				format!("files can be at most {max_bytes} bytes"),
+			)
+			.with_detail("max_file_bytes", max_bytes));
+		}
+		if head.len() < SNIFF_LEN {
+			let take = (SNIFF_LEN - head.len()).min(chunk.len());
+			head.extend_from_slice(&chunk[..take]);
+			if head.len() == SNIFF_LEN {
+				check_signature(content_type, &head)?;
+			}
+		}
+		file.write_all(&chunk)
+			.await
+			.context("failed to write the upload")
+			.map_err(store_failed)?;
+	}
+	if head.len() < SNIFF_LEN {
+		check_signature(content_type, &head)?;
+	}
+	file.sync_all()
+		.await
+		.context("failed to write the upload")
+		.map_err(store_failed)?;
+	Ok(size)
+}
+
+fn mime_allowed(allowed: &[String], content_type: &str) -> bool {
+	let essence = essence(content_type);
+	allowed
+		.iter()
+		.any(|pattern| route_pattern::glob_match(&pattern.to_ascii_lowercase(), &essence))
+}
+
+/// `image/png` of `image/png; charset=...`, lowercased
+fn essence(content_type: &str) -> String {
+	content_type
+		.split(';')
+		.next()
+		.unwrap_or_default()
+		.trim()
+		.to_ascii_lowercase()
+}
+
+fn check_signature(content_type: &str, head: &[u8]) -> Result<(), AppError> {
+	let essence = essence(content_type);
+	match SIGNATURES.iter().find(|(mime, _)| *mime == essence) {
+		Some((_, signature)) if !head.starts_with(signature) => Err(AppError::new(
+			StatusCode::UnsupportedMediaType,
+			"file_content_mismatch",
+			format!("the file is not a valid {essence} file"),
+		)),
+		_ => Ok(()),
+	}
+}
+
+/// The extension of the client's filename, when it is a plain one
+fn extension(filename: &str) -> String {
+	// Windows browsers used to send whole paths
+	let name = filename.rsplit(['/', '\\']).next().unwrap_or_default();
+	Path::new(name)
+		.extension()
+		.and_then(|ext| ext.to_str())
+		.filter(|ext| ext.len() <= 10 && ext.chars().all(|c| c.is_ascii_alphanumeric()))
+		.map(|ext| format!(".{}", ext.to_ascii_lowercase()))
+		.unwrap_or_default()
+}
+
+fn invalid_multipart(err: anyhow_ext::Error) -> AppError {
+	AppError::bad_request("invalid_multipart", err.to_string())
+}
+
+fn store_failed(err: anyhow_ext::Error) -> AppError {
+	error!(?err, "failed to store upload");
+	AppError::from_status(
+		StatusCode::InternalServerError,
+		"failed to store the upload",
+	)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::pin::Pin;
+	use std::task::{Context as TaskContext, Poll};
+	use std::time::Duration;
+
+	use super::*;
+
+	/// Hands out the body a few bytes at a time, so delimiters get split across reads
+	struct Trickle(Vec<u8>, usize);
+
+	impl AsyncRead for Trickle {
+		fn poll_read(
+			mut self: Pin<&mut Self>,
+			_cx: &mut TaskContext<'_>,
+			buf: &mut [u8],
+		) -> Poll<std::io::Result<usize>> {
+			let n = buf.len().min(self.1).min(self.0.len());
+			buf[..n].copy_from_slice(&self.0[..n]);
+			self.0.drain(..n);
+			Poll::Ready(Ok(n))
+		}
+	}
+
+	/// Hands out the body at once, then never completes, like a client gone silent
+	struct Stall(Vec<u8>);
+
+	impl AsyncRead for Stall {
+		fn poll_read(
+			mut self: Pin<&mut Self>,
+			_cx: &mut TaskContext<'_>,
+			buf: &mut [u8],
+		) -> Poll<std::io::Result<usize>> {
+			if self.0.is_empty() {
+				return Poll::Pending;
+			}
+			let n = buf.len().min(self.0.len());
+			buf[..n].copy_from_slice(&self.0[..n]);
+			self.0.drain(..n);
+			Poll::Ready(Ok(n))
+		}
+	}
+
+	const BOUNDARY: &str = "----formdata42";
+
+	/// Field name, filename, content type and data
+	type Part<'a> = (&'a str, Option<&'a str>, Option<&'a str>, &'a [u8]);
+
+	fn body(parts: &[Part]) -> Vec<u8> {
+		let mut body = b"preamble\r\n".to_vec();
+		for (name, filename, content_type, data) in parts {
+			body.extend(format!("--{BOUNDARY}\r\n").bytes());
+			let filename = filename.map_or(String::new(), |f| format!("; filename=\"{f}\""));
+			body.extend(
+				format!("Content-Disposition: form-data; name=\"{name}\"{filename}\r\n").bytes(),
+			);
+			if let Some(content_type) = content_type {
+				body.extend(format!("Content-Type: {content_type}\r\n").bytes());
+			}
+			body.extend(b"\r\n");
+			body.extend(*data);
+			body.extend(b"\r\n");
+		}
+		body.extend(format!("--{BOUNDARY}--\r\n").bytes());
+		body
+	}
+
+	fn png(len: usize) -> Vec<u8> {
+		let mut data = b"\x89PNG\r\n\x1a\n".to_vec();
+		data.resize(len, 7);
+		data
+	}
+
+	fn cfg(name: &str) -> UploadConfig {
+		let dir = std::env::temp_dir().join(format!("rust_tide_template_test_upload_{name}"));
+		let _ = std::fs::remove_dir_all(&dir);
+		UploadConfig {
+			dir: dir.to_str().unwrap().to_string(),
+			max_file_bytes: 1000,
+			..Default::default()
+		}
+	}
+
+	fn files_in(cfg: &UploadConfig) -> usize {
+		std::fs::read_dir(&cfg.dir).unwrap().count()
+	}
+
+	#[test]
+	fn test_boundary() {
+		assert_eq!(
+			boundary("multipart/form-data; boundary=\"abc\""),
+			Some("abc")
+		);
+		assert_eq!(boundary("Multipart/Form-Data;boundary=abc"), Some("abc"));
+		assert_eq!(boundary("application/json"), None);
+		assert_eq!(boundary("multipart/form-data"), None);
+	}
+
+	#[async_std::test]
+	async fn test_parts_split_across_reads() {
+		let data = png(100);
+		let body = body(&[
+			("title", None, None, b"hello\r\n--not-a-boundary"),
+			("file", Some("a.png"), Some("image/png"), &data),
+		]);
+		let mut multipart = Multipart::new(Trickle(body, 7), BOUNDARY);
+
+		let part = multipart.next_part().await.unwrap().unwrap();
+		assert_eq!(part.name, "title");
+		assert_eq!(part.filename, None);
+		let mut value = vec![];
+		while let Some(chunk) = multipart.read_chunk().await.unwrap() {
+			value.extend(chunk);
+		}
+		assert_eq!(value, b"hello\r\n--not-a-boundary");
+
+		let part = multipart.next_part().await.unwrap().unwrap();
+		assert_eq!(part.filename.as_deref(), Some("a.png"));
+		assert_eq!(part.content_type.as_deref(), Some("image/png"));
+		// the unread data of the last part is skipped
+		assert_eq!(multipart.next_part().await.unwrap(), None);
+	}
+
+	#[async_std::test]
+	async fn test_truncated_body_is_rejected() {
+		let mut body = body(&[("file", Some("a.txt"), Some("text/plain"), b"abc")]);
+		body.truncate(body.len() - 10);
+		let mut multipart = Multipart::new(Trickle(body, 64), BOUNDARY);
+		multipart.next_part().await.unwrap();
+		assert!(multipart.next_part().await.is_err());
+	}
+
+	#[async_std::test]
+	async fn test_save_uploads() {
+		let cfg = cfg("ok");
+		let data = png(500);
+		let body = body(&[
+			("note", None, None, b"ignored"),
+			(
+				"file",
+				Some("C:\\photos\\cat.PNG"),
+				Some("image/png"),
+				&data,
+			),
+			(
+				"doc",
+				Some("../../etc/passwd"),
+				Some("text/plain"),
+				b"plain text",
+			),
+		]);
+		let mut multipart = Multipart::new(Trickle(body, 64), BOUNDARY);
+		let files = save_uploads(&mut multipart, &cfg).await.unwrap();
+		assert_eq!(files.len(), 2);
+		assert_eq!(files[0].field, "file");
+		assert_eq!(files[0].size, 500);
+		assert!(files[0].stored_as.ends_with(".png"));
+		assert!(!files[1].stored_as.contains('.'));
+		let stored = std::fs::read(Path::new(&cfg.dir).join(&files[0].stored_as)).unwrap();
+		assert_eq!(stored, data);
+		assert_eq!(files_in(&cfg), 2);
+		std::fs::remove_dir_all(&cfg.dir).ok();
+	}
+
+	#[async_std::test]
+	async fn test_refused_uploads_leave_nothing_behind() {
+		let cfg = cfg("refused");
+		let cases: [(&[u8], &str, StatusCode, &str); 3] = [
+			(
+				&png(1001),
+				"image/png",
+				StatusCode::PayloadTooLarge,
+				"file_too_large",
+			),
+			(
+				b"<svg onload=alert(1)>",
+				"image/png",
+				StatusCode::UnsupportedMediaType,
+				"file_content_mismatch",
+			),
+			(
+				b"<svg/>",
+				"image/svg+xml",
+				StatusCode::UnsupportedMediaType,
+				"file_type_not_allowed",
+			),
+		];
+		for (data, content_type, status, code) in cases {
+			let ok = png(10);
+			let body = body(&[
+				("first", Some("ok.png"), Some("image/png"), &ok),
+				("second", Some("bad.png"), Some(content_type), data),
+			]);
+			let mut multipart = Multipart::new(Trickle(body, 512), BOUNDARY);
+			let err = save_uploads(&mut multipart, &cfg).await.unwrap_err();
+			assert_eq!(err.status, status);
+			assert_eq!(err.code, code);
+			assert_eq!(files_in(&cfg), 0);
+		}
+		std::fs::remove_dir_all(&cfg.dir).ok();
+	}
+
+	#[async_std::test]
+	async fn test_dropped_uploads_leave_nothing_behind() {
+		let cfg = cfg("dropped");
+		let ok = png(10);
+		let mut body = body(&[
+			("first", Some("ok.png"), Some("image/png"), &ok),
+			("second", Some("cut.png"), Some("image/png"), &png(500)),
+		]);
+		body.truncate(body.len() - 200);
+		let mut multipart = Multipart::new(Stall(body), BOUNDARY);
+		let upload = save_uploads(&mut multipart, &cfg);
+		let timeout = Duration::from_millis(100);
+		assert!(async_std::future::timeout(timeout, upload).await.is_err());
+		assert_eq!(files_in(&cfg), 0);
+		std::fs::remove_dir_all(&cfg.dir).ok();
+	}
+}
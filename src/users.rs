@@ -0,0 +1,724 @@
+use anyhow_ext::{Context, Result};
+use sea_orm::sea_query::LikeExpr;
+use sea_orm::{
+	ActiveModelTrait, ColumnTrait, DbErr, EntityTrait, ModelTrait, PaginatorTrait, QueryFilter,
+	QueryOrder, Set, SqlErr,
+};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tide::{Request, Response};
+
+use crate::{auth, database, entity::user, error::AppError, server::make_resp, utils};
+
+pub const USERNAME_MIN_LEN: usize = 3;
+pub const USERNAME_MAX_LEN: usize = 32;
+pub const PASSWORD_MIN_LEN: usize = 8;
+pub const PASSWORD_MAX_LEN: usize = 256;
+pub const AGE_MIN: i32 = 0;
+pub const AGE_MAX: i32 = 150;
+
+const DEFAULT_PER_PAGE: u64 = 20;
+const MAX_PER_PAGE: u64 = 100;
+
+/// A user as returned by the API, never carries the password hash
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct UserDto {
+	pub id: i32,
+	pub username: String,
+	pub age: i32,
+	/// Unix seconds, set while the account is locked out after failed logins
+	pub locked_until: Option<i64>,
+}
+
+impl From<user::Model> for UserDto {
+	fn from(user: user::Model) -> Self {
+		Self {
+			id: user.id,
+			username: user.username,
+			age: user.age,
+			locked_until: user.locked_until,
+		}
+	}
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct CreateUser {
+	pub username: String,
+	pub password: String,
+	pub age: i32,
+}
+
+/// Fields left out are kept as they are, passwords are changed through their own endpoints
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct UpdateUser {
+	pub username: Option<String>,
+	pub age: Option<i32>,
+}
+
+/// `GET /api/users` query, `username` matches substrings and the age bounds are inclusive
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+struct ListQuery {
+	page: u64,
+	per_page: u64,
+	username: Option<String>,
+	min_age: Option<i32>,
+	max_age: Option<i32>,
+}
+
+impl Default for ListQuery {
+	fn default() -> Self {
+		Self {
+			page: 1,
+			per_page: DEFAULT_PER_PAGE,
+			username: None,
+			min_age: None,
+			max_age: None,
+		}
+	}
+}
+
+#[derive(Serialize)]
+struct UserPage {
+	items: Vec<UserDto>,
+	page: u64,
+	per_page: u64,
+	total: u64,
+}
+
+/// A rejected input field, answered with 400
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidField {
+	pub field: &'static str,
+	pub message: String,
+}
+
+impl InvalidField {
+	fn new(field: &'static str, message: impl Into<String>) -> Self {
+		Self {
+			field,
+			message: message.into(),
+		}
+	}
+
+	pub fn into_resp(self) -> Response {
+		AppError::bad_request("invalid_field", self.message)
+			.with_detail("field", self.field)
+			.into()
+	}
+}
+
+/// 3 to 32 characters out of ASCII letters, digits, `_`, `.` and `-`
+pub fn validate_username(username: &str) -> Result<(), InvalidField> {
+	let len = username.chars().count();
+	if !(USERNAME_MIN_LEN..=USERNAME_MAX_LEN).contains(&len) {
+		return Err(InvalidField::new(
+			"username",
+			format!("username must be {USERNAME_MIN_LEN} to {USERNAME_MAX_LEN} characters long"),
+		));
+	}
+	if !username
+		.chars()
+		.all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'))
+	{
+		return Err(InvalidField::new(
+			"username",
+			"username may only contain ASCII letters, digits, '_', '.' and '-'",
+		));
+	}
+	Ok(())
+}
+
+pub fn validate_password(password: &str) -> Result<(), InvalidField> {
+	let len = password.chars().count();
+	if !(PASSWORD_MIN_LEN..=PASSWORD_MAX_LEN).contains(&len) {
+		return Err(InvalidField::new(
+			"password",
+			format!("password must be {PASSWORD_MIN_LEN} to {PASSWORD_MAX_LEN} characters long"),
+		));
+	}
+	Ok(())
+}
+
+pub fn validate_age(age: i32) -> Result<(), InvalidField> {
+	if !(AGE_MIN..=AGE_MAX).contains(&age) {
+		return Err(InvalidField::new(
+			"age",
+			format!("age must be between {AGE_MIN} and {AGE_MAX}"),
+		));
+	}
+	Ok(())
+}
+
+impl CreateUser {
+	fn validate(&self) -> Result<(), InvalidField> {
+		validate_username(&self.username)?;
+		validate_password(&self.password)?;
+		validate_age(self.age)
+	}
+}
+
+impl UpdateUser {
+	fn validate(&self) -> Result<(), InvalidField> {
+		if let Some(username) = &self.username {
+			validate_username(username)?;
+		}
+		if let Some(age) = self.age {
+			validate_age(age)?;
+		}
+		Ok(())
+	}
+}
+
+impl ListQuery {
+	fn validate(&self) -> Result<(), InvalidField> {
+		if self.page == 0 {
+			return Err(InvalidField::new("page", "page starts at 1"));
+		}
+		if !(1..=MAX_PER_PAGE).contains(&self.per_page) {
+			return Err(InvalidField::new(
+				"per_page",
+				format!("per_page must be between 1 and {MAX_PER_PAGE}"),
+			));
+		}
+		Ok(())
+	}
+}
+
+/// `GET /api/users`
+pub async fn list<State: Clone + Send + Sync + 'static>(req: Request<State>) -> tide::Result {
+	let query: ListQuery = match req.query() {
+		Ok(query) => query,
+		Err(err) => return Ok(AppError::bad_request("invalid_query", err.to_string()).into()),
+	};
+	if let Err(invalid) = query.validate() {
+		return Ok(invalid.into_resp());
+	}
+
+	let mut select = user::Entity::find().order_by_asc(user::Column::Id);
+	if let Some(username) = &query.username {
+		let pattern = format!("%{}%", escape_like(username));
+		select = select.filter(user::Column::Username.like(LikeExpr::new(pattern).escape('\\')));
+	}
+	if let Some(min_age) = query.min_age {
+		select = select.filter(user::Column::Age.gte(min_age));
+	}
+	if let Some(max_age) = query.max_age {
+		select = select.filter(user::Column::Age.lte(max_age));
+	}
+
+	let db = database::get_db_conn().dot()?;
+	let paginator = select.paginate(db, query.per_page);
+	let total = paginator.num_items().await.dot()?;
+	let items = paginator.fetch_page(query.page - 1).await.dot()?;
+	let body = UserPage {
+		items: items.into_iter().map(UserDto::from).collect(),
+		page: query.page,
+		per_page: query.per_page,
+		total,
+	};
+	Ok(make_resp(200, tide::Body::from_json(&body)?))
+}
+
+/// `GET /api/users/:id`
+pub async fn get<State: Clone + Send + Sync + 'static>(req: Request<State>) -> tide::Result {
+	let Some(id) = user_id(&req) else {
+		return Ok(invalid_id());
+	};
+	match find_by_id(id).await? {
+		Some(user) => Ok(make_resp(200, tide::Body::from_json(&UserDto::from(user))?)),
+		None => Ok(not_found(id)),
+	}
+}
+
+/// `POST /api/users`, answers 201 with the created user
+pub async fn create<State: Clone + Send + Sync + 'static>(mut req: Request<State>) -> tide::Result {
+	let input: CreateUser = match read_json(&mut req).await {
+		Ok(input) => input,
+		Err(resp) => return Ok(resp),
+	};
+	if let Err(invalid) = input.validate() {
+		return Ok(invalid.into_resp());
+	}
+	if auth::find_user(&input.username).await?.is_some() {
+		return Ok(username_taken(&input.username));
+	}
+
+	let password = input.password;
+	let password_hash =
+		async_std::task::spawn_blocking(move || auth::hash_password(&password)).await?;
+	let active = user::ActiveModel {
+		username: Set(input.username.clone()),
+		password_hash: Set(password_hash),
+		age: Set(input.age),
+		failed_login_count: Set(0),
+		// ends what is left of an earlier user of the same name
+		credentials_changed_at: Set(Some(utils::unix_now_millis())),
+		..Default::default()
+	};
+	let db = database::get_db_conn().dot()?;
+	let user = match active.insert(db).await {
+		Ok(user) => user,
+		// lost a race against another insert of the same name
+		Err(err) if is_unique_violation(&err) => return Ok(username_taken(&input.username)),
+		Err(err) => return Err(anyhow_ext::Error::from(err).into()),
+	};
+
+	let mut resp = make_resp(201, tide::Body::from_json(&UserDto::from(user.clone()))?);
+	resp.insert_header("Location", format!("/api/users/{}", user.id));
+	Ok(resp)
+}
+
+/// `PATCH /api/users/:id`
+pub async fn update<State: Clone + Send + Sync + 'static>(mut req: Request<State>) -> tide::Result {
+	let Some(id) = user_id(&req) else {
+		return Ok(invalid_id());
+	};
+	let input: UpdateUser = match read_json(&mut req).await {
+		Ok(input) => input,
+		Err(resp) => return Ok(resp),
+	};
+	if let Err(invalid) = input.validate() {
+		return Ok(invalid.into_resp());
+	}
+	let Some(user) = find_by_id(id).await? else {
+		return Ok(not_found(id));
+	};
+
+	let old_username = user.username.clone();
+	let mut active: user::ActiveModel = user.into();
+	let new_username = input.username.filter(|name| *name != old_username);
+	if let Some(username) = &new_username {
+		if auth::find_user(username).await?.is_some() {
+			return Ok(username_taken(username));
+		}
+		active.username = Set(username.clone());
+		// sessions and tokens name the user, those of whoever had the name before must
+		// not carry over once it is taken by this user
+		active.credentials_changed_at = Set(Some(utils::unix_now_millis()));
+	}
+	if let Some(age) = input.age {
+		active.age = Set(age);
+	}
+
+	let db = database::get_db_conn().dot()?;
+	let user = match active.update(db).await {
+		Ok(user) => user,
+		Err(err) if is_unique_violation(&err) => {
+			return Ok(username_taken(new_username.as_deref().unwrap_or_default()));
+		}
+		Err(err) => return Err(anyhow_ext::Error::from(err).into()),
+	};
+	if user.username != old_username {
+		auth::invalidate_cached_cred(&old_username);
+	}
+	Ok(make_resp(200, tide::Body::from_json(&UserDto::from(user))?))
+}
+
+/// `DELETE /api/users/:id`, roles and API keys of the user go with it
+pub async fn delete<State: Clone + Send + Sync + 'static>(req: Request<State>) -> tide::Result {
+	let Some(id) = user_id(&req) else {
+		return Ok(invalid_id());
+	};
+	let Some(user) = find_by_id(id).await? else {
+		return Ok(not_found(id));
+	};
+	let username = user.username.clone();
+	let db = database::get_db_conn().dot()?;
+	user.delete(db).await.dot()?;
+	auth::invalidate_cached_cred(&username);
+	Ok(Response::new(204))
+}
+
+pub async fn find_by_id(id: i32) -> Result<Option<user::Model>> {
+	let db = database::get_db_conn().dot()?;
+	let user = user::Entity::find_by_id(id).one(db).await.dot()?;
+	Ok(user)
+}
+
+pub fn user_id<State>(req: &Request<State>) -> Option<i32> {
+	req.param("id").ok()?.parse().ok()
+}
+
+pub fn invalid_id() -> Response {
+	AppError::bad_request("invalid_id", "user id must be an integer").into()
+}
+
+/// Like `body_json`, but a malformed body is a 400 instead of tide's 422
+pub async fn read_json<T: DeserializeOwned, State>(
+	req: &mut Request<State>,
+) -> Result<T, Response> {
+	req.body_json()
+		.await
+		.map_err(|err| AppError::bad_request("invalid_body", err.to_string()).into())
+}
+
+/// `value` matching itself in a `LIKE` pattern with `\` as the escape character, `_` is
+/// a legal username character but a wildcard to `LIKE`
+fn escape_like(value: &str) -> String {
+	let mut escaped = String::with_capacity(value.len());
+	for c in value.chars() {
+		if matches!(c, '%' | '_' | '\\') {
+			escaped.push('\\');
+		}
+		escaped.push(c);
+	}
+	escaped
+}
+
+fn is_unique_violation(err: &DbErr) -> bool {
+	matches!(err.sql_err(), Some(SqlErr::UniqueConstraintViolation(_)))
+}
+
+pub fn not_found(id: i32) -> Response {
+	AppError::not_found("user_not_found", format!("user {id} not found")).into()
+}
+
+fn username_taken(username: &str) -> Response {
+	AppError::conflict(
+		"username_taken",
+		format!("username {username:?} is already taken"),
+	)
+	.into()
+}
+
+#[cfg(test)]
+mod tests {
+	use serde_json::{Value, json};
+	use tide::StatusCode;
+	use tide::http::{Method, Request as HttpRequest, Response as HttpResponse, Url};
+
+	use super::*;
+	use crate::config::SessionConfig;
+	use crate::route_pattern::RoutePattern;
+
+	fn app() -> tide::Server<()> {
+		let mut app = tide::new();
+		app.at("/api/users").get(list).post(create);
+		app.at("/api/users/:id")
+			.get(get)
+			.patch(update)
+			.delete(delete);
+		app
+	}
+
+	async fn send(
+		app: &tide::Server<()>,
+		method: Method,
+		path: &str,
+		body: Option<tide::Body>,
+		cookie: Option<&str>,
+	) -> HttpResponse {
+		let url = Url::parse(&format!("http://localhost{path}")).unwrap();
+		let mut req = HttpRequest::new(method, url);
+		if let Some(body) = body {
+			req.set_body(body);
+		}
+		if let Some(cookie) = cookie {
+			req.insert_header("Cookie", cookie);
+		}
+		app.respond(req).await.unwrap()
+	}
+
+	/// The status and JSON body, `Value::Null` for an empty one
+	async fn call(method: Method, path: &str, body: Option<Value>) -> (StatusCode, Value) {
+		let body = body.map(|body| tide::Body::from_json(&body).unwrap());
+		call_raw(method, path, body).await
+	}
+
+	async fn call_raw(method: Method, path: &str, body: Option<tide::Body>) -> (StatusCode, Value) {
+		let mut resp = send(&app(), method, path, body, None).await;
+		let body = resp.body_string().await.unwrap();
+		(
+			resp.status(),
+			serde_json::from_str(&body).unwrap_or(Value::Null),
+		)
+	}
+
+	async fn create_user(username: &str) -> i32 {
+		let body = json!({"username": username, "password": "correct horse", "age": 30});
+		let (status, user) = call(Method::Post, "/api/users", Some(body)).await;
+		assert_eq!(status, StatusCode::Created, "{user}");
+		user["id"].as_i64().unwrap() as i32
+	}
+
+	async fn rename(id: i32, username: &str) {
+		let path = format!("/api/users/{id}");
+		let body = json!({ "username": username });
+		let (status, user) = call(Method::Patch, &path, Some(body)).await;
+		assert_eq!(status, StatusCode::Ok, "{user}");
+	}
+
+	#[test]
+	fn test_escape_like() {
+		assert_eq!(escape_like("john_doe"), r"john\_doe");
+		assert_eq!(escape_like(r"100%\"), r"100\%\\");
+	}
+
+	#[async_std::test]
+	async fn test_create_and_get() {
+		database::init_test_database();
+		let body = json!({"username": "users-get-alice", "password": "correct horse", "age": 30});
+		let mut resp = send(
+			&app(),
+			Method::Post,
+			"/api/users",
+			Some(tide::Body::from_json(&body).unwrap()),
+			None,
+		)
+		.await;
+		assert_eq!(resp.status(), StatusCode::Created);
+		let created: Value = resp.body_json().await.unwrap();
+		let path = format!("/api/users/{}", created["id"]);
+		assert_eq!(resp["Location"].as_str(), path);
+		assert!(created.get("password_hash").is_none());
+
+		let (status, user) = call(Method::Get, &path, None).await;
+		assert_eq!(status, StatusCode::Ok);
+		assert_eq!(user, created);
+
+		let (status, err) = call(Method::Get, "/api/users/alice", None).await;
+		assert_eq!(status, StatusCode::BadRequest);
+		assert_eq!(err["code"], "invalid_id");
+		let (status, err) = call(Method::Get, &format!("/api/users/{}", i32::MAX), None).await;
+		assert_eq!(status, StatusCode::NotFound);
+		assert_eq!(err["code"], "user_not_found");
+	}
+
+	#[async_std::test]
+	async fn test_create_rejects_bad_input_and_taken_names() {
+		database::init_test_database();
+		create_user("users-create-bob").await;
+		let body = json!({"username": "users-create-bob", "password": "correct horse", "age": 30});
+		let (status, err) = call(Method::Post, "/api/users", Some(body)).await;
+		assert_eq!(status, StatusCode::Conflict);
+		assert_eq!(err["code"], "username_taken");
+
+		let (status, err) = call_raw(Method::Post, "/api/users", Some("{not json".into())).await;
+		assert_eq!(status, StatusCode::BadRequest);
+		assert_eq!(err["code"], "invalid_body");
+		let body = json!({"username": "users-create-eve", "password": "correct horse", "age": 30, "admin": true});
+		let (status, err) = call(Method::Post, "/api/users", Some(body)).await;
+		assert_eq!(status, StatusCode::BadRequest);
+		assert_eq!(err["code"], "invalid_body");
+		let body = json!({"username": "users-create-eve", "password": "short", "age": 30});
+		let (status, err) = call(Method::Post, "/api/users", Some(body)).await;
+		assert_eq!(status, StatusCode::BadRequest);
+		assert_eq!(err["code"], "invalid_field");
+		assert_eq!(err["field"], "password");
+		// nothing was created by the refused requests
+		let (_, page) = call(Method::Get, "/api/users?username=users-create-eve", None).await;
+		assert_eq!(page["total"], 0);
+	}
+
+	#[async_std::test]
+	async fn test_update() {
+		database::init_test_database();
+		let carol = create_user("users-update-carol").await;
+		create_user("users-update-dave").await;
+		let path = format!("/api/users/{carol}");
+
+		let (status, user) = call(Method::Patch, &path, Some(json!({"age": 31}))).await;
+		assert_eq!(status, StatusCode::Ok);
+		assert_eq!(user["age"], 31);
+		assert_eq!(user["username"], "users-update-carol");
+
+		let body = json!({"username": "users-update-dave"});
+		let (status, err) = call(Method::Patch, &path, Some(body)).await;
+		assert_eq!(status, StatusCode::Conflict);
+		assert_eq!(err["code"], "username_taken");
+		let (status, err) = call(Method::Patch, &path, Some(json!({"age": -1}))).await;
+		assert_eq!(status, StatusCode::BadRequest);
+		assert_eq!(err["field"], "age");
+		let (status, err) = call(Method::Patch, &path, Some(json!({"password": "x"}))).await;
+		assert_eq!(status, StatusCode::BadRequest);
+		assert_eq!(err["code"], "invalid_body");
+		let (status, err) = call(Method::Patch, "/api/users/x", Some(json!({"age": 1}))).await;
+		assert_eq!(status, StatusCode::BadRequest);
+		assert_eq!(err["code"], "invalid_id");
+		let missing = format!("/api/users/{}", i32::MAX);
+		let (status, err) = call(Method::Patch, &missing, Some(json!({"age": 1}))).await;
+		assert_eq!(status, StatusCode::NotFound);
+		assert_eq!(err["code"], "user_not_found");
+	}
+
+	#[async_std::test]
+	async fn test_delete() {
+		database::init_test_database();
+		let erin = create_user("users-delete-erin").await;
+		let path = format!("/api/users/{erin}");
+		let (status, _) = call(Method::Delete, &path, None).await;
+		assert_eq!(status, StatusCode::NoContent);
+		let (status, _) = call(Method::Get, &path, None).await;
+		assert_eq!(status, StatusCode::NotFound);
+		let (status, err) = call(Method::Delete, &path, None).await;
+		assert_eq!(status, StatusCode::NotFound);
+		assert_eq!(err["code"], "user_not_found");
+		let (status, _) = call(Method::Delete, "/api/users/-", None).await;
+		assert_eq!(status, StatusCode::BadRequest);
+	}
+
+	#[async_std::test]
+	async fn test_list() {
+		database::init_test_database();
+		for name in ["users-list-1", "users-list-2", "users-list-3"] {
+			create_user(name).await;
+		}
+		let (status, page) = call(
+			Method::Get,
+			"/api/users?username=users-list-&per_page=2&page=2",
+			None,
+		)
+		.await;
+		assert_eq!(status, StatusCode::Ok);
+		assert_eq!(page["total"], 3);
+		assert_eq!(page["items"].as_array().unwrap().len(), 1);
+		assert_eq!(page["items"][0]["username"], "users-list-3");
+
+		let (status, err) = call(Method::Get, "/api/users?per_page=0", None).await;
+		assert_eq!(status, StatusCode::BadRequest);
+		assert_eq!(err["field"], "per_page");
+		let (status, err) = call(Method::Get, "/api/users?page=first", None).await;
+		assert_eq!(status, StatusCode::BadRequest);
+		assert_eq!(err["code"], "invalid_query");
+	}
+
+	#[async_std::test]
+	async fn test_list_matches_underscores_literally() {
+		database::init_test_database();
+		create_user("users-like_1").await;
+		create_user("users-likeX1").await;
+		let (status, page) = call(Method::Get, "/api/users?username=users-like_", None).await;
+		assert_eq!(status, StatusCode::Ok);
+		assert_eq!(page["total"], 1);
+		assert_eq!(page["items"][0]["username"], "users-like_1");
+		let (_, page) = call(Method::Get, "/api/users?username=users-like%25", None).await;
+		assert_eq!(page["total"], 0);
+	}
+
+	#[async_std::test]
+	async fn test_rename_into_a_vacated_name_ends_its_old_sessions() {
+		database::init_test_database();
+		let mut authed = tide::new();
+		authed.with(
+			auth::session_middleware(&SessionConfig {
+				secret: Some("x".repeat(32)),
+				..Default::default()
+			})
+			.unwrap(),
+		);
+		authed.with(auth::AuthMiddleware::new(vec![RoutePattern::new(
+			"/login/*",
+			&["POST"],
+		)]));
+		authed
+			.at("/login/:name")
+			.post(|mut req: Request<()>| async move {
+				let name = req.param("name")?.to_string();
+				auth::start_session(req.session_mut(), &name)?;
+				Ok("")
+			});
+		authed.at("/whoami").get(|req: Request<()>| async move {
+			Ok(req.ext::<auth::Identity>().unwrap().username.clone())
+		});
+
+		let bob = create_user("users-rename-bob").await;
+		let alice = create_user("users-rename-alice").await;
+		async_std::task::sleep(std::time::Duration::from_millis(5)).await;
+		let resp = send(
+			&authed,
+			Method::Post,
+			"/login/users-rename-alice",
+			None,
+			None,
+		)
+		.await;
+		let cookie = resp["Set-Cookie"]
+			.as_str()
+			.split(';')
+			.next()
+			.unwrap()
+			.to_string();
+		let mut resp = send(&authed, Method::Get, "/whoami", None, Some(&cookie)).await;
+		assert_eq!(resp.body_string().await.unwrap(), "users-rename-alice");
+
+		async_std::task::sleep(std::time::Duration::from_millis(5)).await;
+		rename(alice, "users-rename-alice2").await;
+		rename(bob, "users-rename-alice").await;
+		let resp = send(&authed, Method::Get, "/whoami", None, Some(&cookie)).await;
+		assert_eq!(resp.status(), StatusCode::Unauthorized);
+	}
+
+	#[test]
+	fn test_validate_username() {
+		assert!(validate_username("alice").is_ok());
+		assert!(validate_username("a.b-c_1").is_ok());
+		assert!(validate_username(&"a".repeat(USERNAME_MAX_LEN)).is_ok());
+
+		assert_eq!(validate_username("ab").unwrap_err().field, "username");
+		assert!(validate_username(&"a".repeat(USERNAME_MAX_LEN + 1)).is_err());
+		assert!(validate_username("al ice").is_err());
+		assert!(validate_username("al:ice").is_err());
+		assert!(validate_username("ålice").is_err());
+	}
+
+	#[test]
+	fn test_validate_password_and_age() {
+		assert!(validate_password("12345678").is_ok());
+		assert_eq!(validate_password("1234567").unwrap_err().field, "password");
+		assert!(validate_age(AGE_MIN).is_ok());
+		assert!(validate_age(AGE_MAX).is_ok());
+		assert_eq!(validate_age(-1).unwrap_err().field, "age");
+		assert!(validate_age(AGE_MAX + 1).is_err());
+	}
+
+	#[test]
+	fn test_update_rejects_unknown_fields() {
+		let update: UpdateUser = serde_json::from_str(r#"{"age": 30}"#).unwrap();
+		assert_eq!(update.age, Some(30));
+		assert!(update.validate().is_ok());
+		assert!(serde_json::from_str::<UpdateUser>(r#"{"password": "x"}"#).is_err());
+		assert!(
+			serde_json::from_str::<UpdateUser>(r#"{"age": 200}"#)
+				.unwrap()
+				.validate()
+				.is_err()
+		);
+	}
+
+	#[test]
+	fn test_list_query_bounds() {
+		assert!(ListQuery::default().validate().is_ok());
+		let zero_page = ListQuery {
+			page: 0,
+			..Default::default()
+		};
+		assert_eq!(zero_page.validate().unwrap_err().field, "page");
+		let too_many = ListQuery {
+			per_page: MAX_PER_PAGE + 1,
+			..Default::default()
+		};
+		assert_eq!(too_many.validate().unwrap_err().field, "per_page");
+	}
+
+	#[test]
+	fn test_dto_hides_password_hash() {
+		let user = user::Model {
+			id: 1,
+			username: "alice".to_string(),
+			password_hash: "$argon2id$secret".to_string(),
+			age: 30,
+			failed_login_count: 0,
+			last_failed_login_at: None,
+			locked_until: None,
+			oidc_issuer: None,
+			oidc_subject: None,
+			credentials_changed_at: None,
+		};
+		let json = serde_json::to_string(&UserDto::from(user)).unwrap();
+		assert!(!json.contains("argon2"));
+		assert!(!json.contains("password"));
+	}
+}
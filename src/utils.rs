@@ -4,13 +4,28 @@ task_local! {
 	static REQ_ID: std::cell::RefCell<String> = std::cell::RefCell::new(String::new());
 }
 const TOKEN: u32 = 0x60db1e55;
-const A2Z: &str = "0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+/// `n` characters of `[0-9A-Za-z]`, each one equally likely
 pub fn gen_n_random_str(n: u8) -> String {
-	(0..n)
-		.map(|_| {
-			let idx = rand::random::<u8>() % (A2Z.len() as u8);
-			A2Z.chars().nth(idx as usize).unwrap()
-		})
+	use rand::distr::{Alphanumeric, SampleString};
+	Alphanumeric.sample_string(&mut rand::rng(), n as usize)
+}
+
+/// Current time in unix seconds, the format timestamps are stored in the database
+pub fn unix_now() -> i64 {
+	time::OffsetDateTime::now_utc().unix_timestamp()
+}
+
+/// Current time in unix milliseconds
+pub fn unix_now_millis() -> i64 {
+	(time::OffsetDateTime::now_utc().unix_timestamp_nanos() / 1_000_000) as i64
+}
+
+/// Hex encoded SHA-256, how API keys and reset tokens are stored
+pub fn sha256_hex(value: &str) -> String {
+	use sha2::{Digest, Sha256};
+	Sha256::digest(value.as_bytes())
+		.iter()
+		.map(|b| format!("{b:02x}"))
 		.collect()
 }
 